@@ -0,0 +1,265 @@
+/*
+   Command line client for a running jdw-sc, in place of the scripts in python/.
+
+       jdw-sc-ctl [--config=<path>] [--timeout=<secs>] <command> [<args>]
+
+   Commands:
+       note <synth> <external id> [--gate=<beats>] [--delay=<ms>] [<arg> <value>]...
+       modify <external id regex> [--delay=<ms>] [<arg> <value>]...
+       free <external id regex>
+       play-sample <external id> <sample pack> <index> [--category=<tag>] [--delay=<ms>] [<arg> <value>]...
+       load-sample <file> <sample pack> <buffer number> [--category=<tag>] [--tone-index=<index>]
+       create-synthdef <scd file>
+       set-bpm <bpm>
+       status
+       nrt-render <score file> <output file> [--bpm=<bpm>] [--end-beat=<beat>]
+
+   Notes with --gate are sent as /note_on_timed, others as /note_on. Arg values that parse as
+       numbers are sent as floats, anything else as strings.
+
+   Messages go to application_in_port of the config (default config.toml). Commands that get a
+       reply (create-synthdef, status, nrt-render) register a port of their own with /subscribe,
+       wait for the reply there and print it; a FAILURE reply exits with code 1.
+
+   Score files for nrt-render have one row per line: the beats to wait after the row, followed by
+       a note, modify, free or play-sample command. Blank lines and lines starting with # are
+       skipped. The render ends a bar (4 beats) after the last row unless --end-beat is given.
+
+       # beats  command
+       1.0      note example nrt_1 --gate=0.5 freq 130
+       0.25     play-sample drum_1 example 0 amp 1.0
+*/
+use std::fs;
+use std::net::UdpSocket;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use jdw_sc::config::{self, Config};
+use jdw_sc::ctl::{self, Command, USAGE};
+use jdw_sc::dry_run;
+use jdw_sc::osc_daemon;
+use jdw_sc::osc_model::{LoadSampleMessage, NRTRecordMessage};
+use jdw_sc::scd_templating;
+use rosc::{OscMessage, OscPacket, OscType};
+
+// Beats rendered after the last score row, unless --end-beat is given
+const SCORE_TAIL_BEATS: i32 = 4;
+
+fn fail(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    exit(1)
+}
+
+// Reply that a command waits for
+struct Reply {
+    addr: &'static str,
+    // Synthdef or file name of the request, so that replies to other clients are skipped
+    name: Option<String>,
+}
+
+impl Reply {
+    fn new(addr: &'static str, name: Option<String>) -> Option<Reply> {
+        Some(Reply { addr, name })
+    }
+
+    fn matches(&self, msg: &OscMessage) -> bool {
+        msg.addr == self.addr
+            && self.name.as_ref().map_or(true, |name| {
+                msg.args.get(1) == Some(&OscType::String(name.to_string()))
+            })
+    }
+}
+
+/*
+    Packet for the command, along with the reply to wait for, if any.
+*/
+fn request(command: &Command) -> Result<(OscPacket, Option<Reply>), String> {
+    let name = command.word(0, "command")?;
+
+    let message = |addr: &str, args: Vec<OscType>| {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        })
+    };
+
+    match name.as_str() {
+        "load-sample" => Ok((
+            OscPacket::Message(
+                LoadSampleMessage {
+                    file_path: command.word(1, "file")?,
+                    sample_pack: command.word(2, "sample pack")?,
+                    buffer_number: command.parsed_word(3, "buffer number")?,
+                    category_tag: command.flags.get("category").cloned().unwrap_or_default(),
+                    tone_index: command.flag("tone-index")?.unwrap_or(0),
+                }
+                .to_osc(),
+            ),
+            None,
+        )),
+        "create-synthdef" => {
+            let path = command.word(1, "scd file")?;
+            let definition =
+                fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+            // The daemon replies with an empty name when it cannot find one either
+            let name = scd_templating::parse_synthdef_name(&definition).unwrap_or_default();
+
+            Ok((
+                message("/create_synthdef", vec![OscType::String(definition)]),
+                Reply::new("/create_synthdef_finished", Some(name)),
+            ))
+        }
+        "set-bpm" => Ok((
+            message(
+                "/set_bpm",
+                vec![OscType::Int(command.parsed_word(1, "bpm")?)],
+            ),
+            None,
+        )),
+        "status" => Ok((
+            message("/get_status", vec![]),
+            Reply::new("/get_status_reply", None),
+        )),
+        "nrt-render" => {
+            let (rows, total) = ctl::read_score(&command.word(1, "score file")?)?;
+            let file_name = command.word(2, "output file")?;
+            let end_beat = match command.flag("end-beat")? {
+                Some(end_beat) => end_beat,
+                None => (total + BigDecimal::from(SCORE_TAIL_BEATS))
+                    .to_f32()
+                    .unwrap_or(0.0),
+            };
+
+            Ok((
+                NRTRecordMessage {
+                    file_name: file_name.clone(),
+                    bpm: command.flag("bpm")?.unwrap_or(120.0),
+                    messages: rows,
+                    end_beat,
+                }
+                .to_osc(),
+                Reply::new("/nrt_record_finished", Some(file_name)),
+            ))
+        }
+        _ => Ok((OscPacket::Message(ctl::note_message(&name, command)?), None)),
+    }
+}
+
+// Wait for the first message matching the reply on the socket, unwrapping bundles
+fn await_reply(socket: &UdpSocket, reply: &Reply, timeout: Duration) -> Result<OscMessage, String> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; rosc::decoder::MTU];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        socket
+            .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))
+            .map_err(|e| e.to_string())?;
+
+        let size = match socket.recv_from(&mut buf) {
+            Ok((size, _)) => size,
+            Err(_) => continue,
+        };
+
+        let mut packets = match rosc::decoder::decode_udp(&buf[..size]) {
+            Ok((_, packet)) => vec![packet],
+            Err(_) => continue,
+        };
+
+        while let Some(packet) = packets.pop() {
+            match packet {
+                OscPacket::Message(msg) if reply.matches(&msg) => return Ok(msg),
+                OscPacket::Message(_) => {}
+                OscPacket::Bundle(bundle) => packets.extend(bundle.content.into_iter().rev()),
+            }
+        }
+    }
+
+    Err(format!("No {} within {}s", reply.addr, timeout.as_secs()))
+}
+
+// /subscribe or /unsubscribe the reply port
+fn subscription(config: &Config, addr: &str, args: Vec<OscType>) -> Result<(), String> {
+    osc_daemon::send_to_daemon(
+        config,
+        &OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        }),
+    )
+}
+
+fn default_timeout(config: &Config, reply_addr: &str) -> Duration {
+    match reply_addr {
+        "/nrt_record_finished" => {
+            Duration::from_secs(config.nrt_done_timeout_secs + config.scd_reply_timeout_secs)
+        }
+        // Compiled by sclang, then loaded by scsynth
+        _ => Duration::from_secs(config.scd_reply_timeout_secs * 2),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = Command::parse(args.iter().map(|arg| arg.as_str()));
+
+    if command.words.is_empty() || command.flags.contains_key("help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let config_path = command
+        .flags
+        .get("config")
+        .cloned()
+        .unwrap_or("config.toml".to_string());
+    let config = config::load(&config_path).unwrap_or_else(|e| fail(&e));
+
+    let (packet, reply) = request(&command).unwrap_or_else(|e| fail(&e));
+
+    let timeout = match command.flag::<u64>("timeout") {
+        Ok(Some(secs)) => Duration::from_secs(secs),
+        Ok(None) => reply
+            .as_ref()
+            .map(|reply| default_timeout(&config, reply.addr))
+            .unwrap_or_default(),
+        Err(e) => fail(&e),
+    };
+
+    let reply = match reply {
+        Some(reply) => reply,
+        None => {
+            osc_daemon::send_to_daemon(&config, &packet).unwrap_or_else(|e| fail(&e));
+            println!("Sent");
+            return;
+        }
+    };
+
+    // A port of our own leaves outgoing_port to other clients, e.g. the sequencer
+    let socket = UdpSocket::bind(config.addr(0))
+        .unwrap_or_else(|e| fail(&format!("Cannot listen for replies: {}", e)));
+    let port = socket
+        .local_addr()
+        .map(|addr| addr.port() as i32)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+
+    /*
+        Subscribed before sending, so that a fast reply is not missed. The subscription outlives
+            the wait by a second, in case we are killed before we unsubscribe.
+    */
+    let lifetime = OscType::Int(timeout.as_secs() as i32 + 1);
+    subscription(&config, "/subscribe", vec![OscType::Int(port), lifetime])
+        .unwrap_or_else(|e| fail(&e));
+    let reply = osc_daemon::send_to_daemon(&config, &packet)
+        .and_then(|_| await_reply(&socket, &reply, timeout));
+    subscription(&config, "/unsubscribe", vec![OscType::Int(port)]).ok();
+
+    let reply = reply.unwrap_or_else(|e| fail(&e));
+
+    println!("{}", dry_run::readable_message(&reply));
+
+    if reply.args.first() == Some(&OscType::String("FAILURE".to_string())) {
+        exit(1);
+    }
+}
@@ -0,0 +1,539 @@
+/*
+   Stand-in for sclang and scsynth, for running jdw-sc end to end without SuperCollider.
+
+   Point sclang_binary (or scsynth_binary, with boot_mode = "scsynth") at this binary. It is
+       started the same way as the real programs and behaves like them as far as jdw-sc can tell:
+
+   As sclang (started with the boot script and "-u <sclang_in_port>"):
+       - Serves the scsynth side on the server port from the boot script
+       - Sends /init ok and /server_pid to the out socket of the boot script
+       - Answers /read_scd with /read_scd_done; "<server>.quit;" quits
+       - Writes a compiled SynthDef for the synthdef cache script, built from the SynthDef name
+           and arg list in the source
+       - Writes a silent WAV for /read_scd_file with an NRT score and replies /nrt_done ok
+
+   As scsynth (started with "-u <server_in_port>"), replying to the sender like scsynth does:
+       - /status with /status.reply. CPU load is 1% per running synth
+       - /d_recv, /d_load, /b_allocRead, /b_free, /notify and /quit with /done; /sync with /synced
+       - /s_new, /g_new, /p_new, /n_set gate 0, /n_free and /g_freeAll update the node tree, which
+           is reported as /n_go and /n_end to clients registered with /notify
+       - Bundles are executed immediately, regardless of their timetag
+*/
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+use jdw_sc::scd_templating;
+use jdw_sc::scsyndef;
+use jdw_sc::synthdef_builder::{Rate, SynthDefBuilder};
+use regex::Regex;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+
+const SAMPLE_RATE: u32 = 44100;
+
+// Time between starting as sclang and reporting /init, leaving jdw-sc time to bind server_out_port
+const BOOT_MS: u64 = 250;
+
+#[derive(Clone, Copy)]
+struct Node {
+    parent: i32,
+    is_group: bool,
+}
+
+#[derive(Default)]
+struct Server {
+    nodes: BTreeMap<i32, Node>, // Excluding the root group 0
+    synthdefs: HashSet<String>,
+    notify: Vec<SocketAddr>, // Clients registered with /notify 1
+    generated_ids: i32,      // Count of node ids generated for /s_new with node id -1
+}
+
+fn log(message: &str) {
+    println!("[fake-sc] {}", message);
+}
+
+fn send(socket: &UdpSocket, addr: SocketAddr, msg_addr: &str, args: Vec<OscType>) {
+    let packet = OscPacket::Message(OscMessage {
+        addr: msg_addr.to_string(),
+        args,
+    });
+
+    match encoder::encode(&packet) {
+        Ok(buf) => {
+            socket.send_to(&buf, addr).ok();
+        }
+        Err(e) => log(&format!("Failed to encode {}: {}", msg_addr, e)),
+    }
+}
+
+fn string(value: &str) -> OscType {
+    OscType::String(value.to_string())
+}
+
+fn int_at(msg: &OscMessage, index: usize) -> Option<i32> {
+    match msg.args.get(index) {
+        Some(OscType::Int(value)) => Some(*value),
+        Some(OscType::Float(value)) => Some(*value as i32),
+        _ => None,
+    }
+}
+
+fn string_at(msg: &OscMessage, index: usize) -> Option<String> {
+    msg.args.get(index).and_then(|arg| arg.clone().string())
+}
+
+fn float_at(msg: &OscMessage, index: usize) -> Option<f32> {
+    match msg.args.get(index) {
+        Some(OscType::Float(value)) => Some(*value),
+        Some(OscType::Int(value)) => Some(*value as f32),
+        _ => None,
+    }
+}
+
+// Receive messages on the socket until the process exits, unwrapping bundles
+fn serve(socket: UdpSocket, mut handle: impl FnMut(&UdpSocket, OscMessage, SocketAddr)) {
+    let mut buf = [0u8; rosc::decoder::MTU * 64];
+
+    loop {
+        let (size, sender) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+
+        let mut packets = match rosc::decoder::decode_udp(&buf[..size]) {
+            Ok((_, packet)) => vec![packet],
+            Err(_) => continue,
+        };
+
+        while let Some(packet) = packets.pop() {
+            match packet {
+                OscPacket::Message(msg) => handle(&socket, msg, sender),
+                OscPacket::Bundle(bundle) => packets.extend(bundle.content.into_iter().rev()),
+            }
+        }
+    }
+}
+
+impl Server {
+    fn notify_all(&self, socket: &UdpSocket, addr: &str, node_id: i32, node: &Node) {
+        for client in &self.notify {
+            send(
+                socket,
+                *client,
+                addr,
+                vec![
+                    OscType::Int(node_id),
+                    OscType::Int(node.parent),
+                    OscType::Int(-1),
+                    OscType::Int(-1),
+                    OscType::Int(node.is_group as i32),
+                ],
+            );
+        }
+    }
+
+    fn add_node(&mut self, socket: &UdpSocket, node_id: i32, target: i32, is_group: bool) {
+        let node = Node {
+            parent: target,
+            is_group,
+        };
+
+        self.nodes.insert(node_id, node);
+        self.notify_all(socket, "/n_go", node_id, &node);
+    }
+
+    // Remove a node along with everything in it
+    fn free_node(&mut self, socket: &UdpSocket, node_id: i32) {
+        let children: Vec<i32> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.parent == node_id)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for child in children {
+            self.free_node(socket, child);
+        }
+
+        if let Some(node) = self.nodes.remove(&node_id) {
+            self.notify_all(socket, "/n_end", node_id, &node);
+        }
+    }
+
+    fn synth_count(&self) -> i32 {
+        self.nodes.values().filter(|node| !node.is_group).count() as i32
+    }
+
+    fn status_reply(&self) -> Vec<OscType> {
+        let synths = self.synth_count();
+        let groups = self.nodes.len() as i32 - synths + 1; // Including the root group
+        let cpu = (synths as f32).min(100.0);
+
+        vec![
+            OscType::Int(1),
+            OscType::Int(synths),
+            OscType::Int(synths),
+            OscType::Int(groups),
+            OscType::Int(self.synthdefs.len() as i32),
+            OscType::Float(cpu),
+            OscType::Float(cpu),
+            OscType::Double(SAMPLE_RATE as f64),
+            OscType::Double(SAMPLE_RATE as f64),
+        ]
+    }
+
+    fn load_synthdef(&mut self, bytes: &[u8]) {
+        match scsyndef::decode(bytes) {
+            Ok(compiled) => {
+                self.synthdefs.insert(compiled.name);
+            }
+            Err(e) => log(&format!("Ignoring unreadable synthdef: {}", e)),
+        }
+    }
+
+    fn handle_scsynth(&mut self, socket: &UdpSocket, msg: OscMessage, sender: SocketAddr) {
+        let done = |addr: &str, mut args: Vec<OscType>| {
+            args.insert(0, string(addr));
+            send(socket, sender, "/done", args);
+        };
+
+        match msg.addr.as_str() {
+            "/status" => send(socket, sender, "/status.reply", self.status_reply()),
+            "/notify" => {
+                if int_at(&msg, 0) == Some(0) {
+                    self.notify.retain(|client| *client != sender);
+                } else if !self.notify.contains(&sender) {
+                    self.notify.push(sender);
+                }
+                done("/notify", vec![OscType::Int(0)]);
+            }
+            "/sync" => send(socket, sender, "/synced", msg.args.clone()),
+            "/d_recv" => {
+                if let Some(OscType::Blob(bytes)) = msg.args.get(0) {
+                    self.load_synthdef(bytes);
+                }
+                done("/d_recv", vec![]);
+            }
+            "/d_load" => {
+                match string_at(&msg, 0).map(fs::read) {
+                    Some(Ok(bytes)) => self.load_synthdef(&bytes),
+                    _ => log(&format!("Cannot read synthdef file {:?}", msg.args.get(0))),
+                }
+                done("/d_load", vec![]);
+            }
+            "/d_free" => {
+                for name in msg.args.iter().filter_map(|arg| arg.clone().string()) {
+                    self.synthdefs.remove(&name);
+                }
+            }
+            "/s_new" => {
+                let node_id = match int_at(&msg, 1) {
+                    Some(id) if id >= 0 => id,
+                    // Generated ids are negative, as in scsynth
+                    _ => {
+                        self.generated_ids += 1;
+                        -1000 - self.generated_ids
+                    }
+                };
+                let target = int_at(&msg, 3).unwrap_or(0);
+                self.add_node(socket, node_id, target, false);
+            }
+            "/g_new" | "/p_new" => {
+                for triple in msg.args.chunks(3) {
+                    if let (Some(OscType::Int(node_id)), Some(OscType::Int(target))) =
+                        (triple.get(0), triple.get(2))
+                    {
+                        self.add_node(socket, *node_id, *target, true);
+                    }
+                }
+            }
+            // Synths are assumed to free themselves once released, as with doneAction: 2
+            "/n_set" => {
+                let node_id = int_at(&msg, 0).unwrap_or(-1);
+                let released = (1..msg.args.len()).step_by(2).any(|index| {
+                    string_at(&msg, index).as_deref() == Some("gate")
+                        && float_at(&msg, index + 1) == Some(0.0)
+                });
+
+                if released && self.nodes.get(&node_id).is_some_and(|node| !node.is_group) {
+                    self.free_node(socket, node_id);
+                }
+            }
+            "/n_free" => {
+                for node_id in msg.args.iter().filter_map(|arg| arg.clone().int()) {
+                    self.free_node(socket, node_id);
+                }
+            }
+            "/g_freeAll" => {
+                for group in msg.args.iter().filter_map(|arg| arg.clone().int()) {
+                    let children: Vec<i32> = self
+                        .nodes
+                        .iter()
+                        .filter(|(_, node)| node.parent == group)
+                        .map(|(id, _)| *id)
+                        .collect();
+
+                    for child in children {
+                        self.free_node(socket, child);
+                    }
+                }
+            }
+            "/b_allocRead" | "/b_free" => {
+                done(&msg.addr, vec![OscType::Int(int_at(&msg, 0).unwrap_or(0))]);
+            }
+            "/quit" => {
+                done("/quit", vec![]);
+                log("Quit");
+                exit(0);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Controls declared in the first arg list of a SynthDef function, e.g. { |freq = 440, amp| ... }
+fn parse_controls(definition: &str) -> Vec<(String, f32)> {
+    let body = match definition.find("SynthDef") {
+        Some(index) => &definition[index..],
+        None => return vec![],
+    };
+
+    let regex = Regex::new(r"\{\s*(?:\|([^|]*)\||arg\s+([^;]*);)").unwrap();
+
+    let arg_list = match regex
+        .captures(body)
+        .and_then(|captures| captures.get(1).or(captures.get(2)))
+    {
+        Some(arg_list) => arg_list.as_str(),
+        None => return vec![],
+    };
+
+    arg_list
+        .split(',')
+        .filter_map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            let name = parts.next()?.trim();
+            let default = parts
+                .next()
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+
+            if name.is_empty() {
+                None
+            } else {
+                Some((name.to_string(), default))
+            }
+        })
+        .collect()
+}
+
+// Stand-in for the compiled form of a SynthDef source: same name and controls, silent output
+fn compile(definition: &str) -> Option<Vec<u8>> {
+    let name = scd_templating::parse_synthdef_name(definition)?;
+    let mut def = SynthDefBuilder::new(&name);
+
+    for (control, default) in parse_controls(definition) {
+        def.control(&control, default);
+    }
+
+    let sig = def.sin_osc(Rate::Audio, 440.0, 0.0);
+    let sig = def.mul(sig, 0.0);
+    def.out(0.0, &[sig]);
+
+    Some(def.encode())
+}
+
+fn silent_wav(channels: u16, seconds: f32) -> Vec<u8> {
+    let frames = (seconds.max(0.0) * SAMPLE_RATE as f32) as u32;
+    let data_size = frames * channels as u32 * 2;
+
+    let mut wav = vec![];
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * channels as u32 * 2).to_le_bytes());
+    wav.extend_from_slice(&(channels * 2).to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    wav.resize(wav.len() + data_size as usize, 0);
+    wav
+}
+
+// Render an NRT score script (see nrt_record.scd.template) as silence of the score's duration
+fn render_nrt(script: &str) -> Result<(), String> {
+    let capture = |pattern: &str| {
+        Regex::new(pattern)
+            .unwrap()
+            .captures(script)
+            .and_then(|captures| captures.get(1))
+            .map(|value| value.as_str().to_string())
+    };
+
+    let path = capture(r#"outputFilePath:\s*"([^"]*)""#).ok_or("No outputFilePath in score")?;
+    let duration = capture(r"duration:\s*([0-9.]+)")
+        .and_then(|value| value.parse::<f32>().ok())
+        .unwrap_or(0.0);
+    let channels = capture(r"numOutputBusChannels\s*=\s*(\d+)")
+        .and_then(|value| value.parse::<u16>().ok())
+        .unwrap_or(2);
+
+    fs::write(&path, silent_wav(channels, duration)).map_err(|e| e.to_string())
+}
+
+fn handle_sclang(socket: &UdpSocket, out: SocketAddr, msg: OscMessage) {
+    match msg.addr.as_str() {
+        "/read_scd" => {
+            let scd = string_at(&msg, 0).unwrap_or_default();
+            let request_id = string_at(&msg, 1).unwrap_or_default();
+
+            let result = match Regex::new(r#"File\.use\("((?:[^"\\]|\\.)*)", "wb""#)
+                .unwrap()
+                .captures(&scd)
+            {
+                Some(captures) => compile(&scd)
+                    .ok_or("Failed to parse scd string".to_string())
+                    .and_then(|bytes| {
+                        fs::write(
+                            captures[1].replace("\\\"", "\"").replace("\\\\", "\\"),
+                            bytes,
+                        )
+                        .map_err(|e| e.to_string())
+                    }),
+                None => Ok(()),
+            };
+
+            let (status, message) = match result {
+                Ok(()) => ("ok", "".to_string()),
+                Err(e) => ("error", e),
+            };
+
+            send(
+                socket,
+                out,
+                "/read_scd_done",
+                vec![string(&request_id), string(status), string(&message)],
+            );
+
+            if scd.trim_end().ends_with(".quit;") {
+                log("Quit");
+                exit(0);
+            }
+        }
+        "/read_scd_file" => {
+            let path = string_at(&msg, 0).unwrap_or_default();
+            log(&format!("Reading scd file: {}", path));
+
+            let script = fs::read_to_string(&path).unwrap_or_default();
+
+            if script.contains("recordNRT") {
+                match render_nrt(&script) {
+                    Ok(()) => send(socket, out, "/nrt_done", vec![string("ok")]),
+                    Err(e) => log(&format!("ERROR: NRT render failed: {}", e)),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn arg_after(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+fn fail(message: &str) -> ! {
+    log(&format!("ERROR: {}", message));
+    exit(1);
+}
+
+fn bind(ip: &str, port: &str) -> UdpSocket {
+    UdpSocket::bind(format!("{}:{}", ip, port))
+        .unwrap_or_else(|e| fail(&format!("Cannot bind {}:{}: {}", ip, port, e)))
+}
+
+// Server address and out socket address, in the order the boot script declares them
+fn boot_script_addresses(path: &str) -> (SocketAddr, SocketAddr) {
+    let script = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(&format!("Cannot read boot script {}: {}", path, e)));
+
+    let addresses: Vec<SocketAddr> = Regex::new(r#"NetAddr\.new\("([^"]+)",\s*(\d+)\)"#)
+        .unwrap()
+        .captures_iter(&script)
+        .filter_map(|captures| format!("{}:{}", &captures[1], &captures[2]).parse().ok())
+        .collect();
+
+    match addresses.as_slice() {
+        [server, out, ..] => (*server, *out),
+        _ => fail("Boot script lacks server and out socket addresses"),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let boot_script = args.get(1).filter(|arg| arg.ends_with(".scd"));
+
+    let (server_addr, out) = match boot_script {
+        // Running as sclang, serving the server address of the boot script
+        Some(path) => {
+            let (server_addr, out) = boot_script_addresses(path);
+
+            let sclang_port = arg_after(&args, "-u").unwrap_or("57120".to_string());
+            let sclang_socket = bind(&server_addr.ip().to_string(), &sclang_port);
+
+            thread::spawn(move || {
+                serve(sclang_socket, |socket, msg, _| {
+                    handle_sclang(socket, out, msg)
+                })
+            });
+
+            log(&format!("Client Port: {}", sclang_port));
+
+            (server_addr, Some(out))
+        }
+        // Running as scsynth
+        None => {
+            let ip = arg_after(&args, "-B").unwrap_or("127.0.0.1".to_string());
+            let port = arg_after(&args, "-u").unwrap_or("57110".to_string());
+            let server_addr = format!("{}:{}", ip, port)
+                .parse()
+                .unwrap_or_else(|_| fail("Invalid server address"));
+
+            (server_addr, None)
+        }
+    };
+
+    let server_socket = bind(
+        &server_addr.ip().to_string(),
+        &server_addr.port().to_string(),
+    );
+    log(&format!("Server listening on {}", server_addr));
+
+    // Sent after a short "boot", like the boot script does in waitForBoot
+    if let Some(out) = out {
+        thread::sleep(Duration::from_millis(BOOT_MS));
+        send(&server_socket, out, "/init", vec![string("ok")]);
+        send(
+            &server_socket,
+            out,
+            "/server_pid",
+            vec![OscType::Int(std::process::id() as i32)],
+        );
+    }
+
+    let mut server = Server::default();
+
+    serve(server_socket, |socket, msg, sender| {
+        server.handle_scsynth(socket, msg, sender)
+    });
+}
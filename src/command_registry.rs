@@ -0,0 +1,548 @@
+/*
+   Every command that jdw-sc understands, with its args, reply and description. Incoming messages
+       are validated against it before they are interpreted (and score messages before they are
+       rendered), /describe and the JSON endpoint report it, and the protocol docs are generated
+       from it (jdw-sc --protocol-docs).
+
+   Args are listed in order: required args first, then optional args, then at most one vararg that
+       takes all remaining args.
+*/
+use rosc::{OscMessage, OscType};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgType {
+    String,
+    Int,     // Int or Long
+    Decimal, // Any number, or a string such as "0.25"
+    Blob,
+    Any,
+}
+
+impl ArgType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgType::String => "string",
+            ArgType::Int => "int",
+            ArgType::Decimal => "decimal",
+            ArgType::Blob => "blob",
+            ArgType::Any => "any",
+        }
+    }
+
+    pub fn accepts(&self, arg: &OscType) -> bool {
+        match self {
+            ArgType::String => matches!(arg, OscType::String(_)),
+            ArgType::Int => matches!(arg, OscType::Int(_) | OscType::Long(_)),
+            ArgType::Decimal => matches!(
+                arg,
+                OscType::String(_)
+                    | OscType::Int(_)
+                    | OscType::Long(_)
+                    | OscType::Float(_)
+                    | OscType::Double(_)
+            ),
+            ArgType::Blob => matches!(arg, OscType::Blob(_)),
+            ArgType::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgKind {
+    Required,
+    Optional,
+    Vararg,
+}
+
+impl ArgKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgKind::Required => "required",
+            ArgKind::Optional => "optional",
+            ArgKind::Vararg => "vararg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub arg_type: ArgType,
+    pub kind: ArgKind,
+    pub description: &'static str,
+}
+
+const fn required(name: &'static str, arg_type: ArgType, description: &'static str) -> ArgSpec {
+    ArgSpec {
+        name,
+        arg_type,
+        kind: ArgKind::Required,
+        description,
+    }
+}
+
+const fn optional(name: &'static str, arg_type: ArgType, description: &'static str) -> ArgSpec {
+    ArgSpec {
+        name,
+        arg_type,
+        kind: ArgKind::Optional,
+        description,
+    }
+}
+
+const fn vararg(name: &'static str, description: &'static str) -> ArgSpec {
+    ArgSpec {
+        name,
+        arg_type: ArgType::Any,
+        kind: ArgKind::Vararg,
+        description,
+    }
+}
+
+const NAMED_ARGS: &str = "Name/value pairs of synth controls, e.g. \"freq\", 440.0. \"priority\" sets the load shedding priority of the note";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandSpec {
+    pub addr: &'static str,
+    pub args: &'static [ArgSpec],
+    pub reply: Option<&'static str>, // Sent to outgoing_port once handled
+    pub in_scores: bool,             // Can be a timed message of nrt_record and nrt_preload
+    pub description: &'static str,
+}
+
+impl CommandSpec {
+    pub fn validate(&self, msg: &OscMessage) -> Result<(), String> {
+        let required = self
+            .args
+            .iter()
+            .filter(|arg| arg.kind == ArgKind::Required)
+            .count();
+
+        if msg.args.len() < required {
+            return Err(format!(
+                "{} expects at least {} args, got {}",
+                self.addr,
+                required,
+                msg.args.len()
+            ));
+        }
+
+        let has_vararg = self.args.iter().any(|arg| arg.kind == ArgKind::Vararg);
+
+        if !has_vararg && msg.args.len() > self.args.len() {
+            return Err(format!(
+                "{} expects at most {} args, got {}",
+                self.addr,
+                self.args.len(),
+                msg.args.len()
+            ));
+        }
+
+        let positional = self.args.iter().filter(|arg| arg.kind != ArgKind::Vararg);
+
+        for (spec, arg) in positional.zip(msg.args.iter()) {
+            if !spec.arg_type.accepts(arg) {
+                return Err(format!(
+                    "{} arg {} must be {}, got {:?}",
+                    self.addr,
+                    spec.name,
+                    spec.arg_type.name(),
+                    arg
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        MSG OUT: /describe_reply, <addr>, <description>, <reply or "">, <in scores 0|1>,
+            [<arg name>, <type>, <"required"|"optional"|"vararg">, <description>]...
+    */
+    pub fn describe(&self) -> OscMessage {
+        let mut args = vec![
+            OscType::String(self.addr.to_string()),
+            OscType::String(self.description.to_string()),
+            OscType::String(self.reply.unwrap_or_default().to_string()),
+            OscType::Int(self.in_scores as i32),
+        ];
+
+        for arg in self.args {
+            args.push(OscType::String(arg.name.to_string()));
+            args.push(OscType::String(arg.arg_type.name().to_string()));
+            args.push(OscType::String(arg.kind.name().to_string()));
+            args.push(OscType::String(arg.description.to_string()));
+        }
+
+        OscMessage {
+            addr: "/describe_reply".to_string(),
+            args,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleSpec {
+    pub tag: &'static str,
+    pub layout: &'static str, // Contents following [/bundle_info, <tag>]
+    pub description: &'static str,
+}
+
+impl BundleSpec {
+    // MSG OUT: /describe_bundle_reply, <tag>, <layout>, <description>
+    pub fn describe(&self) -> OscMessage {
+        OscMessage {
+            addr: "/describe_bundle_reply".to_string(),
+            args: vec![
+                OscType::String(self.tag.to_string()),
+                OscType::String(self.layout.to_string()),
+                OscType::String(self.description.to_string()),
+            ],
+        }
+    }
+}
+
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        addr: "/note_on_timed",
+        args: &[
+            required("synth_name", ArgType::String, "Synthdef to play"),
+            required("external_id", ArgType::String, "Id for later /note_modify and /free_notes"),
+            required("gate_time", ArgType::Decimal, "Beats until the gate is released"),
+            required("delay_ms", ArgType::Int, "Delay from receive time"),
+            vararg("args", NAMED_ARGS),
+        ],
+        reply: None,
+        in_scores: true,
+        description: "Play a note that is released after gate_time",
+    },
+    CommandSpec {
+        addr: "/note_on",
+        args: &[
+            required("synth_name", ArgType::String, "Synthdef to play"),
+            required("external_id", ArgType::String, "Id for later /note_modify and /free_notes"),
+            required("delay_ms", ArgType::Int, "Delay from receive time"),
+            vararg("args", NAMED_ARGS),
+        ],
+        reply: None,
+        in_scores: true,
+        description: "Play a note until it is released with /note_modify (gate 0) or freed",
+    },
+    CommandSpec {
+        addr: "/note_modify",
+        args: &[
+            required("external_id_regex", ArgType::String, "Notes to modify"),
+            required("delay_ms", ArgType::Int, "Delay from receive time"),
+            vararg("args", "Name/value pairs of synth controls to set, e.g. \"gate\", 0"),
+        ],
+        reply: None,
+        in_scores: true,
+        description: "Set controls of all running notes with matching external ids",
+    },
+    CommandSpec {
+        addr: "/free_notes",
+        args: &[required("external_id_regex", ArgType::String, "Notes to free")],
+        reply: None,
+        in_scores: true,
+        description: "Free all running notes with matching external ids",
+    },
+    CommandSpec {
+        addr: "/play_sample",
+        args: &[
+            required("external_id", ArgType::String, "Id for later /note_modify and /free_notes"),
+            required("sample_pack", ArgType::String, "Sample pack of a loaded sample"),
+            required("index", ArgType::Int, "Sample number in the pack, or in the category"),
+            required("category", ArgType::String, "Category tag, \"\" for none"),
+            required("delay_ms", ArgType::Int, "Delay from receive time"),
+            vararg("args", NAMED_ARGS),
+        ],
+        reply: None,
+        in_scores: true,
+        description: "Play a loaded sample with the sampler synthdef",
+    },
+    CommandSpec {
+        addr: "/empty_message",
+        args: &[],
+        reply: None,
+        in_scores: true,
+        description: "Does nothing, for padding in scores",
+    },
+    CommandSpec {
+        addr: "/empty_msg",
+        args: &[],
+        reply: None,
+        in_scores: true,
+        description: "Same as /empty_message",
+    },
+    CommandSpec {
+        addr: "/load_sample",
+        args: &[
+            required("file_path", ArgType::String, "Sample file to read"),
+            required("sample_pack", ArgType::String, "Sample pack to add it to"),
+            required("buffer_number", ArgType::Int, "Server buffer, below num_buffers"),
+            required("category_tag", ArgType::String, "Category tag, \"\" for none"),
+            required("tone_index", ArgType::Int, "Index within the category"),
+        ],
+        reply: None,
+        in_scores: false,
+        description: "Load a sample into a server buffer for /play_sample",
+    },
+    CommandSpec {
+        addr: "/set_bpm",
+        args: &[required("bpm", ArgType::Int, "Beats per minute, above 0")],
+        reply: None,
+        in_scores: false,
+        description: "Tempo for gate times of /note_on_timed",
+    },
+    CommandSpec {
+        addr: "/jdw_sc_event_trigger",
+        args: &[
+            required("message", ArgType::String, "Passed back in the reply"),
+            required("delay_ms", ArgType::Int, "Delay from receive time"),
+        ],
+        reply: Some("/jdw_sc_event, <message>, <execution time>"),
+        in_scores: false,
+        description: "Reply with the time at which a note with the same delay would play",
+    },
+    CommandSpec {
+        addr: "/quit",
+        args: &[optional("fade_ms", ArgType::Int, "Overrides shutdown_fade_ms")],
+        reply: None,
+        in_scores: false,
+        description: "Fade out, free all notes and buffers and stop the server and daemon",
+    },
+    CommandSpec {
+        addr: "/read_scd",
+        args: &[
+            required("scd", ArgType::String, "Code to run in sclang"),
+            optional("request_id", ArgType::String, "Passed back in the reply"),
+        ],
+        reply: Some("/read_scd_done, <request id>, <\"ok\"|\"error\">, <error message>"),
+        in_scores: false,
+        description: "Run scd code in sclang",
+    },
+    CommandSpec {
+        addr: "/clear_nrt",
+        args: &[],
+        reply: None,
+        in_scores: false,
+        description: "Forget the synthdefs, samples and preloads kept for NRT scores",
+    },
+    CommandSpec {
+        addr: "/create_synthdef",
+        args: &[required("definition", ArgType::String, "SynthDef(...) scd code")],
+        reply: Some("/create_synthdef_finished, <\"SUCCESS\"|\"FAILURE\">, <synthdef name>, <error message>"),
+        in_scores: false,
+        description: "Compile and add a synthdef; re-sending a name replaces the old version",
+    },
+    CommandSpec {
+        addr: "/create_synthdef_bytes",
+        args: &[required("bytes", ArgType::Blob, "Compiled SynthDef2 file")],
+        reply: Some("/create_synthdef_finished, <\"SUCCESS\"|\"FAILURE\">, <synthdef name>, <error message>"),
+        in_scores: false,
+        description: "Add a compiled synthdef, e.g. built with synthdef_builder",
+    },
+    CommandSpec {
+        addr: "/free_synthdef",
+        args: &[required("name", ArgType::String, "Synthdef to free")],
+        reply: None,
+        in_scores: false,
+        description: "Remove a synthdef from the server; the sampler cannot be freed",
+    },
+    CommandSpec {
+        addr: "/synthdef_info",
+        args: &[required("name", ArgType::String, "Synthdef to look up")],
+        reply: Some("/synthdef_info_reply, <name>, <\"ok\"|\"unknown\">, [<control name>, <default value>]..."),
+        in_scores: false,
+        description: "Controls of a synthdef and their defaults",
+    },
+    CommandSpec {
+        addr: "/list_synthdefs",
+        args: &[],
+        reply: Some("/list_synthdefs_reply, [<name>, <updated time>]..."),
+        in_scores: false,
+        description: "Registered synthdefs and when each was last updated",
+    },
+    CommandSpec {
+        addr: "/create_group",
+        args: &[
+            required("name", ArgType::String, "Group name"),
+            required("external_id_regex", ArgType::String, "Notes to create in the group"),
+            optional("parallel", ArgType::Int, "1 (default) for a parallel group, 0 for a regular one"),
+        ],
+        reply: None,
+        in_scores: false,
+        description: "Create notes with matching external ids in a group; re-sending a name updates its regex",
+    },
+    CommandSpec {
+        addr: "/free_group",
+        args: &[required("name", ArgType::String, "Group name")],
+        reply: None,
+        in_scores: false,
+        description: "Free a group along with all notes playing in it",
+    },
+    CommandSpec {
+        addr: "/set_note_priority",
+        args: &[
+            required("external_id_regex", ArgType::String, "Notes to prioritise"),
+            required("priority", ArgType::Int, "Notes below shed_keep_priority are dropped under load"),
+        ],
+        reply: None,
+        in_scores: false,
+        description: "Load shedding priority of notes with matching external ids",
+    },
+    CommandSpec {
+        addr: "/get_shed_stats",
+        args: &[optional("reset", ArgType::String, "\"reset\" to reset the counts")],
+        reply: Some("/get_shed_stats_reply, <total dropped>, [<msg addr>, <dropped>]..."),
+        in_scores: false,
+        description: "Notes dropped by load shedding since boot or the last reset",
+    },
+    CommandSpec {
+        addr: "/get_status",
+        args: &[],
+        reply: Some("/get_status_reply, <\"ok\"|\"unavailable\">, [<ugens>, <synths>, <groups>, <synthdefs>, <avg cpu>, <peak cpu>, <nominal sample rate>, <actual sample rate>, <history length>, <max peak cpu>, <mean avg cpu>]"),
+        in_scores: false,
+        description: "Latest polled server status and a summary of the rolling history",
+    },
+    CommandSpec {
+        addr: "/describe",
+        args: &[],
+        reply: Some("/describe_reply per command and /describe_bundle_reply per bundle, see command_registry.rs"),
+        in_scores: false,
+        description: "All supported commands and bundles with their args",
+    },
+    CommandSpec {
+        addr: "/subscribe",
+        args: &[
+            required("port", ArgType::Int, "Port on application_ip"),
+            required("lifetime", ArgType::Int, "Seconds until the subscription expires"),
+        ],
+        reply: None,
+        in_scores: false,
+        description: "Also send everything that goes to outgoing_port to the port",
+    },
+    CommandSpec {
+        addr: "/unsubscribe",
+        args: &[required("port", ArgType::Int, "Port given to /subscribe")],
+        reply: None,
+        in_scores: false,
+        description: "Stop sending to a port registered with /subscribe",
+    },
+];
+
+pub static BUNDLES: &[BundleSpec] = &[
+    BundleSpec {
+        tag: "batch-send",
+        layout: "<packet>...",
+        description: "Packets that are interpreted one by one on arrival",
+    },
+    BundleSpec {
+        tag: "real_time_packet",
+        layout: "[/real_time_packet_info, <send time>], <packet>",
+        description: "A packet with the time it was sent, used as its receive time",
+    },
+    BundleSpec {
+        tag: "timed_msg",
+        layout: "[/timed_msg_info, <beats to next row>], <message>",
+        description: "Row of an nrt_record or nrt_preload score",
+    },
+    BundleSpec {
+        tag: "nrt_preload",
+        layout: "<timed_msg bundle>...",
+        description: "Score rows placed at the start of every following nrt_record, until /clear_nrt",
+    },
+    BundleSpec {
+        tag: "nrt_record",
+        layout: "[/nrt_record_info, <bpm>, <file name>, <end beat>], [<timed_msg bundle>...]",
+        description: "Render a score to a sound file. Replies /nrt_record_finished, <\"SUCCESS\"|\"FAILURE\">, <file name>",
+    },
+];
+
+pub fn find(addr: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|command| command.addr == addr)
+}
+
+// Err if the message does not match the args of its command; unknown addresses are left to the caller
+pub fn validate(msg: &OscMessage) -> Result<(), String> {
+    match find(&msg.addr) {
+        Some(command) => command.validate(msg)?,
+        None => return Ok(()),
+    }
+
+    // Ranges that arg types do not cover
+    match (msg.addr.as_str(), msg.args.get(0)) {
+        ("/set_bpm", Some(OscType::Int(bpm))) if *bpm <= 0 => {
+            Err(format!("/set_bpm arg bpm must be above 0, got {}", bpm))
+        }
+        ("/set_bpm", Some(OscType::Long(bpm))) if *bpm <= 0 => {
+            Err(format!("/set_bpm arg bpm must be above 0, got {}", bpm))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Reply to /describe: one message per command, then one per bundle
+pub fn describe() -> Vec<OscMessage> {
+    COMMANDS
+        .iter()
+        .map(CommandSpec::describe)
+        .chain(BUNDLES.iter().map(BundleSpec::describe))
+        .collect()
+}
+
+pub fn to_json() -> Value {
+    json!({
+        "commands": COMMANDS,
+        "bundles": BUNDLES,
+    })
+}
+
+// Markdown reference of all commands and bundles
+pub fn protocol_markdown() -> String {
+    let mut doc = String::from("# jdw-sc protocol\n\n");
+    doc += "Generated from src/command_registry.rs with `jdw-sc --protocol-docs`.\n\n";
+    doc += "Messages are sent to `application_in_port`, replies go to `outgoing_port`.\n\n";
+    doc += "## Commands\n";
+
+    for command in COMMANDS {
+        doc += &format!("\n### {}\n\n{}.\n", command.addr, command.description);
+
+        if !command.args.is_empty() {
+            doc += "\n| Arg | Type | | Description |\n|---|---|---|---|\n";
+            for arg in command.args {
+                doc += &format!(
+                    "| {} | {} | {} | {} |\n",
+                    arg.name,
+                    arg.arg_type.name(),
+                    arg.kind.name(),
+                    arg.description.replace('|', "\\|")
+                );
+            }
+        }
+
+        if let Some(reply) = command.reply {
+            doc += &format!("\nReply: `{}`\n", reply);
+        }
+
+        if command.in_scores {
+            doc += "\nCan be used in `nrt_record` and `nrt_preload` scores.\n";
+        }
+    }
+
+    doc += "\n## Bundles\n\nTagged bundles start with `[/bundle_info, <tag>]`, followed by the contents below.\n";
+
+    for bundle in BUNDLES {
+        doc += &format!(
+            "\n### {}\n\n{}.\n\n`{}`\n",
+            bundle.tag, bundle.description, bundle.layout
+        );
+    }
+
+    doc
+}
@@ -1,13 +1,15 @@
+use crate::synthdef_registry::ArgValidationMode;
 use log::LevelFilter;
 use serde::Deserialize;
-use std::path::Path;
-use std::sync::OnceLock;
+use std::path::{Path, PathBuf};
 use toml::Value as TomlValue;
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
 static APP_NAME: &str = "sc";
 
-#[derive(Debug, Deserialize)]
+// Sample formats accepted by Score.recordNRT
+const NRT_SAMPLE_FORMATS: [&str; 7] = ["int8", "int16", "int24", "int32", "float", "double", "mulaw"];
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub application_ip: String,
     pub server_osc_socket_name: String,
@@ -33,6 +35,41 @@ pub struct Config {
     pub sample_channels: i32,
     pub group_id: i32,
     pub group_placement: i32,
+    pub shutdown_fade_ms: u64,
+    pub shutdown_timeout_secs: u64,
+    pub scd_reply_timeout_secs: u64,
+    pub arg_validation: String,
+    pub gateless_fade_ms: u64,
+    pub synthdef_dir: String,
+    pub synthdef_watch_interval_ms: u64,
+    pub boot_mode: String,
+    pub scsynth_binary: String,
+    pub num_output_bus_channels: i32,
+    pub num_input_bus_channels: i32,
+    pub sample_rate: i32,
+    pub block_size: i32,
+    pub num_buffers: i32,
+    pub max_nodes: i32,
+    pub num_wire_bufs: i32,
+    pub hardware_buffer_size: i32,
+    pub in_device: String,
+    pub out_device: String,
+    pub nrt_sample_format: String,
+    pub server_backend: String,
+    pub supernova_binary: String,
+    pub status_poll_interval_ms: u64,
+    pub status_history_size: usize,
+    pub status_alert_avg_cpu: f64,
+    pub status_alert_peak_cpu: f64,
+    pub status_alert_synths: i32,
+    pub status_alert_ugens: i32,
+    pub shed_peak_cpu: f64,
+    pub shed_avg_cpu: f64,
+    pub shed_synths: i32,
+    pub shed_keep_priority: i32,
+    pub journal_path: String,
+    pub json_port: i32,
+    pub state_dir: String,
 }
 
 impl Default for Config {
@@ -62,13 +99,49 @@ impl Default for Config {
             sample_channels: 2,
             group_id: 0,
             group_placement: 0,
+            shutdown_fade_ms: 0,
+            shutdown_timeout_secs: 5,
+            scd_reply_timeout_secs: 5,
+            arg_validation: "warn".to_string(),
+            gateless_fade_ms: 0,
+            synthdef_dir: "".to_string(),
+            synthdef_watch_interval_ms: 1000,
+            boot_mode: "sclang".to_string(),
+            scsynth_binary: "scsynth".to_string(),
+            num_output_bus_channels: 2,
+            num_input_bus_channels: 2,
+            sample_rate: 0,
+            block_size: 64,
+            num_buffers: 1024,
+            max_nodes: 1024,
+            num_wire_bufs: 64,
+            hardware_buffer_size: 0,
+            in_device: "".to_string(),
+            out_device: "".to_string(),
+            nrt_sample_format: "int16".to_string(),
+            server_backend: "scsynth".to_string(),
+            supernova_binary: "supernova".to_string(),
+            status_poll_interval_ms: 1000,
+            status_history_size: 300,
+            status_alert_avg_cpu: 0.0,
+            status_alert_peak_cpu: 80.0,
+            status_alert_synths: 0,
+            status_alert_ugens: 0,
+            shed_peak_cpu: 0.0,
+            shed_avg_cpu: 0.0,
+            shed_synths: 0,
+            shed_keep_priority: 1,
+            journal_path: "".to_string(),
+            json_port: 0,
+            state_dir: "~/.config/jdw-sc".to_string(),
         }
     }
 }
 
 impl Config {
-    pub fn get() -> &'static Config {
-        CONFIG.get().expect("Config not initialized")
+    // Local address for the given port
+    pub fn addr(&self, port: i32) -> String {
+        format!("{}:{}", self.application_ip, port)
     }
 
     pub fn log_level_filter(&self) -> LevelFilter {
@@ -81,31 +154,181 @@ impl Config {
             _ => LevelFilter::Debug,
         }
     }
+
+    /*
+        Directory for the pidfile, boot script and synthdef cache, with "~/" expanded.
+        Instances that run side by side need one each.
+    */
+    pub fn state_path(&self) -> Result<PathBuf, String> {
+        match self.state_dir.strip_prefix("~/") {
+            Some(relative) => home::home_dir()
+                .map(|home| home.join(relative))
+                .ok_or(format!(
+                    "Cannot expand state_dir {}: no home directory",
+                    self.state_dir
+                )),
+            None => Ok(PathBuf::from(&self.state_dir)),
+        }
+    }
+
+    // boot_mode "scsynth" runs the server without sclang, "sclang" boots through it
+    pub fn sclang_enabled(&self) -> bool {
+        self.boot_mode.to_lowercase() != "scsynth"
+    }
+
+    // NRT renders need a fixed rate; live servers use the device rate unless sample_rate is set
+    pub fn nrt_sample_rate(&self) -> i32 {
+        if self.sample_rate > 0 {
+            self.sample_rate
+        } else {
+            48000
+        }
+    }
+
+    /*
+        Check server options before boot, so that bad values fail with a clear message
+            instead of a server that refuses to start. Returns all problems found.
+    */
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        let positive = [
+            ("num_output_bus_channels", self.num_output_bus_channels),
+            ("block_size", self.block_size),
+            ("num_buffers", self.num_buffers),
+            ("max_nodes", self.max_nodes),
+            ("num_wire_bufs", self.num_wire_bufs),
+            ("supercollider_memory_bytes", self.supercollider_memory_bytes),
+            ("default_bpm", self.default_bpm),
+        ];
+
+        for (name, value) in positive {
+            if value <= 0 {
+                errors.push(format!("{} must be above 0, was {}", name, value));
+            }
+        }
+
+        // 0 means default for these
+        let non_negative = [
+            ("num_input_bus_channels", self.num_input_bus_channels),
+            ("sample_rate", self.sample_rate),
+            ("hardware_buffer_size", self.hardware_buffer_size),
+        ];
+
+        for (name, value) in non_negative {
+            if value < 0 {
+                errors.push(format!("{} cannot be negative, was {}", name, value));
+            }
+        }
+
+        // CPU usage in percent, 0 disables
+        let percentages = [
+            ("status_alert_avg_cpu", self.status_alert_avg_cpu),
+            ("status_alert_peak_cpu", self.status_alert_peak_cpu),
+            ("shed_peak_cpu", self.shed_peak_cpu),
+            ("shed_avg_cpu", self.shed_avg_cpu),
+        ];
+
+        for (name, value) in percentages {
+            if !(0.0..=100.0).contains(&value) {
+                errors.push(format!("{} must be between 0 and 100, was {}", name, value));
+            }
+        }
+
+        if self.block_size > 0 && (self.block_size as u32).count_ones() != 1 {
+            errors.push(format!("block_size must be a power of two, was {}", self.block_size));
+        }
+
+        if !["scsynth", "supernova"].contains(&self.server_backend.to_lowercase().as_str()) {
+            errors.push(format!(
+                "server_backend must be scsynth or supernova, was {}",
+                self.server_backend
+            ));
+        }
+
+        if !["sclang", "scsynth"].contains(&self.boot_mode.to_lowercase().as_str()) {
+            errors.push(format!(
+                "boot_mode must be sclang or scsynth, was {}",
+                self.boot_mode
+            ));
+        }
+
+        if !["off", "warn", "strip", "reject"]
+            .contains(&self.arg_validation.to_lowercase().as_str())
+        {
+            errors.push(format!(
+                "arg_validation must be off, warn, strip or reject, was {}",
+                self.arg_validation
+            ));
+        }
+
+        if self.state_dir.is_empty() {
+            errors.push("state_dir cannot be empty".to_string());
+        } else if let Err(e) = self.state_path() {
+            errors.push(e);
+        }
+
+        if !NRT_SAMPLE_FORMATS.contains(&self.nrt_sample_format.as_str()) {
+            errors.push(format!(
+                "nrt_sample_format must be one of {:?}, was {}",
+                NRT_SAMPLE_FORMATS, self.nrt_sample_format
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn supernova(&self) -> bool {
+        self.server_backend.to_lowercase() == "supernova"
+    }
+
+    // Server binary launched directly when running without sclang
+    pub fn server_binary(&self) -> &str {
+        if self.supernova() {
+            &self.supernova_binary
+        } else {
+            &self.scsynth_binary
+        }
+    }
+
+    pub fn arg_validation_mode(&self) -> ArgValidationMode {
+        match self.arg_validation.to_lowercase().as_str() {
+            "off" => ArgValidationMode::Off,
+            "strip" => ArgValidationMode::Strip,
+            "reject" => ArgValidationMode::Reject,
+            _ => ArgValidationMode::Warn,
+        }
+    }
 }
 
-fn central_config_path() -> String {
+fn central_config_path() -> Result<String, String> {
     if let Ok(path) = std::env::var("JDW_CONFIG") {
         if Path::new(&path).exists() {
-            return path;
+            return Ok(path);
         }
     }
     let home = std::env::var("HOME").ok();
     if let Some(home) = home {
         let xdg = Path::new(&home).join(".config").join("jdw.toml");
         if xdg.exists() {
-            return xdg.to_string_lossy().to_string();
+            return Ok(xdg.to_string_lossy().to_string());
         }
     }
-    eprintln!("Error: Central config not found at ~/.config/jdw.toml");
-    eprintln!("       Set $JDW_CONFIG to a custom path, or create the file.");
-    std::process::exit(1);
+    Err("Central config not found at ~/.config/jdw.toml. \
+         Set $JDW_CONFIG to a custom path, or create the file."
+        .to_string())
 }
 
-fn load_central_section() -> Option<TomlValue> {
-    let path = central_config_path();
-    let contents = std::fs::read_to_string(&path).ok()?;
-    let root: TomlValue = contents.parse().ok()?;
-    root.get(APP_NAME).cloned()
+fn load_central_section() -> Result<Option<TomlValue>, String> {
+    let path = central_config_path()?;
+    let root = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| contents.parse::<TomlValue>().ok());
+    Ok(root.and_then(|root| root.get(APP_NAME).cloned()))
 }
 
 fn merge_str(base: &mut String, overlay: &TomlValue, key: &str) {
@@ -133,7 +356,12 @@ fn merge_usize(base: &mut usize, overlay: &TomlValue, key: &str) {
 }
 
 fn merge_f64(base: &mut f64, overlay: &TomlValue, key: &str) {
-    if let Some(v) = overlay.get(key).and_then(|v| v.as_float()) {
+    // Whole numbers like 80 are integers in TOML
+    let value = overlay
+        .get(key)
+        .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)));
+
+    if let Some(v) = value {
         *base = v;
     }
 }
@@ -163,12 +391,51 @@ fn merge_config(base: &mut Config, overlay: &TomlValue) {
     merge_i32(&mut base.sample_channels, overlay, "sample_channels");
     merge_i32(&mut base.group_id, overlay, "group_id");
     merge_i32(&mut base.group_placement, overlay, "group_placement");
+    merge_u64(&mut base.shutdown_fade_ms, overlay, "shutdown_fade_ms");
+    merge_u64(&mut base.shutdown_timeout_secs, overlay, "shutdown_timeout_secs");
+    merge_u64(&mut base.scd_reply_timeout_secs, overlay, "scd_reply_timeout_secs");
+    merge_str(&mut base.arg_validation, overlay, "arg_validation");
+    merge_u64(&mut base.gateless_fade_ms, overlay, "gateless_fade_ms");
+    merge_str(&mut base.synthdef_dir, overlay, "synthdef_dir");
+    merge_u64(&mut base.synthdef_watch_interval_ms, overlay, "synthdef_watch_interval_ms");
+    merge_str(&mut base.boot_mode, overlay, "boot_mode");
+    merge_str(&mut base.scsynth_binary, overlay, "scsynth_binary");
+    merge_i32(&mut base.num_output_bus_channels, overlay, "num_output_bus_channels");
+    merge_i32(&mut base.num_input_bus_channels, overlay, "num_input_bus_channels");
+    merge_i32(&mut base.sample_rate, overlay, "sample_rate");
+    merge_i32(&mut base.block_size, overlay, "block_size");
+    merge_i32(&mut base.num_buffers, overlay, "num_buffers");
+    merge_i32(&mut base.max_nodes, overlay, "max_nodes");
+    merge_i32(&mut base.num_wire_bufs, overlay, "num_wire_bufs");
+    merge_i32(&mut base.hardware_buffer_size, overlay, "hardware_buffer_size");
+    merge_str(&mut base.in_device, overlay, "in_device");
+    merge_str(&mut base.out_device, overlay, "out_device");
+    merge_str(&mut base.nrt_sample_format, overlay, "nrt_sample_format");
+    merge_str(&mut base.server_backend, overlay, "server_backend");
+    merge_str(&mut base.supernova_binary, overlay, "supernova_binary");
+    merge_u64(&mut base.status_poll_interval_ms, overlay, "status_poll_interval_ms");
+    merge_usize(&mut base.status_history_size, overlay, "status_history_size");
+    merge_f64(&mut base.status_alert_avg_cpu, overlay, "status_alert_avg_cpu");
+    merge_f64(&mut base.status_alert_peak_cpu, overlay, "status_alert_peak_cpu");
+    merge_i32(&mut base.status_alert_synths, overlay, "status_alert_synths");
+    merge_i32(&mut base.status_alert_ugens, overlay, "status_alert_ugens");
+    merge_f64(&mut base.shed_peak_cpu, overlay, "shed_peak_cpu");
+    merge_f64(&mut base.shed_avg_cpu, overlay, "shed_avg_cpu");
+    merge_i32(&mut base.shed_synths, overlay, "shed_synths");
+    merge_i32(&mut base.shed_keep_priority, overlay, "shed_keep_priority");
+    merge_str(&mut base.journal_path, overlay, "journal_path");
+    merge_i32(&mut base.json_port, overlay, "json_port");
+    merge_str(&mut base.state_dir, overlay, "state_dir");
 }
 
-pub fn load(config_path: &str) -> Config {
+/*
+    Defaults, overridden by the [sc] section of the central jdw.toml and then by the file at config_path.
+    Err if the central config cannot be found.
+*/
+pub fn load(config_path: &str) -> Result<Config, String> {
     let mut cfg = Config::default();
 
-    if let Some(central) = load_central_section() {
+    if let Some(central) = load_central_section()? {
         merge_config(&mut cfg, &central);
     }
 
@@ -182,14 +449,5 @@ pub fn load(config_path: &str) -> Config {
         }
     }
 
-    cfg
-}
-
-pub fn init(path: &str) {
-    let config = load(path);
-    CONFIG.set(config).ok();
-}
-
-pub fn get_addr(port: i32) -> String {
-    format!("{}:{}", Config::get().application_ip, port)
+    Ok(cfg)
 }
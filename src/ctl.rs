@@ -0,0 +1,184 @@
+/*
+   Argument parsing for jdw-sc-ctl (src/bin/ctl.rs): commands with their --name=value flags and
+       <arg> <value> pairs, the messages of note commands and nrt-render score files.
+*/
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Zero};
+use jdw_osc_lib::model::TimedOSCPacket;
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::osc_model::{
+    FreeNotesMessage, NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage, PlaySampleMessage,
+};
+
+pub const USAGE: &str = "Usage: jdw-sc-ctl [--config=<path>] [--timeout=<secs>] <command> [<args>]
+Commands: note, modify, free, play-sample, load-sample, create-synthdef, set-bpm, status, nrt-render";
+
+pub struct Command {
+    pub words: Vec<String>,
+    pub flags: HashMap<String, String>,
+}
+
+impl Command {
+    // Splits --name=value flags from the positional words
+    pub fn parse<'a>(args: impl Iterator<Item = &'a str>) -> Command {
+        let mut words = vec![];
+        let mut flags = HashMap::new();
+
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some(flag) => {
+                    let (name, value) = flag.split_once('=').unwrap_or((flag, ""));
+                    flags.insert(name.to_string(), value.to_string());
+                }
+                None => words.push(arg.to_string()),
+            }
+        }
+
+        Command { words, flags }
+    }
+
+    pub fn word(&self, index: usize, name: &str) -> Result<String, String> {
+        self.words
+            .get(index)
+            .cloned()
+            .ok_or(format!("Missing <{}>", name))
+    }
+
+    pub fn parsed_word<T: FromStr>(&self, index: usize, name: &str) -> Result<T, String> {
+        self.word(index, name)?
+            .parse()
+            .map_err(|_| format!("Invalid <{}>", name))
+    }
+
+    pub fn flag<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.flags
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid --{}: {}", name, value))
+            })
+            .transpose()
+    }
+
+    // <arg> <value> pairs from the given word on
+    pub fn named_args(&self, from: usize) -> Result<Vec<OscType>, String> {
+        let pairs = self.words.get(from..).unwrap_or(&[]);
+
+        if pairs.len() % 2 != 0 {
+            return Err(format!(
+                "Arg {} has no value",
+                pairs.last().cloned().unwrap_or_default()
+            ));
+        }
+
+        Ok(pairs
+            .chunks(2)
+            .flat_map(|pair| {
+                let value = match pair[1].parse::<f32>() {
+                    Ok(number) => OscType::Float(number),
+                    Err(_) => OscType::String(pair[1].clone()),
+                };
+
+                vec![OscType::String(pair[0].clone()), value]
+            })
+            .collect())
+    }
+
+    pub fn delay_ms(&self) -> Result<u64, String> {
+        Ok(self.flag("delay")?.unwrap_or(0))
+    }
+}
+
+// Message for a note command, also used for score rows
+pub fn note_message(name: &str, command: &Command) -> Result<OscMessage, String> {
+    match name {
+        "note" => {
+            let synth_name = command.word(1, "synth")?;
+            let external_id = command.word(2, "external id")?;
+            let delay_ms = command.delay_ms()?;
+            let args = command.named_args(3)?;
+
+            match command.flag::<BigDecimal>("gate")? {
+                Some(gate_time) => Ok(NoteOnTimedMessage {
+                    synth_name,
+                    external_id,
+                    gate_time,
+                    delay_ms,
+                    args,
+                }
+                .to_osc()),
+                None => Ok(NoteOnMessage {
+                    synth_name,
+                    external_id,
+                    delay_ms,
+                    args,
+                }
+                .to_osc()),
+            }
+        }
+        "modify" => Ok(NoteModifyMessage {
+            external_id_regex: command.word(1, "external id regex")?,
+            delay_ms: command.delay_ms()?,
+            args: command.named_args(2)?,
+        }
+        .to_osc()),
+        "free" => Ok(FreeNotesMessage {
+            external_id_regex: command.word(1, "external id regex")?,
+        }
+        .to_osc()),
+        "play-sample" => Ok(PlaySampleMessage {
+            external_id: command.word(1, "external id")?,
+            sample_pack: command.word(2, "sample pack")?,
+            index: command.parsed_word(3, "index")?,
+            category: command.flags.get("category").cloned(),
+            delay_ms: command.delay_ms()?,
+            args: command.named_args(4)?,
+        }
+        .to_osc()),
+        _ => Err(format!("Unknown command: {}\n{}", name, USAGE)),
+    }
+}
+
+/*
+    Rows of a score file, see the top of src/bin/ctl.rs.
+    Returns the timed rows and the total number of beats waited.
+*/
+pub fn read_score(path: &str) -> Result<(Vec<TimedOSCPacket>, BigDecimal), String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let mut rows = vec![];
+    let mut total = BigDecimal::zero();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let in_line = |e: String| format!("{} line {}: {}", path, line_number + 1, e);
+
+        let wait = words
+            .next()
+            .and_then(|word| BigDecimal::from_str(word).ok())
+            .ok_or(in_line("Expected the beats to wait first".to_string()))?;
+
+        let row = Command::parse(words);
+        let name = row.word(0, "command").map_err(in_line)?;
+        let message = note_message(&name, &row).map_err(in_line)?;
+
+        total += wait.clone();
+        rows.push(TimedOSCPacket {
+            time: wait,
+            packet: OscPacket::Message(message),
+        });
+    }
+
+    Ok((rows, total))
+}
@@ -0,0 +1,273 @@
+/*
+   Dry run backend: prints everything that would be sent to scsynth and sclang instead of
+       sending it, so that the exact server traffic of a pattern can be inspected without booting
+       SuperCollider.
+
+   Each scsynth message is printed on its own line with the resolved OSC timetag and the node ids
+       it targets, either readable or as one JSON object per line:
+
+       scsynth @1700000000.250 (+250ms, timetag 3908988800.1073741824) /s_new "default" 1000 0 100 [nodes 1000]
+       {"target":"scsynth","time":1700000000.25,"delay_ms":250,"timetag":[3908988800,1073741824],"addr":"/s_new","args":["default",1000,0,100],"nodes":[1000]}
+
+   Messages to clients are still sent to outgoing_port. Nothing ever replies, so commands that
+       await a reply from sclang or scsynth (e.g. create_synthdef, nrt_record) report a failure.
+*/
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::sc_backend::{self, ScBackend, Subscribers};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DryRunFormat {
+    Readable,
+    Json,
+}
+
+impl FromStr for DryRunFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DryRunFormat, String> {
+        match s {
+            "readable" => Ok(DryRunFormat::Readable),
+            "json" => Ok(DryRunFormat::Json),
+            _ => Err(format!(
+                "Unknown dry run format '{}', expected readable or json",
+                s
+            )),
+        }
+    }
+}
+
+pub struct DryRunBackend {
+    config: Arc<Config>,
+    format: DryRunFormat,
+    socket: UdpSocket,
+    events: Option<Sender<OscMessage>>, // Receives a copy of everything sent out, when embedded
+    subscribers: Subscribers,
+}
+
+impl DryRunBackend {
+    pub fn new(
+        config: Arc<Config>,
+        format: DryRunFormat,
+        events: Option<Sender<OscMessage>>,
+    ) -> std::io::Result<DryRunBackend> {
+        let socket = UdpSocket::bind(config.addr(0))?;
+
+        Ok(DryRunBackend {
+            config,
+            format,
+            socket,
+            events,
+            subscribers: Subscribers::default(),
+        })
+    }
+
+    // Traffic alone goes to stdout; the logger writes to stderr (simple_logger's stderr feature)
+    fn print(&self, line: String) {
+        println!("{}", line);
+    }
+}
+
+impl ScBackend for DryRunBackend {
+    fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    fn has_sclang(&self) -> bool {
+        self.config.sclang_enabled()
+    }
+
+    fn send_to_scsynth_with_delay(&self, msg: OscPacket, delay_ms: u64, receive_time: SystemTime) {
+        if let OscPacket::Bundle(OscBundle { timetag, content }) =
+            sc_backend::timed_bundle(msg, delay_ms, receive_time)
+        {
+            for msg in flatten(content) {
+                let execute_at = receive_time + Duration::from_millis(delay_ms);
+                self.print(format_scsynth(
+                    self.format,
+                    &msg,
+                    timetag,
+                    execute_at,
+                    delay_ms,
+                ));
+            }
+        }
+    }
+
+    fn send_to_sclang(&self, msg: OscMessage) {
+        self.print(format_sclang(self.format, &msg));
+    }
+
+    fn send_out(&self, msg: OscMessage) {
+        if let Some(events) = &self.events {
+            // The receiver being gone only means that nobody listens anymore
+            events.send(msg.clone()).ok();
+        }
+
+        let msg_buf = encoder::encode(&OscPacket::Message(msg)).unwrap();
+        if let Err(e) = self
+            .socket
+            .send_to(&msg_buf, self.config.addr(self.config.outgoing_port))
+        {
+            warn!("Failed to send to outgoing port: {}", e);
+        }
+        self.subscribers.send(&self.socket, &self.config, &msg_buf);
+    }
+
+    fn subscribers(&self) -> &Subscribers {
+        &self.subscribers
+    }
+
+    fn await_message(
+        &self,
+        message_name: &str,
+        _matches: &dyn Fn(&OscMessage) -> bool,
+        _timeout: Duration,
+    ) -> Result<OscMessage, String> {
+        warn!("Dry run: no {} will ever arrive", message_name);
+        Err(format!("Dry run, no {} received", message_name))
+    }
+
+    fn take_messages(&self) -> Vec<OscMessage> {
+        vec![]
+    }
+
+    fn has_replies(&self) -> bool {
+        false
+    }
+}
+
+// Nested bundles (e.g. from real_time_packet) execute at the time of the outer bundle
+fn flatten(content: Vec<OscPacket>) -> Vec<OscMessage> {
+    content
+        .into_iter()
+        .flat_map(|packet| match packet {
+            OscPacket::Message(msg) => vec![msg],
+            OscPacket::Bundle(bundle) => flatten(bundle.content),
+        })
+        .collect()
+}
+
+// Ids of the nodes (or groups) that a server command creates or targets
+pub fn node_ids(msg: &OscMessage) -> Vec<i32> {
+    let ints = |args: Vec<&OscType>| {
+        args.into_iter()
+            .filter_map(|arg| arg.clone().int())
+            .collect::<Vec<i32>>()
+    };
+
+    match msg.addr.as_str() {
+        // MSG: /s_new, <name>, <node id>, <add action>, <target>, ...
+        "/s_new" => ints(msg.args.iter().skip(1).take(1).collect()),
+        // MSG: /g_new, [<node id>, <add action>, <target>]...
+        "/g_new" | "/p_new" => ints(msg.args.iter().step_by(3).collect()),
+        "/n_set" | "/n_setn" | "/n_fill" | "/n_map" | "/n_mapn" | "/n_mapa" | "/n_mapan"
+        | "/n_run" | "/n_before" | "/n_after" | "/n_order" => {
+            ints(msg.args.iter().take(1).collect())
+        }
+        "/n_free" | "/g_freeAll" | "/g_deepFree" | "/n_query" => ints(msg.args.iter().collect()),
+        _ => vec![],
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+fn readable_arg(arg: &OscType) -> String {
+    match arg {
+        OscType::Int(value) => value.to_string(),
+        OscType::Long(value) => value.to_string(),
+        OscType::Float(value) => value.to_string(),
+        OscType::Double(value) => value.to_string(),
+        OscType::String(value) => format!("{:?}", value),
+        OscType::Bool(value) => value.to_string(),
+        OscType::Blob(value) => format!("<blob {} bytes>", value.len()),
+        other => format!("{:?}", other),
+    }
+}
+
+fn json_arg(arg: &OscType) -> Value {
+    match arg {
+        OscType::Int(value) => json!(value),
+        OscType::Long(value) => json!(value),
+        OscType::Float(value) => json!(value),
+        OscType::Double(value) => json!(value),
+        OscType::String(value) => json!(value),
+        OscType::Bool(value) => json!(value),
+        OscType::Blob(value) => json!({ "blob_bytes": value.len() }),
+        other => json!(format!("{:?}", other)),
+    }
+}
+
+pub fn readable_message(msg: &OscMessage) -> String {
+    std::iter::once(msg.addr.clone())
+        .chain(msg.args.iter().map(readable_arg))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn format_scsynth(
+    format: DryRunFormat,
+    msg: &OscMessage,
+    timetag: OscTime,
+    execute_at: SystemTime,
+    delay_ms: u64,
+) -> String {
+    let nodes = node_ids(msg);
+
+    match format {
+        DryRunFormat::Readable => {
+            let nodes = if nodes.is_empty() {
+                String::new()
+            } else {
+                let ids: Vec<String> = nodes.iter().map(|id| id.to_string()).collect();
+                format!(" [nodes {}]", ids.join(" "))
+            };
+
+            format!(
+                "scsynth @{:.3} (+{}ms, timetag {}.{}) {}{}",
+                unix_seconds(execute_at),
+                delay_ms,
+                timetag.seconds,
+                timetag.fractional,
+                readable_message(msg),
+                nodes
+            )
+        }
+        DryRunFormat::Json => json!({
+            "target": "scsynth",
+            "time": unix_seconds(execute_at),
+            "delay_ms": delay_ms,
+            "timetag": [timetag.seconds, timetag.fractional],
+            "addr": msg.addr,
+            "args": msg.args.iter().map(json_arg).collect::<Vec<Value>>(),
+            "nodes": nodes,
+        })
+        .to_string(),
+    }
+}
+
+// sclang messages are not timetagged, they are interpreted on arrival
+pub fn format_sclang(format: DryRunFormat, msg: &OscMessage) -> String {
+    match format {
+        DryRunFormat::Readable => format!("sclang {}", readable_message(msg)),
+        DryRunFormat::Json => json!({
+            "target": "sclang",
+            "addr": msg.addr,
+            "args": msg.args.iter().map(json_arg).collect::<Vec<Value>>(),
+        })
+        .to_string(),
+    }
+}
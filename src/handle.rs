@@ -0,0 +1,444 @@
+/*
+   Embeddable entry point: boots SuperCollider and runs the daemon on its own thread.
+
+   All state is owned by the handle and the config value it was built with. Nothing exits the
+       process and no signal handlers are installed unless requested with signal_handlers(true).
+
+   Example:
+
+       let mut sc = JdwSc::builder(config::load("config.toml")?).build();
+       let events = sc.events().unwrap();
+       sc.start()?;
+       sc.send(OscPacket::Message(OscMessage { addr: "/list_synthdefs".to_string(), args: vec![] }))?;
+       let reply = events.recv();
+       let exit_code = sc.shutdown()?;
+*/
+use std::net::UdpSocket;
+use std::process::exit;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use jdw_osc_lib::model::{OscArgHandler, TimedOSCPacket};
+use log::{info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::config::Config;
+use crate::dry_run::{DryRunBackend, DryRunFormat};
+use crate::internal_osc_conversion::SuperColliderMessage;
+use crate::journal::Replay;
+use crate::json_endpoint;
+use crate::node_lookup::NodeIDRegistry;
+use crate::osc_daemon;
+use crate::osc_model::NoteOnTimedMessage;
+use crate::pidfile;
+use crate::sc_backend::ScBackend;
+use crate::sc_process_management::{self, SCClient};
+use crate::scd_templating;
+use crate::shutdown::ShutdownHandle;
+use crate::status_monitor::StatusMonitor;
+
+pub struct JdwScBuilder {
+    config: Config,
+    signal_handlers: bool,
+    dry_run: Option<DryRunFormat>,
+    replay: Option<Replay>,
+}
+
+impl JdwScBuilder {
+    pub fn new(config: Config) -> JdwScBuilder {
+        JdwScBuilder {
+            config,
+            signal_handlers: false,
+            dry_run: None,
+            replay: None,
+        }
+    }
+
+    /*
+        Handle SIGINT, SIGTERM and SIGHUP by running the shutdown sequence and exiting the process.
+        Handlers can only be installed once per process.
+    */
+    pub fn signal_handlers(mut self, enabled: bool) -> JdwScBuilder {
+        self.signal_handlers = enabled;
+        self
+    }
+
+    /*
+        Print all scsynth and sclang traffic in the given format instead of booting SuperCollider.
+        See dry_run.rs.
+    */
+    pub fn dry_run(mut self, format: Option<DryRunFormat>) -> JdwScBuilder {
+        self.dry_run = format;
+        self
+    }
+
+    /*
+        Feed a recorded session journal into the daemon once it is started, before it handles
+            anything from application_in_port. See journal.rs.
+    */
+    pub fn replay(mut self, replay: Option<Replay>) -> JdwScBuilder {
+        self.replay = replay;
+        self
+    }
+
+    /*
+        Directory for the pidfile, the synthdef cache and the generated boot script.
+        Instances running side by side need one each. A leading ~/ is expanded to the home directory.
+    */
+    pub fn state_dir(mut self, dir: &str) -> JdwScBuilder {
+        self.config.state_dir = dir.to_string();
+        self
+    }
+
+    pub fn build(self) -> JdwSc {
+        let (events_sender, events) = mpsc::channel();
+
+        JdwSc {
+            config: Arc::new(self.config),
+            signal_handlers: self.signal_handlers,
+            dry_run: self.dry_run,
+            replay: self.replay,
+            events_sender,
+            events: Some(events),
+            running: None,
+        }
+    }
+}
+
+struct Running {
+    shutdown: ShutdownHandle,
+    daemon: JoinHandle<i32>,
+    packets: Sender<OscPacket>, // Passed to the daemon loop, see send
+}
+
+pub struct JdwSc {
+    config: Arc<Config>,
+    signal_handlers: bool,
+    dry_run: Option<DryRunFormat>,
+    replay: Option<Replay>,
+    events_sender: Sender<OscMessage>,
+    events: Option<Receiver<OscMessage>>,
+    running: Option<Running>,
+}
+
+impl JdwSc {
+    pub fn builder(config: Config) -> JdwScBuilder {
+        JdwScBuilder::new(config)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /*
+        Receiver for every message the daemon sends out (replies, status reports, errors), in
+            addition to them being sent to outgoing_port. Can only be taken once, and must be
+            taken before start() - otherwise events are not collected at all.
+    */
+    pub fn events(&mut self) -> Option<Receiver<OscMessage>> {
+        self.events.take()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|running| !running.daemon.is_finished())
+    }
+
+    /*
+        Boot the server and start the daemon. Returns once the daemon accepts messages.
+        In dry run mode, nothing is booted and the daemon prints its server traffic instead.
+        On failure, any processes launched so far are stopped again.
+    */
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.running.is_some() {
+            return Err("jdw-sc is already started".to_string());
+        }
+
+        self.config
+            .validate()
+            .map_err(|errors| format!("Invalid config: {}", errors.join(", ")))?;
+
+        // Bound before booting so that a taken port fails start() instead of the daemon thread
+        let sock =
+            UdpSocket::bind(self.config.addr(self.config.application_in_port)).map_err(|e| {
+                format!(
+                    "Failed to bind application_in_port {}: {}",
+                    self.config.application_in_port, e
+                )
+            })?;
+
+        // Without a taker, events would only pile up in the channel
+        let events = match self.events {
+            Some(_) => None,
+            None => Some(self.events_sender.clone()),
+        };
+
+        // Packets from JSON clients and send(), handled by the daemon loop
+        let (packets_sender, packets) = mpsc::channel();
+
+        // JSON clients get everything that is sent out, before it is passed on to events
+        let events = if self.config.json_port > 0 {
+            Some(json_endpoint::spawn(
+                self.config.clone(),
+                packets_sender.clone(),
+                events,
+            )?)
+        } else {
+            events
+        };
+
+        if let Some(format) = self.dry_run {
+            let client = DryRunBackend::new(self.config.clone(), format, events)
+                .map_err(|e| format!("Failed to create dry run socket: {}", e))?;
+            let shutdown = ShutdownHandle::without_process(self.config.clone());
+            self.install_signal_handlers(&shutdown)?;
+
+            info!("Dry run, SuperCollider is not booted");
+
+            let status = StatusMonitor::new(self.config.status_history_size);
+            let replay = self.replay.take();
+            return self.spawn_daemon(shutdown.clone(), packets_sender, move |sampler_def| {
+                osc_daemon::serve(sock, packets, client, sampler_def, shutdown, status, replay)
+            });
+        }
+
+        let sc_process_data = sc_process_management::init(self.config.clone(), events)
+            .map_err(|err| format!("Error booting SuperCollider: {:?}", err))?;
+
+        let client = sc_process_data.client;
+        let shutdown = ShutdownHandle::new(sc_process_data.process, self.config.clone());
+
+        self.install_signal_handlers(&shutdown)?;
+
+        if let Err(e) = await_ready(&client, shutdown.child_pid()) {
+            shutdown.handle_signal();
+            return Err(e);
+        }
+
+        info!("Server online!");
+
+        // The "default" synthdef is added by sclang on boot
+        if client.has_sclang() {
+            let node_reg = Arc::new(Mutex::new(NodeIDRegistry::new(&self.config)));
+            for freq in [130.81, 146.83, 196.00] {
+                client.send_timed_packets_to_scsynth(
+                    0,
+                    beep(freq, node_reg.clone()),
+                    SystemTime::now(),
+                );
+                sleep(Duration::from_millis(125));
+            }
+        }
+
+        let daemon_shutdown = shutdown.clone();
+        let replay = self.replay.take();
+        self.spawn_daemon(shutdown, packets_sender, move |sampler_def| {
+            osc_daemon::run(sock, packets, client, sampler_def, daemon_shutdown, replay)
+        })
+    }
+
+    fn install_signal_handlers(&self, shutdown: &ShutdownHandle) -> Result<(), String> {
+        if self.signal_handlers {
+            let shutdown_signal = shutdown.clone();
+
+            // Handles SIGINT, SIGTERM and SIGHUP
+            let installed = ctrlc::set_handler(move || {
+                info!("Shutdown requested by signal");
+                exit(shutdown_signal.handle_signal());
+            });
+
+            if let Err(e) = installed {
+                shutdown.handle_signal();
+                return Err(format!("Error setting signal handler: {}", e));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Run the daemon on its own thread and wait until it accepts messages
+    fn spawn_daemon<F>(
+        &mut self,
+        shutdown: ShutdownHandle,
+        packets: Sender<OscPacket>,
+        daemon: F,
+    ) -> Result<(), String>
+    where
+        F: FnOnce(String) -> i32 + Send + 'static,
+    {
+        // Loaded by the interpreter on startup
+        let sampler_def = scd_templating::read_scd_file("sampler.scd");
+
+        let daemon = thread::Builder::new()
+            .name("jdw-sc-daemon".to_string())
+            .spawn(move || daemon(sampler_def))
+            .map_err(|e| format!("Failed to start daemon thread: {}", e))?;
+
+        while !shutdown.is_listening() && !daemon.is_finished() {
+            sleep(Duration::from_millis(self.config.poll_sleep_ms));
+        }
+
+        if daemon.is_finished() {
+            shutdown.handle_signal();
+            return Err("Daemon stopped during startup".to_string());
+        }
+
+        info!("Startup completed, polling for messages ...");
+
+        self.running = Some(Running {
+            shutdown,
+            daemon,
+            packets,
+        });
+        Ok(())
+    }
+
+    /*
+        Pass a packet to the running daemon, handled as if it arrived on application_in_port.
+        Not limited to the size of a UDP datagram.
+    */
+    pub fn send(&self, packet: OscPacket) -> Result<(), String> {
+        let running = self
+            .running
+            .as_ref()
+            .filter(|_| self.is_running())
+            .ok_or("jdw-sc is not running".to_string())?;
+
+        running
+            .packets
+            .send(packet)
+            .map_err(|_| "jdw-sc is not running".to_string())
+    }
+
+    /*
+        Block until the daemon has handled a /quit, returning the exit code of the shutdown sequence
+            (see shutdown::EXIT_CLEAN and friends).
+    */
+    pub fn wait(&mut self) -> Result<i32, String> {
+        let running = self
+            .running
+            .take()
+            .ok_or("jdw-sc is not running".to_string())?;
+
+        running
+            .daemon
+            .join()
+            .map_err(|_| "Daemon thread panicked".to_string())
+    }
+
+    /*
+        Run the shutdown sequence (see /quit) and stop the daemon.
+        Returns the exit code of the shutdown sequence.
+    */
+    pub fn shutdown(&mut self) -> Result<i32, String> {
+        let running = self
+            .running
+            .take()
+            .ok_or("jdw-sc is not running".to_string())?;
+
+        let code = running.shutdown.handle_signal();
+
+        if running.shutdown.exit_code().is_some() {
+            running
+                .daemon
+                .join()
+                .map_err(|_| "Daemon thread panicked".to_string())?;
+        } else {
+            warn!("Daemon did not handle /quit, its thread is left behind");
+        }
+
+        Ok(code)
+    }
+}
+
+impl Drop for JdwSc {
+    fn drop(&mut self) {
+        if self.running.is_some() {
+            if let Err(e) = self.shutdown() {
+                warn!("Shutdown on drop failed: {}", e);
+            }
+        }
+    }
+}
+
+fn await_ready(client: &SCClient, child_pid: Option<u32>) -> Result<(), String> {
+    let init_timeout = Duration::from_secs(client.config().init_wait_timeout_secs);
+
+    if client.has_sclang() {
+        client.await_internal_response(
+            "/init",
+            vec![OscType::String("ok".to_string())],
+            init_timeout,
+        )?;
+        register_server_pid(client, child_pid);
+    } else {
+        client.await_scsynth_ready(init_timeout)?;
+        create_default_group(client);
+    }
+
+    Ok(())
+}
+
+fn beep(freq: f32, node_reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
+    NoteOnTimedMessage::new(&OscMessage {
+        addr: "/note_on_timed".to_string(),
+        args: vec![
+            OscType::String("default".to_string()),
+            OscType::String("launch_ping_{nodeId}".to_string()),
+            OscType::String("0.125".to_string()),
+            OscType::Int(0),
+            OscType::String("freq".to_string()),
+            OscType::Float(freq),
+            OscType::String("amp".to_string()),
+            OscType::Float(1.0),
+        ],
+    })
+    .unwrap()
+    .as_osc(node_reg)
+}
+
+// sclang creates the default group on boot; without it, the configured group must be created here
+fn create_default_group(client: &SCClient) {
+    let group_id = client.config().group_id;
+
+    if group_id != 0 {
+        client.send_to_scsynth_with_delay(
+            OscPacket::Message(OscMessage {
+                addr: "/g_new".to_string(),
+                args: vec![OscType::Int(group_id), OscType::Int(0), OscType::Int(0)],
+            }),
+            0,
+            SystemTime::now(),
+        );
+    }
+}
+
+// Add the scsynth pid reported by the boot script to the pidfile next to sclang
+fn register_server_pid(client: &SCClient, sclang_pid: Option<u32>) {
+    let scsynth_pid = client
+        .await_internal_message(
+            "/server_pid",
+            Duration::from_secs(client.config().init_wait_timeout_secs),
+        )
+        .and_then(|msg| msg.get_int_at(0, "scsynth pid"))
+        .ok()
+        .filter(|pid| *pid > 0);
+
+    let mut processes = vec![];
+
+    if let Some(pid) = sclang_pid {
+        processes.push(pidfile::ManagedProcess::new("sclang", pid));
+    }
+
+    match scsynth_pid {
+        Some(pid) => processes.push(pidfile::ManagedProcess::new("scsynth", pid as u32)),
+        None => warn!("scsynth pid not reported, it will not be cleaned up after a crash"),
+    }
+
+    if let Err(e) = pidfile::write(client.config(), &processes) {
+        warn!("Failed to write pidfile: {}", e);
+    }
+}
@@ -1,7 +1,10 @@
-use crate::config;
+use crate::command_registry;
 use crate::node_lookup::NodeIDRegistry;
-use crate::osc_model::{NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage, PlaySampleMessage};
+use crate::osc_model::{
+    FreeNotesMessage, NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage, PlaySampleMessage,
+};
 use crate::sampling::SamplePackDict;
+use crate::synthdef_registry::SynthDefRegistry;
 use bigdecimal::{BigDecimal, FromPrimitive, Zero};
 use jdw_osc_lib::model::TimedOSCPacket;
 use log::{info, warn};
@@ -33,13 +36,19 @@ pub trait SuperColliderMessage {
     }
 }
 
-fn create_s_new(node_id: i32, synth_name: &str, msg_args: &Vec<OscType>) -> TimedOSCPacket {
-    let cfg = config::Config::get();
+// group is the target group, see NodeIDRegistry::group_for; placement is the add action
+fn create_s_new(
+    node_id: i32,
+    group: i32,
+    placement: i32,
+    synth_name: &str,
+    msg_args: &Vec<OscType>,
+) -> TimedOSCPacket {
     let mut final_args = vec![
         OscType::String(synth_name.to_string()),
         OscType::Int(node_id),
-        OscType::Int(cfg.group_id),
-        OscType::Int(cfg.group_placement),
+        OscType::Int(group),
+        OscType::Int(placement),
     ];
 
     final_args.extend(msg_args.clone());
@@ -57,33 +66,172 @@ fn create_s_new(node_id: i32, synth_name: &str, msg_args: &Vec<OscType>) -> Time
     }
 }
 
-impl SuperColliderMessage for NoteOnTimedMessage {
-    fn as_osc(&self, reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
-        return match reg.lock().unwrap().create_node_id(&self.external_id) {
-            Ok(node_id) => {
-                let on_message = create_s_new(node_id, &self.synth_name, &self.args);
+/*
+    How a timed note is ended. Synths without a gate control ignore "/n_set gate 0" and would
+    ring forever, so they are freed instead - optionally after a stepped fade through their amp control.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoteOffStrategy {
+    Gate,
+    Free,
+    Fade { duration_secs: f32, from_amp: f32 },
+}
 
-                let off_packet = OscPacket::Message(OscMessage {
-                    addr: "/n_set".to_string(),
-                    args: vec![
-                        OscType::Int(node_id),               // NodeID
-                        OscType::String("gate".to_string()), // gate=0 is note off
-                        OscType::Float(0.0),
-                    ],
-                });
-
-                let off_message = TimedOSCPacket {
-                    time: self.gate_time.clone(),
-                    packet: off_packet,
-                };
+// Number of amp steps used for NoteOffStrategy::Fade
+const FADE_STEPS: i32 = 8;
+
+// Pick a note off strategy based on the known controls of the synthdef; fade_ms is gateless_fade_ms
+pub fn note_off_strategy(
+    synthdefs: &SynthDefRegistry,
+    synth_name: &str,
+    args: &Vec<OscType>,
+    fade_ms: u64,
+) -> NoteOffStrategy {
+    let controls = match synthdefs.get(synth_name).and_then(|entry| entry.controls.as_ref()) {
+        Some(controls) => controls,
+        None => return NoteOffStrategy::Gate, // Unknown synth, assume the usual gate convention
+    };
+
+    if controls.iter().any(|control| control.name == "gate") {
+        return NoteOffStrategy::Gate;
+    }
+
+    let amp_control = controls.iter().find(|control| control.name == "amp");
+
+    match amp_control {
+        Some(control) if fade_ms > 0 => {
+            // Fade from the amp given in the note, falling back to the synthdef default
+            let from_amp = args
+                .chunks(2)
+                .find(|pair| pair[0].clone().string().is_some_and(|name| name == "amp"))
+                .and_then(|pair| pair.get(1).cloned())
+                .and_then(|value| match value {
+                    OscType::Float(amp) => Some(amp),
+                    OscType::Int(amp) => Some(amp as f32),
+                    _ => None,
+                })
+                .unwrap_or(control.default);
+
+            NoteOffStrategy::Fade {
+                duration_secs: fade_ms as f32 / 1000.0,
+                from_amp,
+            }
+        }
+        _ => NoteOffStrategy::Free,
+    }
+}
+
+fn create_node_message(addr: &str, args: Vec<OscType>, time: BigDecimal) -> TimedOSCPacket {
+    TimedOSCPacket {
+        time,
+        packet: OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        }),
+    }
+}
+
+/*
+    Messages ending a note at off_time, relative to note start. Times are in seconds, or in beats
+        for NRT scores, where time_per_sec converts the fade duration (bpm / 60).
+*/
+fn create_note_off(
+    node_id: i32,
+    off_time: BigDecimal,
+    strategy: &NoteOffStrategy,
+    time_per_sec: BigDecimal,
+) -> Vec<TimedOSCPacket> {
+    match strategy {
+        NoteOffStrategy::Gate => vec![create_node_message(
+            "/n_set",
+            vec![
+                OscType::Int(node_id),               // NodeID
+                OscType::String("gate".to_string()), // gate=0 is note off
+                OscType::Float(0.0),
+            ],
+            off_time,
+        )],
+        NoteOffStrategy::Free => vec![create_node_message(
+            "/n_free",
+            vec![OscType::Int(node_id)],
+            off_time,
+        )],
+        NoteOffStrategy::Fade {
+            duration_secs,
+            from_amp,
+        } => {
+            let step_time =
+                BigDecimal::from_f32(duration_secs / FADE_STEPS as f32).unwrap() * time_per_sec;
+
+            let mut packets: Vec<TimedOSCPacket> = (1..=FADE_STEPS)
+                .map(|step| {
+                    let amp = from_amp * (1.0 - step as f32 / FADE_STEPS as f32);
+                    create_node_message(
+                        "/n_set",
+                        vec![
+                            OscType::Int(node_id),
+                            OscType::String("amp".to_string()),
+                            OscType::Float(amp),
+                        ],
+                        off_time.clone() + step_time.clone() * BigDecimal::from(step - 1),
+                    )
+                })
+                .collect();
+
+            packets.push(create_node_message(
+                "/n_free",
+                vec![OscType::Int(node_id)],
+                off_time + step_time * BigDecimal::from(FADE_STEPS),
+            ));
+
+            packets
+        }
+    }
+}
 
-                vec![on_message, off_message]
+impl NoteOnTimedMessage {
+    // Timed in beats, for NRT scores
+    pub fn as_osc_with_note_off(
+        &self,
+        reg: Arc<Mutex<NodeIDRegistry>>,
+        note_off: &NoteOffStrategy,
+        bpm: f32,
+    ) -> Vec<TimedOSCPacket> {
+        let reg = reg.lock().unwrap();
+        return match reg.create_node_id(&self.external_id) {
+            Ok(node_id) => {
+                let group = reg.group_for(&self.external_id);
+                let on_message =
+                    create_s_new(node_id, group, reg.placement(), &self.synth_name, &self.args);
+
+                let mut packets = vec![on_message];
+                packets.extend(create_note_off(
+                    node_id,
+                    self.gate_time.clone(),
+                    note_off,
+                    BigDecimal::from_f32(bpm / 60.0).unwrap_or_default(),
+                ));
+                packets
             }
             Err(_) => vec![],
         };
     }
 }
 
+// Transitional struct carrying the note off strategy picked from synthdef controls, and the score bpm
+pub struct PreparedNoteOnTimedMessage {
+    pub message: NoteOnTimedMessage,
+    pub note_off: NoteOffStrategy,
+    pub bpm: f32,
+}
+
+impl SuperColliderMessage for PreparedNoteOnTimedMessage {
+    fn as_osc(&self, reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
+        self.message
+            .as_osc_with_note_off(reg, &self.note_off, self.bpm)
+    }
+}
+
 // TODO: Util lib
 
 fn seconds_from_beats(bpm: i32, beats: BigDecimal) -> BigDecimal {
@@ -92,35 +240,35 @@ fn seconds_from_beats(bpm: i32, beats: BigDecimal) -> BigDecimal {
 }
 
 impl NoteOnTimedMessage {
-    pub fn create_osc(&self, node_id: i32, bpm: i32) -> Vec<TimedOSCPacket> {
-        let on_message = create_s_new(node_id, &self.synth_name, &self.args);
-
-        let off_packet = OscPacket::Message(OscMessage {
-            addr: "/n_set".to_string(),
-            args: vec![
-                OscType::Int(node_id),               // NodeID
-                OscType::String("gate".to_string()), // gate=0 is note off
-                OscType::Float(0.0),
-            ],
-        });
+    pub fn create_osc(
+        &self,
+        node_id: i32,
+        group: i32,
+        placement: i32,
+        bpm: i32,
+        note_off: &NoteOffStrategy,
+    ) -> Vec<TimedOSCPacket> {
+        let on_message = create_s_new(node_id, group, placement, &self.synth_name, &self.args);
 
         // Calculate time of off-message as seconds-from-beats
 
         let seconds = seconds_from_beats(bpm, self.gate_time.clone());
         //info!("Sustain time was {}sec", seconds.clone());
 
-        let off_message = TimedOSCPacket {
-            time: seconds,
-            packet: off_packet,
-        };
-
-        vec![on_message, off_message]
+        let mut packets = vec![on_message];
+        packets.extend(create_note_off(
+            node_id,
+            seconds,
+            note_off,
+            BigDecimal::from(1),
+        ));
+        packets
     }
 }
 
 impl NoteOnMessage {
-    pub fn create_osc(&self, node_id: i32) -> Vec<TimedOSCPacket> {
-        let msg = create_s_new(node_id, &self.synth_name, &self.args);
+    pub fn create_osc(&self, node_id: i32, group: i32, placement: i32) -> Vec<TimedOSCPacket> {
+        let msg = create_s_new(node_id, group, placement, &self.synth_name, &self.args);
 
         vec![msg]
     }
@@ -128,9 +276,11 @@ impl NoteOnMessage {
 
 impl SuperColliderMessage for NoteOnMessage {
     fn as_osc(&self, reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
-        return match reg.lock().unwrap().create_node_id(&self.external_id) {
+        let reg = reg.lock().unwrap();
+        return match reg.create_node_id(&self.external_id) {
             Ok(node_id) => {
-                let msg = create_s_new(node_id, &self.synth_name, &self.args);
+                let group = reg.group_for(&self.external_id);
+                let msg = create_s_new(node_id, group, reg.placement(), &self.synth_name, &self.args);
 
                 vec![msg]
             }
@@ -194,6 +344,19 @@ impl SuperColliderMessage for NoteModifyMessage {
     }
 }
 
+impl SuperColliderMessage for FreeNotesMessage {
+    fn as_osc(&self, reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
+        let reg = reg.lock().unwrap();
+        let node_ids = reg.regex_search_node_ids(&self.external_id_regex);
+        reg.regex_clear_node_ids(&self.external_id_regex);
+
+        node_ids
+            .into_iter()
+            .map(|id| create_node_message("/n_free", vec![OscType::Int(id)], BigDecimal::zero()))
+            .collect()
+    }
+}
+
 // Transitional struct used to keep sample lookup logic out of osc_model
 // external osc message -> PlaySampleMessage -> PlaySampleInternalMessage -> internal osc, etc.
 pub struct PreparedPlaySampleMessage {
@@ -226,10 +389,14 @@ impl PlaySampleMessage {
 
 impl SuperColliderMessage for PreparedPlaySampleMessage {
     fn as_osc(&self, reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
-        return match reg.lock().unwrap().create_node_id(&self.external_id) {
+        let reg = reg.lock().unwrap();
+        return match reg.create_node_id(&self.external_id) {
             Ok(node_id) => {
                 vec![create_s_new(
-                    node_id, "sampler", // The "synth" used to play buffer samples
+                    node_id,
+                    reg.group_for(&self.external_id),
+                    reg.placement(),
+                    "sampler", // The "synth" used to play buffer samples
                     &self.args,
                 )]
             }
@@ -239,9 +406,9 @@ impl SuperColliderMessage for PreparedPlaySampleMessage {
 }
 
 impl PreparedPlaySampleMessage {
-    pub fn create_osc(&self, node_id: i32) -> Vec<TimedOSCPacket> {
+    pub fn create_osc(&self, node_id: i32, group: i32, placement: i32) -> Vec<TimedOSCPacket> {
         vec![create_s_new(
-            node_id, "sampler", // The "synth" used to play buffer samples
+            node_id, group, placement, "sampler", // The "synth" used to play buffer samples
             &self.args,
         )]
     }
@@ -251,6 +418,9 @@ impl PreparedPlaySampleMessage {
 pub fn resolve_msg(
     packet: OscPacket,
     dict: Arc<Mutex<SamplePackDict>>,
+    synthdefs: &SynthDefRegistry,
+    fade_ms: u64,
+    bpm: f32,
 ) -> Option<Box<dyn SuperColliderMessage>> {
     let msg = match packet {
         OscPacket::Message(msg) => Some(msg),
@@ -261,8 +431,22 @@ pub fn resolve_msg(
     }
     .unwrap();
 
+    if let Err(reason) = command_registry::validate(&msg) {
+        warn!("Skipped score message: {}", reason);
+        return None;
+    }
+
     let sc_msg: Option<Box<dyn SuperColliderMessage>> = match msg.addr.as_str() {
-        "/note_on_timed" => Some(Box::new(NoteOnTimedMessage::new(&msg.clone()).unwrap())),
+        "/note_on_timed" => {
+            let message = NoteOnTimedMessage::new(&msg.clone()).unwrap();
+            let note_off =
+                note_off_strategy(synthdefs, &message.synth_name, &message.args, fade_ms);
+            Some(Box::new(PreparedNoteOnTimedMessage {
+                message,
+                note_off,
+                bpm,
+            }))
+        }
         "/play_sample" => {
             // TODO: Wrangled this a bit, does
             return PlaySampleMessage::new(&msg.clone())
@@ -288,6 +472,7 @@ pub fn resolve_msg(
         }
         "/note_on" => Some(Box::new(NoteOnMessage::new(&msg.clone()).unwrap())),
         "/note_modify" => Some(Box::new(NoteModifyMessage::new(&msg.clone()).unwrap())),
+        "/free_notes" => Some(Box::new(FreeNotesMessage::new(&msg.clone()).unwrap())),
         "/empty_message" | "/empty_msg" => None, // silence padding, no-op
         msgtype => {
             warn!("Unknown message type: {}", msgtype);
@@ -0,0 +1,203 @@
+/*
+   Session journal: every packet received on application_in_port, with its receive time, appended
+       to the file at journal_path so that a live session can be replayed later.
+
+   File layout: the magic bytes "JDWJ" and a format version byte, followed by one record per packet:
+
+       <receive time, microseconds since the unix epoch: u64 BE> <packet size: u32 BE> <raw OSC packet>
+
+   Replay feeds the packets straight into the interpreter, with receive times shifted to start now.
+       Timetags sent to the server therefore keep their original spacing, even when packets are
+       replayed as fast as possible (e.g. into a dry run).
+*/
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use rosc::OscPacket;
+
+use crate::osc_daemon::Interpreter;
+use crate::sc_backend::ScBackend;
+
+const MAGIC: &[u8; 4] = b"JDWJ";
+const VERSION: u8 = 1;
+const RECORD_HEADER_SIZE: usize = 12;
+
+pub struct JournalWriter {
+    file: BufWriter<File>,
+}
+
+impl JournalWriter {
+    // Append to the journal at path, creating it if needed
+    pub fn open(path: &str) -> Result<JournalWriter, String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open journal {}: {}", path, e))?;
+
+        let is_new = file
+            .metadata()
+            .map(|meta| meta.len() == 0)
+            .map_err(|e| format!("Failed to read journal {}: {}", path, e))?;
+
+        if is_new {
+            file.write_all(MAGIC)
+                .and_then(|_| file.write_all(&[VERSION]))
+                .map_err(|e| format!("Failed to write journal {}: {}", path, e))?;
+        }
+
+        Ok(JournalWriter {
+            file: BufWriter::new(file),
+        })
+    }
+
+    // Flushed per packet, so that the journal survives a crash of the daemon
+    pub fn append(&mut self, receive_time: SystemTime, packet: &[u8]) -> std::io::Result<()> {
+        let micros = receive_time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        self.file.write_all(&micros.to_be_bytes())?;
+        self.file.write_all(&(packet.len() as u32).to_be_bytes())?;
+        self.file.write_all(packet)?;
+        self.file.flush()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub receive_time: SystemTime,
+    pub packet: OscPacket,
+}
+
+/*
+    All entries of the journal at path, in the order they were received.
+    A record cut short at the end (e.g. by a crash mid-write) is skipped with a warning.
+*/
+pub fn read(path: &str) -> Result<Vec<JournalEntry>, String> {
+    let mut bytes = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| format!("Failed to read journal {}: {}", path, e))?;
+
+    if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(format!("{} is not a jdw-sc journal", path));
+    }
+
+    if bytes[MAGIC.len()] != VERSION {
+        return Err(format!(
+            "Unsupported journal version {} in {}",
+            bytes[MAGIC.len()],
+            path
+        ));
+    }
+
+    let mut entries = vec![];
+    let mut rest = &bytes[MAGIC.len() + 1..];
+
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_SIZE {
+            warn!("Skipping truncated record at the end of journal {}", path);
+            break;
+        }
+
+        let micros = u64::from_be_bytes(rest[..8].try_into().unwrap());
+        let size = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+        rest = &rest[RECORD_HEADER_SIZE..];
+
+        if rest.len() < size {
+            warn!("Skipping truncated record at the end of journal {}", path);
+            break;
+        }
+
+        let (_, packet) = rosc::decoder::decode_udp(&rest[..size])
+            .map_err(|e| format!("Unreadable packet in journal {}: {:?}", path, e))?;
+
+        entries.push(JournalEntry {
+            receive_time: UNIX_EPOCH + Duration::from_micros(micros),
+            packet,
+        });
+
+        rest = &rest[size..];
+    }
+
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayTiming {
+    Original, // Wait out the original time between packets
+    Fast,     // Interpret all packets at once
+}
+
+impl FromStr for ReplayTiming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReplayTiming, String> {
+        match s {
+            "original" => Ok(ReplayTiming::Original),
+            "fast" => Ok(ReplayTiming::Fast),
+            _ => Err(format!(
+                "Unknown replay timing '{}', expected original or fast",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub entries: Vec<JournalEntry>,
+    pub timing: ReplayTiming,
+}
+
+impl Replay {
+    pub fn load(path: &str, timing: ReplayTiming) -> Result<Replay, String> {
+        Ok(Replay {
+            entries: read(path)?,
+            timing,
+        })
+    }
+
+    /*
+        Feed all entries into the interpreter, as if received from now on.
+        Stops early if a replayed /quit was handled, returning its exit code.
+    */
+    pub fn run<B: ScBackend>(&self, interpreter: &mut Interpreter<B>) -> Option<i32> {
+        let first = self.entries.first()?.receive_time;
+        let start = SystemTime::now();
+
+        info!("Replaying {} journal entries", self.entries.len());
+
+        for entry in &self.entries {
+            let offset = entry
+                .receive_time
+                .duration_since(first)
+                .unwrap_or(Duration::ZERO);
+            let receive_time = start + offset;
+
+            if self.timing == ReplayTiming::Original {
+                if let Ok(wait) = receive_time.duration_since(SystemTime::now()) {
+                    sleep(wait);
+                }
+            }
+
+            interpreter.interpret(entry.packet.clone(), receive_time);
+            interpreter.await_pending_requests();
+
+            if let Some(code) = interpreter.exit_code() {
+                info!("Replayed /quit, replay stopped");
+                return Some(code);
+            }
+        }
+
+        info!("Replay finished");
+        None
+    }
+}
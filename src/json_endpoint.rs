@@ -0,0 +1,236 @@
+/*
+   Line-delimited JSON interface on json_port (0 disables it), for scripting tools and web UIs that
+       find OSC awkward. Listens on application_ip like the OSC ports.
+
+   Each line is one command, named by "command" and with the fields of the matching osc_model type
+       (see osc_json.rs for args, packets and timelines):
+
+       {"command": "note_on_timed", "synth_name": "default", "external_id": "lead_1", "gate_time": 0.5, "delay_ms": 0, "args": ["freq", 440.0]}
+       {"command": "nrt_record", "file_name": "out.wav", "bpm": 120.0, "end_beat": 4.0, "messages": [{"time": "1.0", "packet": {"addr": "/note_on_timed", "args": [...]}}]}
+       {"command": "osc", "addr": "/set_bpm", "args": [96]}
+       {"command": "describe"}
+
+   Commands are passed to the daemon loop as their OSC equivalent, so that they are interpreted
+       (and journaled) like any other packet, without the size limit of a UDP datagram. Everything the daemon sends out is written to all
+       connected clients as {"addr": "/get_status_reply", "args": [...]}. Clients that do not read
+       their events within CLIENT_WRITE_TIMEOUT are disconnected. Lines that cannot be used
+       are answered with {"error": <reason>} to the sending client only, as are messages that do not
+       match their command in command_registry.rs.
+
+   "describe" is answered to the sending client only with {"commands": [...], "bundles": [...]}, the
+       JSON form of the /describe reply.
+*/
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::command_registry;
+use crate::config::Config;
+use crate::osc_json;
+use crate::osc_model::{
+    BatchSendMessage, FreeNotesMessage, LoadSampleMessage, NRTPreloadMessage, NRTRecordMessage,
+    NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage, PlaySampleMessage, RealTimePacket,
+};
+
+type Clients = Arc<Mutex<Vec<TcpStream>>>;
+
+// Writes to clients happen with the client list locked, so a stalled client holds up all others
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Any other message of the OSC protocol, e.g. /set_bpm or /create_synthdef
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonMessage {
+    pub addr: String,
+    #[serde(with = "osc_json::args", default)]
+    pub args: Vec<OscType>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum JsonCommand {
+    NoteOnTimed(NoteOnTimedMessage),
+    NoteOn(NoteOnMessage),
+    NoteModify(NoteModifyMessage),
+    FreeNotes(FreeNotesMessage),
+    PlaySample(PlaySampleMessage),
+    LoadSample(LoadSampleMessage),
+    BatchSend(BatchSendMessage),
+    RealTimePacket(RealTimePacket),
+    NrtPreload(NRTPreloadMessage),
+    NrtRecord(NRTRecordMessage),
+    Osc(JsonMessage),
+    Describe,
+}
+
+impl JsonCommand {
+    pub fn parse(line: &str) -> Result<JsonCommand, String> {
+        serde_json::from_str(line).map_err(|e| format!("Malformed command: {}", e))
+    }
+
+    pub fn to_osc(&self) -> OscPacket {
+        match self {
+            JsonCommand::NoteOnTimed(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::NoteOn(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::NoteModify(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::FreeNotes(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::PlaySample(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::LoadSample(msg) => OscPacket::Message(msg.to_osc()),
+            JsonCommand::BatchSend(bundle) => bundle.to_osc(),
+            JsonCommand::RealTimePacket(bundle) => bundle.to_osc(),
+            JsonCommand::NrtPreload(bundle) => bundle.to_osc(),
+            JsonCommand::NrtRecord(bundle) => bundle.to_osc(),
+            JsonCommand::Osc(msg) => OscPacket::Message(OscMessage {
+                addr: msg.addr.clone(),
+                args: msg.args.clone(),
+            }),
+            JsonCommand::Describe => OscPacket::Message(OscMessage {
+                addr: "/describe".to_string(),
+                args: vec![],
+            }),
+        }
+    }
+}
+
+// Line written to clients for a message sent out by the daemon
+pub fn event_line(msg: &OscMessage) -> String {
+    osc_json::message_to_json(msg).to_string()
+}
+
+/*
+    Start listening on json_port. Commands are passed on to the daemon loop through packets.
+    Returns the sender for everything the daemon sends out, which is written to the JSON clients
+        and then passed on to forward, if given.
+    Stops accepting clients once the returned sender has been dropped along with the daemon.
+*/
+pub fn spawn(
+    cfg: Arc<Config>,
+    packets: Sender<OscPacket>,
+    forward: Option<Sender<OscMessage>>,
+) -> Result<Sender<OscMessage>, String> {
+    let addr = cfg.addr(cfg.json_port);
+    let listener = TcpListener::bind(&addr)
+        .map_err(|e| format!("Failed to bind json_port {}: {}", addr, e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("Failed to configure json_port: {}", e))?;
+
+    let clients: Clients = Arc::new(Mutex::new(vec![]));
+    let closed = Arc::new(AtomicBool::new(false));
+    let (events, received) = mpsc::channel::<OscMessage>();
+
+    let broadcast_clients = clients.clone();
+    let broadcast_closed = closed.clone();
+    thread::spawn(move || {
+        for msg in received {
+            let line = event_line(&msg) + "\n";
+
+            // Clients that cannot be written to have disconnected or stalled
+            broadcast_clients.lock().unwrap().retain_mut(|client| {
+                let written = client.write_all(line.as_bytes()).is_ok();
+                if !written {
+                    // Also ends the reader thread of the client
+                    client.shutdown(Shutdown::Both).ok();
+                }
+                written
+            });
+
+            if let Some(forward) = &forward {
+                forward.send(msg).ok();
+            }
+        }
+
+        broadcast_closed.store(true, Ordering::SeqCst);
+    });
+
+    info!("Accepting JSON commands on {}", addr);
+
+    thread::spawn(move || {
+        while !closed.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    info!("JSON client connected from {}", peer);
+
+                    if let Err(e) = add_client(stream, packets.clone(), clients.clone()) {
+                        warn!("Failed to set up JSON client {}: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(cfg.poll_sleep_ms));
+                }
+                Err(e) => warn!("Failed to accept JSON client: {}", e),
+            }
+        }
+    });
+
+    Ok(events)
+}
+
+fn add_client(
+    stream: TcpStream,
+    packets: Sender<OscPacket>,
+    clients: Clients,
+) -> std::io::Result<()> {
+    // Accepted streams may inherit non-blocking mode from the listener
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+    let mut replies = stream.try_clone()?;
+    clients.lock().unwrap().push(stream.try_clone()?);
+
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let reply = match JsonCommand::parse(&line) {
+                Ok(JsonCommand::Describe) => Some(command_registry::to_json()),
+                Ok(command) => send_command(&packets, &command).err().map(|e| {
+                    warn!("Rejected JSON command: {}", e);
+                    json!({ "error": e })
+                }),
+                Err(e) => {
+                    warn!("Rejected JSON command: {}", e);
+                    Some(json!({ "error": e }))
+                }
+            };
+
+            if let Some(reply) = reply {
+                // Locked so that the reply is not interleaved with an event line
+                let _clients = clients.lock().unwrap();
+                let reply = reply.to_string() + "\n";
+                if replies.write_all(reply.as_bytes()).is_err() {
+                    replies.shutdown(Shutdown::Both).ok();
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn send_command(packets: &Sender<OscPacket>, command: &JsonCommand) -> Result<(), String> {
+    let packet = command.to_osc();
+
+    if let OscPacket::Message(msg) = &packet {
+        command_registry::validate(msg)?;
+    }
+
+    packets
+        .send(packet)
+        .map_err(|_| "jdw-sc is not running".to_string())
+}
@@ -1,33 +1,58 @@
 #![feature(result_flattening)]
 
+pub mod command_registry;
 pub mod config;
+pub mod ctl;
+pub mod dry_run;
+pub mod handle;
 pub mod internal_osc_conversion;
+pub mod journal;
+pub mod json_endpoint;
+pub mod load_shedding;
 pub mod node_lookup;
 pub mod nrt_record;
 pub mod osc_daemon;
+pub mod osc_json;
 pub mod osc_model;
+pub mod pidfile;
 pub mod sampling;
+pub mod sc_backend;
 pub mod sc_process_management;
+pub mod sclang_output;
 pub mod scd_templating;
-
-use crate::internal_osc_conversion::SuperColliderMessage;
-use crate::node_lookup::NodeIDRegistry;
-use crate::osc_model::NoteOnTimedMessage;
-use home::home_dir;
-use jdw_osc_lib::model::TimedOSCPacket;
-use log::{error, info};
-use rosc::{OscMessage, OscType};
+pub mod session_render;
+pub mod scsyndef;
+pub mod shutdown;
+pub mod status_monitor;
+pub mod synthdef_builder;
+pub mod synthdef_cache;
+pub mod synthdef_library;
+pub mod synthdef_registry;
+
+pub use crate::handle::{JdwSc, JdwScBuilder};
+
+use log::error;
 use std::process::exit;
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::{Duration, SystemTime};
 
-/// Run the jdw-sc daemon. Blocks the calling thread indefinitely.
+/// Run the jdw-sc daemon. Blocks the calling thread until a /quit or termination signal, then
+/// exits the process. Embedding applications should use [`JdwSc`] instead.
 ///
 /// * `config_path` – path to the per-app `config.toml`.
 /// * `quiet`       – suppress non-error log output.
-pub fn run(config_path: &str, quiet: bool) {
-    config::init(config_path);
+/// * `dry_run`     – print scsynth/sclang traffic in this format instead of booting SuperCollider.
+/// * `replay`      – path and timing of a session journal to feed into the daemon on startup.
+/// * `render`      – session journal and output file for an offline bounce, replacing `replay`.
+pub fn run(
+    config_path: &str,
+    quiet: bool,
+    dry_run: Option<dry_run::DryRunFormat>,
+    replay: Option<(&str, journal::ReplayTiming)>,
+    render: Option<(&str, &str)>,
+) {
+    let config = config::load(config_path).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        exit(1)
+    });
 
     // Logging must only be initialised once per process; in library mode the
     // caller is responsible. We attempt init here for the standalone-binary
@@ -36,89 +61,36 @@ pub fn run(config_path: &str, quiet: bool) {
         .with_level(if quiet {
             log::LevelFilter::Error
         } else {
-            config::Config::get().log_level_filter()
+            config.log_level_filter()
         })
         .init();
 
-    let sc_process_data = sc_process_management::init().unwrap_or_else(|err| {
-        error!("ERROR BOOTING SUPERCOLLIDER: {:?}", err);
-        exit(0)
-    });
+    let replay = match render {
+        Some((path, file_name)) => Some(session_render::load(path, file_name, config.default_bpm)),
+        None => replay.map(|(path, timing)| journal::Replay::load(path, timing)),
+    };
 
-    let client = sc_process_data.client;
+    let replay = replay.transpose().unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
 
-    let process_arc_interrupt = Arc::new(Mutex::new(sc_process_data.process));
-    let process_arc_failure = process_arc_interrupt.clone();
+    let mut sc = JdwSc::builder(config)
+        .signal_handlers(true)
+        .dry_run(dry_run)
+        .replay(replay)
+        .build();
 
-    ctrlc::set_handler(move || {
-        info!("Thread abort requested");
-        process_arc_interrupt
-            .clone()
-            .lock()
-            .unwrap()
-            .terminate()
-            .unwrap();
-        exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    if let Err(e) = sc.start() {
+        error!("{}", e);
+        exit(shutdown::EXIT_FAILED);
+    }
 
-    match client.await_internal_response(
-        "/init",
-        vec![OscType::String("ok".to_string())],
-        Duration::from_secs(config::Config::get().init_wait_timeout_secs),
-    ) {
+    match sc.wait() {
+        Ok(code) => exit(code),
         Err(e) => {
             error!("{}", e);
-            process_arc_failure.lock().unwrap().terminate().unwrap();
+            exit(shutdown::EXIT_FAILED)
         }
-        Ok(()) => (),
-    };
-
-    info!("Server online!");
-
-    let mut sample_pack_dir = home_dir().unwrap();
-    sample_pack_dir.push(
-        config::Config::get()
-            .sample_pack_dir
-            .trim_start_matches("~/"),
-    );
-
-    let node_reg = Arc::new(Mutex::new(NodeIDRegistry::new()));
-
-    let sampler_def = scd_templating::read_scd_file("sampler.scd");
-    client.send_to_sclang(OscMessage {
-        addr: "/read_scd".to_string(),
-        args: vec![OscType::String(sampler_def.clone() + ".add;")],
-    });
-
-    fn beep(freq: f32, node_reg: Arc<Mutex<NodeIDRegistry>>) -> Vec<TimedOSCPacket> {
-        NoteOnTimedMessage::new(&OscMessage {
-            addr: "/note_on_timed".to_string(),
-            args: vec![
-                OscType::String("default".to_string()),
-                OscType::String("launch_ping_{nodeId}".to_string()),
-                OscType::String("0.125".to_string()),
-                OscType::Int(0),
-                OscType::String("freq".to_string()),
-                OscType::Float(freq),
-                OscType::String("amp".to_string()),
-                OscType::Float(1.0),
-            ],
-        })
-        .unwrap()
-        .as_osc(node_reg)
     }
-
-    for i in [130.81, 146.83, 196.00] {
-        client.send_timed_packets_to_scsynth(0, beep(i, node_reg.clone()), SystemTime::now());
-        sleep(Duration::from_millis(125));
-    }
-
-    info!("Startup completed, polling for messages ...");
-
-    osc_daemon::run(
-        config::get_addr(config::Config::get().application_in_port),
-        client,
-        sampler_def,
-    );
 }
@@ -0,0 +1,155 @@
+/*
+   Drops new notes while scsynth is overloaded, according to the latest polled status.
+
+   Shedding is active while the latest status exceeds any enabled shed_* threshold (0 disables
+       a threshold, all are disabled by default). Statuses older than a few poll intervals are
+       ignored, so that a stalled poller never blocks notes indefinitely.
+   Notes whose priority is below shed_keep_priority are dropped. The priority is taken from an
+       explicit "priority" named arg, else from the first matching external id pattern, else 0.
+
+   MSG OUT: /jdw_sc_note_shed, <msg addr>, <external id>, <priority>, <total dropped>
+*/
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use regex::Regex;
+use rosc::OscType;
+
+use crate::config::Config;
+use crate::status_monitor::ServerStatus;
+
+// Polls missed before a status no longer counts as current
+const STALE_POLL_COUNT: u32 = 3;
+
+pub const PRIORITY_ARG: &str = "priority";
+
+pub struct LoadShedder {
+    patterns: Vec<(Regex, i32)>, // Checked in order of definition, first match wins
+    dropped: BTreeMap<String, u64>, // Dropped count per message address
+    config: Arc<Config>,
+}
+
+/*
+    Remove the "priority" named arg, if present, returning its value.
+    Notes never pass it on to the server.
+*/
+pub fn take_priority(args: &mut Vec<OscType>) -> Option<i32> {
+    let index = args
+        .chunks(2)
+        .position(|pair| pair[0] == OscType::String(PRIORITY_ARG.to_string()))?
+        * 2;
+
+    let value = match args.get(index + 1) {
+        Some(OscType::Int(value)) => Some(*value),
+        Some(OscType::Float(value)) => Some(*value as i32),
+        Some(OscType::Double(value)) => Some(*value as i32),
+        _ => None,
+    };
+
+    let end = (index + 2).min(args.len());
+    args.drain(index..end);
+
+    value
+}
+
+// Name of the first enabled threshold exceeded by the status
+pub fn exceeded_threshold(cfg: &Config, status: &ServerStatus) -> Option<&'static str> {
+    if cfg.shed_peak_cpu > 0.0 && status.peak_cpu as f64 > cfg.shed_peak_cpu {
+        Some("peak_cpu")
+    } else if cfg.shed_avg_cpu > 0.0 && status.avg_cpu as f64 > cfg.shed_avg_cpu {
+        Some("avg_cpu")
+    } else if cfg.shed_synths > 0 && status.synths > cfg.shed_synths {
+        Some("synths")
+    } else {
+        None
+    }
+}
+
+fn is_current(cfg: &Config, status: &ServerStatus) -> bool {
+    let poll_interval = cfg.status_poll_interval_ms;
+    let max_age = Duration::from_millis(poll_interval) * STALE_POLL_COUNT;
+
+    SystemTime::now()
+        .duration_since(status.time)
+        .map(|age| age <= max_age)
+        .unwrap_or(true)
+}
+
+impl LoadShedder {
+    pub fn new(config: Arc<Config>) -> LoadShedder {
+        LoadShedder {
+            patterns: vec![],
+            dropped: BTreeMap::new(),
+            config,
+        }
+    }
+
+    // Redefining an existing pattern only updates its priority
+    pub fn set_priority(&mut self, external_id_regex: &str, priority: i32) -> Result<(), String> {
+        if let Some(existing) = self
+            .patterns
+            .iter_mut()
+            .find(|(regex, _)| regex.as_str() == external_id_regex)
+        {
+            existing.1 = priority;
+            return Ok(());
+        }
+
+        let regex = Regex::new(external_id_regex).map_err(|e| e.to_string())?;
+        self.patterns.push((regex, priority));
+        Ok(())
+    }
+
+    pub fn priority_for(&self, external_id: &str) -> i32 {
+        self.patterns
+            .iter()
+            .find(|(regex, _)| regex.is_match(external_id))
+            .map(|(_, priority)| *priority)
+            .unwrap_or(0)
+    }
+
+    /*
+        Decide whether a new note should be dropped given the latest status.
+        Some holds the exceeded threshold when it should; the drop is counted.
+    */
+    pub fn check(
+        &mut self,
+        addr: &str,
+        external_id: &str,
+        priority: i32,
+        latest: Option<&ServerStatus>,
+    ) -> Option<&'static str> {
+        let cfg = &self.config;
+
+        if priority >= cfg.shed_keep_priority {
+            return None;
+        }
+
+        let threshold = latest
+            .filter(|status| is_current(cfg, status))
+            .and_then(|status| exceeded_threshold(cfg, status))?;
+
+        *self.dropped.entry(addr.to_string()).or_insert(0) += 1;
+
+        warn!(
+            "Server overloaded ({}), dropped {} {} with priority {}",
+            threshold, addr, external_id, priority
+        );
+
+        Some(threshold)
+    }
+
+    pub fn total_dropped(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+
+    pub fn dropped(&self) -> &BTreeMap<String, u64> {
+        &self.dropped
+    }
+
+    pub fn reset(&mut self) {
+        self.dropped.clear();
+    }
+}
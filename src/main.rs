@@ -1,7 +1,17 @@
 #![feature(result_flattening)]
 
+use jdw_sc::dry_run::DryRunFormat;
+use jdw_sc::journal::ReplayTiming;
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+
+    // --protocol-docs prints the markdown reference of all OSC commands
+    if args.iter().any(|a| a == "--protocol-docs") {
+        print!("{}", jdw_sc::command_registry::protocol_markdown());
+        return;
+    }
+
     let quiet = args.iter().any(|a| a == "-q" || a == "--quiet");
     let config_path = args
         .iter()
@@ -11,5 +21,53 @@ fn main() {
         .map(|s| s.as_str())
         .unwrap_or("config.toml");
 
-    jdw_sc::run(config_path, quiet);
+    // --dry-run prints readable lines, --dry-run=json one JSON object per line
+    let dry_run = args.iter().find_map(|a| match a.as_str() {
+        "--dry-run" => Some(Ok(DryRunFormat::Readable)),
+        _ => a.strip_prefix("--dry-run=").map(|format| format.parse()),
+    });
+
+    let dry_run = match dry_run.transpose() {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
+        }
+    };
+
+    // --replay=<journal> feeds a recorded session in with its original timing,
+    // add --replay-timing=fast to feed it in all at once
+    let replay_path = args.iter().find_map(|a| a.strip_prefix("--replay="));
+
+    let replay_timing = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--replay-timing="))
+        .map(|timing| timing.parse())
+        .unwrap_or(Ok(ReplayTiming::Original));
+
+    let replay_timing = match replay_timing {
+        Ok(timing) => timing,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1)
+        }
+    };
+
+    // --render=<journal> bounces a recorded session to <journal>.wav, or to --render-file=<path>
+    let render_path = args.iter().find_map(|a| a.strip_prefix("--render="));
+
+    let render_file = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--render-file="))
+        .map(|file| file.to_string())
+        .or_else(|| render_path.map(|path| format!("{}.wav", path)))
+        .unwrap_or_default();
+
+    jdw_sc::run(
+        config_path,
+        quiet,
+        dry_run,
+        replay_path.map(|path| (path, replay_timing)),
+        render_path.map(|path| (path, render_file.as_str())),
+    );
 }
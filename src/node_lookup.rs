@@ -2,35 +2,75 @@
    Created notes often get assigned an external_id from the caller, which
        is then used to look up the actual nodeId used in the created internal
        supercollider osc message. IdRegistry keeps track of these variables.
+
+   The registry also routes notes into named groups (e.g. one per track) by
+       matching their external_id against each group's regex.
 */
 use std::cell::RefCell;
 use std::collections::HashMap;
 
 use log::{debug, warn};
 use regex::Regex;
+use rosc::{OscMessage, OscType};
+
+use crate::config::Config;
 
-use crate::config;
+#[derive(Debug, Clone)]
+pub struct GroupDefinition {
+    pub name: String,
+    pub external_id_regex: String,
+    pub node_id: i32,
+    pub parallel: bool, // Created with /p_new, so that supernova can process its nodes in parallel
+    pub parent: i32,    // Default group that the group is created in
+}
+
+impl GroupDefinition {
+    // Server message creating the group at the tail of the configured default group
+    pub fn create_message(&self) -> OscMessage {
+        OscMessage {
+            addr: if self.parallel { "/p_new" } else { "/g_new" }.to_string(),
+            args: vec![
+                OscType::Int(self.node_id),
+                OscType::Int(1), // addToTail
+                OscType::Int(self.parent),
+            ],
+        }
+    }
+}
 
 pub struct NodeIDRegistry {
     pub registry: RefCell<HashMap<String, i32>>,
+    groups: RefCell<Vec<GroupDefinition>>, // Checked in creation order, first match wins
+    synth_names: RefCell<HashMap<i32, String>>, // Synthdef played by each node, where known
     curr_id: RefCell<i32>,
+    default_group: i32, // Notes matching no group are created here
+    placement: i32,     // Add action for new notes within their group
     regex_cache: RefCell<HashMap<String, Regex>>,
 }
 
 impl NodeIDRegistry {
-    pub fn new() -> NodeIDRegistry {
+    pub fn new(cfg: &Config) -> NodeIDRegistry {
         NodeIDRegistry {
             registry: RefCell::new(HashMap::new()),
-            curr_id: RefCell::new(config::Config::get().first_node_id),
+            groups: RefCell::new(vec![]),
+            synth_names: RefCell::new(HashMap::new()),
+            curr_id: RefCell::new(cfg.first_node_id),
+            default_group: cfg.group_id,
+            placement: cfg.group_placement,
             regex_cache: RefCell::new(HashMap::new()),
         }
     }
 
-    // Assign and return a new unique node_id for the given external_id
-    pub fn create_node_id(&self, external_id: &str) -> Result<i32, String> {
+    fn next_node_id(&self) -> i32 {
         let mut node_id = *self.curr_id.borrow();
         node_id += 1;
         self.curr_id.replace(node_id);
+        node_id
+    }
+
+    // Assign and return a new unique node_id for the given external_id
+    pub fn create_node_id(&self, external_id: &str) -> Result<i32, String> {
+        let node_id = self.next_node_id();
 
         let with_id_fill = external_id.replace("{nodeId}", &node_id.to_string());
 
@@ -45,6 +85,111 @@ impl NodeIDRegistry {
         Ok(node_id)
     }
 
+    /*
+        Define a group that notes with matching external ids are created in.
+        Redefining an existing name only updates its regex; Ok holds the definition and
+            whether the group node is new and must be created on the server.
+    */
+    pub fn create_group(
+        &self,
+        name: &str,
+        external_id_regex: &str,
+        parallel: bool,
+    ) -> Result<(GroupDefinition, bool), String> {
+        self.get_regex(external_id_regex)
+            .map_err(|e| format!("Invalid group regex {}: {}", external_id_regex, e))?;
+
+        let mut groups = self.groups.borrow_mut();
+
+        if let Some(existing) = groups.iter_mut().find(|group| group.name == name) {
+            if existing.parallel != parallel {
+                warn!(
+                    "Group {} already exists, parallel setting {} is kept",
+                    name, existing.parallel
+                );
+            }
+            existing.external_id_regex = external_id_regex.to_string();
+            return Ok((existing.clone(), false));
+        }
+
+        let group = GroupDefinition {
+            name: name.to_string(),
+            external_id_regex: external_id_regex.to_string(),
+            node_id: self.next_node_id(),
+            parallel,
+            parent: self.default_group,
+        };
+
+        groups.push(group.clone());
+        Ok((group, true))
+    }
+
+    /*
+        Remove a group definition. Notes created in it are freed along with the group on the server,
+            so their node ids are forgotten as well.
+    */
+    pub fn remove_group(&self, name: &str) -> Option<GroupDefinition> {
+        let group = self
+            .groups
+            .borrow()
+            .iter()
+            .find(|group| group.name == name)
+            .cloned()?;
+
+        let in_group: Vec<String> = self
+            .registry
+            .borrow()
+            .keys()
+            .filter(|external_id| self.group_for(external_id) == group.node_id)
+            .cloned()
+            .collect();
+
+        let mut synth_names = self.synth_names.borrow_mut();
+        let mut registry = self.registry.borrow_mut();
+        for external_id in in_group {
+            if let Some(node_id) = registry.remove(&external_id) {
+                synth_names.remove(&node_id);
+            }
+        }
+
+        self.groups
+            .borrow_mut()
+            .retain(|existing| existing.name != name);
+        Some(group)
+    }
+
+    pub fn groups(&self) -> Vec<GroupDefinition> {
+        self.groups.borrow().clone()
+    }
+
+    // Target group for a new note: the first group whose regex matches, or the configured default group
+    pub fn group_for(&self, external_id: &str) -> i32 {
+        self.groups
+            .borrow()
+            .iter()
+            .find(|group| {
+                self.get_regex(&group.external_id_regex)
+                    .is_ok_and(|regex| regex.is_match(external_id))
+            })
+            .map(|group| group.node_id)
+            .unwrap_or(self.default_group)
+    }
+
+    // Add action (config: group_placement) used when creating notes
+    pub fn placement(&self) -> i32 {
+        self.placement
+    }
+
+    pub fn set_synth_name(&self, node_id: i32, synth_name: &str) {
+        self.synth_names
+            .borrow_mut()
+            .insert(node_id, synth_name.to_string());
+    }
+
+    pub fn synth_name(&self, node_id: i32) -> Option<String> {
+        self.synth_names.borrow().get(&node_id).cloned()
+    }
+
     // Clear all node_ids matching regex
     fn get_regex(&self, pattern: &str) -> Result<Regex, regex::Error> {
         if let Some(cached) = self.regex_cache.borrow().get(pattern) {
@@ -58,7 +203,14 @@ impl NodeIDRegistry {
     pub fn regex_clear_node_ids(&self, external_id_regex: &str) {
         match self.get_regex(external_id_regex) {
             Ok(regex) => {
-                self.registry.borrow_mut().retain(|entry, _| !regex.is_match(entry));
+                let mut synth_names = self.synth_names.borrow_mut();
+                self.registry.borrow_mut().retain(|entry, node_id| {
+                    let keep = !regex.is_match(entry);
+                    if !keep {
+                        synth_names.remove(node_id);
+                    }
+                    keep
+                });
             }
             Err(_) => {
                 warn!("Invalid regex: {}", external_id_regex);
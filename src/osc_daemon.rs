@@ -1,66 +1,707 @@
 use std::{
+    collections::{HashMap, VecDeque},
     convert::{TryFrom, TryInto},
     fs::File,
-    io::Write,
-    net::{SocketAddrV4, UdpSocket},
+    io::{ErrorKind, Write},
+    net::UdpSocket,
+    path::Path,
     str::FromStr,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, Zero};
 use jdw_osc_lib::model::{OscArgHandler, TaggedBundle, TimedOSCPacket};
-use log::{error, info, warn};
-use rosc::{OscMessage, OscPacket, OscTime, OscType};
+use log::{debug, error, info, warn};
+use rosc::{encoder, OscMessage, OscPacket, OscTime, OscType};
 
 use crate::{
-    config,
+    command_registry,
+    config::Config,
     internal_osc_conversion::{self},
+    journal::{JournalWriter, Replay},
+    load_shedding::{self, LoadShedder},
     node_lookup::NodeIDRegistry,
     nrt_record::NRTConvert,
     osc_model::{
-        LoadSampleMessage, NRTRecordMessage, NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage,
-        PlaySampleMessage, RealTimePacket,
+        LoadSampleMessage, NRTPreloadMessage, NRTRecordMessage, NoteModifyMessage, NoteOnMessage,
+        NoteOnTimedMessage, PlaySampleMessage, RealTimePacket,
     },
     sampling::SamplePackDict,
+    sc_backend::ScBackend,
     sc_process_management::SCClient,
     scd_templating::{self, create_nrt_script},
+    shutdown::ShutdownHandle,
+    status_monitor::StatusMonitor,
+    scsyndef::{self, CompiledSynthDef},
+    synthdef_builder, synthdef_cache,
+    synthdef_library::{self, LibraryEvent},
+    synthdef_registry::{RegisterOutcome, SynthDefControl, SynthDefRegistry, SAMPLER_SYNTH_NAME},
 };
 
 // Lots of code stolen from OSCStack to avoid having to work around client sharing over closures
 
 const FUNNELED_TBUNDLES: [&str; 1] = ["batch-send"];
 
-struct Interpreter {
-    client: SCClient,
+// Where a synthdef being created came from, which decides how its outcome is reported
+enum SynthDefOrigin {
+    Client,              // /create_synthdef(_bytes): /create_synthdef_finished
+    LibraryFile(String), // synthdef_dir file: /synthdef_file_loaded
+    Sampler,             // sampler.scd: falls back to the built-in sampler
+    BuiltInSampler,
+}
+
+// Request to sclang awaiting its /read_scd_done
+enum ScdRequest {
+    // /read_scd from a client, whose reply is passed on
+    Client,
+    // Synthdef name and definition, compiled into the synthdef cache
+    Compile(String, String, SynthDefOrigin),
+}
+
+enum Definition {
+    Source(String),
+    Compiled(Vec<u8>),
+}
+
+// Synthdef sent to scsynth with /d_load or /d_recv, awaiting its /done (or /fail)
+struct SynthDefLoad {
+    command: &'static str,
+    name: String,
+    definition: Definition,
+    controls: Vec<SynthDefControl>,
+    origin: SynthDefOrigin,
+}
+
+/*
+    Interprets incoming packets against a backend: SCClient when running, RecordingBackend in tests.
+*/
+pub struct Interpreter<B: ScBackend> {
+    client: B,
+    config: Arc<Config>,
     reg: NodeIDRegistry,
     sample_pack_dict: SamplePackDict,
     nrt_sample_pack_dict: SamplePackDict,
-    synthef_snippets: Vec<String>,
-    nrt_synthdef_snippets: Vec<String>, // Same as synthdef_snippets, but cleared with clear_nrt to avoid redundancy
+    synthdefs: SynthDefRegistry,
+    nrt_synthdefs: SynthDefRegistry, // Same as synthdefs, but cleared with clear_nrt to avoid redundancy
     sampler_synth_snippet: String, // Default of the sampler, to allow keeping it when we wipe the other nrt snippets
     nrt_preloads: Vec<TimedOSCPacket>, // Packets to load on time 0.0 for all future nrt records,
     bpm: i32,
+    shutdown: ShutdownHandle,
+    exit_code: Option<i32>, // Set once a /quit has completed
+    request_counter: u64,   // Source of request ids for sclang commands that expect a reply
+    scd_requests: HashMap<String, ScdRequest>, // By request id
+    synthdef_loads: VecDeque<SynthDefLoad>, // In the order sent; scsynth replies in the same order
+    library_files: HashMap<String, String>, // Synthdef name loaded from each synthdef_dir file path
+    status: StatusMonitor,
+    shedder: LoadShedder,
 }
 
-impl Interpreter {
-    fn new(client: SCClient, sampler_snippet: String) -> Interpreter {
+impl<B: ScBackend> Interpreter<B> {
+    pub fn new(
+        client: B,
+        sampler_snippet: String,
+        shutdown: ShutdownHandle,
+        status: StatusMonitor,
+    ) -> Interpreter<B> {
+        let config = client.config().clone();
+
         Interpreter {
             client,
-            reg: NodeIDRegistry::new(),
+            reg: NodeIDRegistry::new(&config),
             sample_pack_dict: SamplePackDict::new(),
             nrt_sample_pack_dict: SamplePackDict::new(),
-            synthef_snippets: vec![sampler_snippet.clone()],
-            nrt_synthdef_snippets: vec![sampler_snippet.clone()],
+            synthdefs: SynthDefRegistry::new(),
+            nrt_synthdefs: SynthDefRegistry::with_sampler(&sampler_snippet),
             sampler_synth_snippet: sampler_snippet,
             nrt_preloads: vec![],
-            bpm: config::Config::get().default_bpm,
+            bpm: config.default_bpm,
+            shutdown,
+            exit_code: None,
+            request_counter: 0,
+            scd_requests: HashMap::new(),
+            synthdef_loads: VecDeque::new(),
+            library_files: HashMap::new(),
+            status,
+            shedder: LoadShedder::new(config.clone()),
+            config,
+        }
+    }
+
+    /*
+        Load the definition into scsynth unless an identical version is already loaded, then register it.
+        Compiled bytes come from the synthdef cache; sclang is only asked to compile unseen sources.
+        Nothing is awaited here: the outcome is reported through finish_synthdef once sclang and
+            scsynth have replied, see handle_replies.
+    */
+    fn create_synthdef(&mut self, name: &str, definition: &str, origin: SynthDefOrigin) {
+        if self.synthdefs.is_current(name, definition) {
+            self.nrt_synthdefs.register(name, definition);
+            return self.finish_synthdef(origin, name, Ok(()));
+        }
+
+        if let Some(compiled) = self.cached_synthdef(definition) {
+            return self.load_synthdef(name, definition, compiled, origin);
+        }
+
+        let request_id = self.next_request_id();
+
+        let sent = synthdef_cache::ensure_dir(&self.config)
+            .and_then(|_| synthdef_cache::cache_path(&self.config, definition))
+            .and_then(|path| {
+                self.send_scd(
+                    scd_templating::cache_synthdef_scd(definition, &path),
+                    &request_id,
+                )
+            });
+
+        match sent {
+            Ok(()) => {
+                let compile = ScdRequest::Compile(name.to_string(), definition.to_string(), origin);
+                self.scd_requests.insert(request_id, compile);
+            }
+            Err(e) => self.finish_synthdef(origin, name, Err(e)),
+        }
+    }
+
+    // Send a compiled definition from the synthdef cache to scsynth with /d_load
+    fn load_synthdef(
+        &mut self,
+        name: &str,
+        definition: &str,
+        compiled: CompiledSynthDef,
+        origin: SynthDefOrigin,
+    ) {
+        if compiled.name != name {
+            warn!(
+                "Synthdef declared as {} compiled under the name {}",
+                name, compiled.name
+            );
+        }
+
+        let path = match synthdef_cache::cache_path(&self.config, definition) {
+            Ok(path) => path,
+            Err(e) => return self.finish_synthdef(origin, name, Err(e)),
+        };
+
+        self.client.send_to_scsynth_with_delay(
+            OscPacket::Message(OscMessage {
+                addr: "/d_load".to_string(),
+                args: vec![OscType::String(path.to_string_lossy().to_string())],
+            }),
+            0,
+            SystemTime::now(),
+        );
+
+        self.synthdef_loads.push_back(SynthDefLoad {
+            command: "/d_load",
+            name: name.to_string(),
+            definition: Definition::Source(definition.to_string()),
+            controls: compiled.controls,
+            origin,
+        });
+    }
+
+    /*
+        Load a definition compiled outside of sclang (e.g. by synthdef_builder) with /d_recv.
+        Reported through finish_synthdef under the name declared in the bytes, like create_synthdef.
+    */
+    fn create_compiled_synthdef(&mut self, bytes: &[u8], origin: SynthDefOrigin) {
+        let compiled = match scsyndef::decode(bytes) {
+            Ok(compiled) => compiled,
+            Err(e) => return self.finish_synthdef(origin, "", Err(e)),
+        };
+
+        let name = compiled.name.clone();
+
+        if self.synthdefs.is_current_compiled(&name, bytes) {
+            self.nrt_synthdefs.register_compiled(&name, bytes);
+            return self.finish_synthdef(origin, &name, Ok(()));
+        }
+
+        self.client.send_to_scsynth_with_delay(
+            OscPacket::Message(OscMessage {
+                addr: "/d_recv".to_string(),
+                args: vec![OscType::Blob(bytes.to_vec())],
+            }),
+            0,
+            SystemTime::now(),
+        );
+
+        self.synthdef_loads.push_back(SynthDefLoad {
+            command: "/d_recv",
+            name,
+            definition: Definition::Compiled(bytes.to_vec()),
+            controls: compiled.controls,
+            origin,
+        });
+    }
+
+    /*
+        Register a synthdef once scsynth has loaded it, for real time and NRT use.
+        Only definitions that loaded are kept for later NRT scores.
+    */
+    fn register_synthdef(&mut self, load: SynthDefLoad) {
+        let outcome = match &load.definition {
+            Definition::Source(definition) => {
+                self.nrt_synthdefs.register(&load.name, definition);
+                self.synthdefs.register(&load.name, definition)
+            }
+            Definition::Compiled(bytes) => {
+                self.nrt_synthdefs.register_compiled(&load.name, bytes);
+                self.synthdefs.register_compiled(&load.name, bytes)
+            }
+        };
+
+        if outcome == RegisterOutcome::Replaced {
+            info!("Replaced synthdef {}", load.name);
+        }
+
+        self.synthdefs.set_controls(&load.name, load.controls);
+        self.finish_synthdef(load.origin, &load.name, Ok(()));
+    }
+
+    /*
+        Report the outcome of creating a synthdef according to where it came from.
+        MSG OUT: /create_synthdef_finished, <"SUCCESS"|"FAILURE">, <synthdef name>, <error message>
+    */
+    fn finish_synthdef(&mut self, origin: SynthDefOrigin, name: &str, result: Result<(), String>) {
+        match origin {
+            SynthDefOrigin::Client => {
+                let (status, message) = match result {
+                    Ok(()) => ("SUCCESS".to_string(), "".to_string()),
+                    Err(e) => {
+                        error!("Failed to create synthdef '{}': {}", name, e);
+                        ("FAILURE".to_string(), e)
+                    }
+                };
+
+                self.client.send_out(OscMessage {
+                    addr: "/create_synthdef_finished".to_string(),
+                    args: vec![
+                        OscType::String(status),
+                        OscType::String(name.to_string()),
+                        OscType::String(message),
+                    ],
+                });
+            }
+            SynthDefOrigin::LibraryFile(path) => self.synthdef_file_loaded(&path, name, result),
+            SynthDefOrigin::Sampler => {
+                if let Err(e) = result {
+                    warn!("Using the built-in sampler: {}", e);
+                    self.create_compiled_synthdef(
+                        &synthdef_builder::sampler().encode(),
+                        SynthDefOrigin::BuiltInSampler,
+                    );
+                }
+            }
+            SynthDefOrigin::BuiltInSampler => {
+                if let Err(e) = result {
+                    error!(
+                        "Failed to load sampler synthdef, /play_sample will not work: {}",
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    // Remove a synthdef from the registries and the server; the sampler is protected
+    fn free_synthdef(&mut self, name: &str, time: SystemTime) -> Result<(), String> {
+        if name == SAMPLER_SYNTH_NAME {
+            return Err(
+                "The sampler synthdef is required for sample playback and cannot be freed"
+                    .to_string(),
+            );
+        }
+
+        if self.synthdefs.remove(name).is_none() {
+            return Err(format!("Cannot free unknown synthdef: {}", name));
+        }
+
+        self.nrt_synthdefs.remove(name);
+
+        self.client.send_to_scsynth_with_delay(
+            OscPacket::Message(OscMessage {
+                addr: "/d_free".to_string(),
+                args: vec![OscType::String(name.to_string())],
+            }),
+            0,
+            time,
+        );
+
+        info!("Freed synthdef {}", name);
+        Ok(())
+    }
+
+    /*
+        Load the sampler used by /play_sample from sampler.scd, compiled by sclang or taken from the
+            synthdef cache. Without either, the equivalent graph from synthdef_builder is loaded.
+    */
+    pub fn load_sampler(&mut self) {
+        let snippet = self.sampler_synth_snippet.clone();
+
+        if self.client.has_sclang() || synthdef_cache::read(&self.config, &snippet).is_some() {
+            self.create_synthdef(SAMPLER_SYNTH_NAME, &snippet, SynthDefOrigin::Sampler);
+        } else {
+            self.finish_synthdef(
+                SynthDefOrigin::Sampler,
+                SAMPLER_SYNTH_NAME,
+                Err(
+                    "sclang is not available and sampler.scd is not in the synthdef cache"
+                        .to_string(),
+                ),
+            );
+        }
+    }
+
+    // Change reported by the synthdef_dir watcher
+    pub fn library_event(&mut self, event: LibraryEvent) {
+        match event {
+            LibraryEvent::Changed(path) => self.load_synthdef_file(&path.to_string_lossy()),
+            LibraryEvent::Removed(path) => self.unload_synthdef_file(&path.to_string_lossy()),
+        }
+    }
+
+    /*
+        Create the synthdef contained in a synthdef_dir file; the outcome is announced by
+            synthdef_file_loaded. Files outside of synthdef_dir are refused.
+    */
+    fn load_synthdef_file(&mut self, path: &str) {
+        let in_library = synthdef_library::library_dir(&self.config)
+            .is_some_and(|dir| synthdef_library::contains(&dir, Path::new(path)));
+        let origin = SynthDefOrigin::LibraryFile(path.to_string());
+
+        if !in_library {
+            let e = format!("{} is not inside synthdef_dir", path);
+            return self.finish_synthdef(origin, "", Err(e));
+        }
+
+        if synthdef_library::is_compiled(Path::new(path)) {
+            match std::fs::read(path) {
+                Ok(bytes) => self.create_compiled_synthdef(&bytes, origin),
+                Err(e) => {
+                    let e = format!("Failed to read {}: {}", path, e);
+                    self.finish_synthdef(origin, "", Err(e))
+                }
+            }
+        } else {
+            let definition = synthdef_library::read_definition(Path::new(path));
+            let name = definition
+                .as_ref()
+                .ok()
+                .and_then(|definition| scd_templating::parse_synthdef_name(definition));
+
+            match (definition, name) {
+                (Ok(definition), Some(name)) => self.create_synthdef(&name, &definition, origin),
+                (Ok(_), None) => self.finish_synthdef(
+                    origin,
+                    "",
+                    Err("Could not find a SynthDef name in definition".to_string()),
+                ),
+                (Err(e), _) => self.finish_synthdef(origin, "", Err(e)),
+            }
+        }
+    }
+
+    /*
+        Announce the outcome of loading a synthdef_dir file.
+        If the file now declares a different SynthDef name, the old one is freed.
+        MSG OUT: /synthdef_file_loaded, <"SUCCESS"|"FAILURE">, <synthdef name>, <path>, <error message>
+    */
+    fn synthdef_file_loaded(&mut self, path: &str, name: &str, result: Result<(), String>) {
+        let (status, name, message) = match result {
+            Ok(()) => {
+                info!("Loaded synthdef {} from {}", name, path);
+
+                if let Some(previous) = self
+                    .library_files
+                    .insert(path.to_string(), name.to_string())
+                {
+                    if previous != name {
+                        if let Err(e) = self.free_synthdef(&previous, SystemTime::now()) {
+                            warn!("{}", e);
+                        }
+                    }
+                }
+
+                ("SUCCESS".to_string(), name.to_string(), "".to_string())
+            }
+            Err(e) => {
+                error!("Failed to load synthdef file {}: {}", path, e);
+                let name = self.library_files.get(path).cloned().unwrap_or_default();
+                ("FAILURE".to_string(), name, e)
+            }
+        };
+
+        self.client.send_out(OscMessage {
+            addr: "/synthdef_file_loaded".to_string(),
+            args: vec![
+                OscType::String(status),
+                OscType::String(name),
+                OscType::String(path.to_string()),
+                OscType::String(message),
+            ],
+        });
+    }
+
+    /*
+        Free the synthdef of a deleted synthdef_dir file.
+        MSG OUT: /synthdef_file_removed, <synthdef name>, <path>
+    */
+    fn unload_synthdef_file(&mut self, path: &str) {
+        match self.library_files.remove(path) {
+            Some(name) => {
+                if let Err(e) = self.free_synthdef(&name, SystemTime::now()) {
+                    warn!("{}", e);
+                }
+
+                self.client.send_out(OscMessage {
+                    addr: "/synthdef_file_removed".to_string(),
+                    args: vec![OscType::String(name), OscType::String(path.to_string())],
+                });
+            }
+            None => warn!("No synthdef loaded from {}", path),
+        }
+    }
+
+    // Compiled form of the definition from the synthdef cache, if compiled before
+    fn cached_synthdef(&self, definition: &str) -> Option<CompiledSynthDef> {
+        let bytes = synthdef_cache::read(&self.config, definition)?;
+
+        match scsyndef::decode(&bytes) {
+            Ok(compiled) => Some(compiled),
+            Err(e) => {
+                warn!("Discarding unreadable cached synthdef: {}", e);
+                synthdef_cache::invalidate(&self.config, definition);
+                None
+            }
+        }
+    }
+
+    // Args validated according to the configured mode; None if the message should be dropped
+    fn validated_args(
+        &self,
+        synth_name: &str,
+        args: &[OscType],
+        reserved: &[&str],
+    ) -> Option<Vec<OscType>> {
+        let mode = self.config.arg_validation_mode();
+
+        match self.synthdefs.validate_args(synth_name, args, reserved, &mode) {
+            Ok(args) => Some(args),
+            Err(e) => {
+                warn!("Dropped message: {}", e);
+                None
+            }
+        }
+    }
+
+    /*
+        Take the priority arg off a new note and check it against the current server load.
+        Returns true if the note was dropped.
+        MSG OUT: /jdw_sc_note_shed, <msg addr>, <external id>, <priority>, <total dropped>
+    */
+    fn shed_note(&mut self, addr: &str, external_id: &str, args: &mut Vec<OscType>) -> bool {
+        let priority = load_shedding::take_priority(args)
+            .unwrap_or_else(|| self.shedder.priority_for(external_id));
+
+        let latest = self.status.latest();
+
+        if self
+            .shedder
+            .check(addr, external_id, priority, latest.as_ref())
+            .is_none()
+        {
+            return false;
+        }
+
+        self.client.send_out(OscMessage {
+            addr: "/jdw_sc_note_shed".to_string(),
+            args: vec![
+                OscType::String(addr.to_string()),
+                OscType::String(external_id.to_string()),
+                OscType::Int(priority),
+                OscType::Long(self.shedder.total_dropped() as i64),
+            ],
+        });
+
+        true
+    }
+
+    fn next_request_id(&mut self) -> String {
+        self.request_counter += 1;
+        format!("jdw_sc_{}", self.request_counter)
+    }
+
+    /*
+        Run scd code in sclang. Its outcome arrives as /read_scd_done with the request id,
+            see handle_replies.
+    */
+    fn send_scd(&mut self, scd: String, request_id: &str) -> Result<(), String> {
+        if !self.client.has_sclang() {
+            return Err("sclang is not running (boot_mode = \"scsynth\")".to_string());
+        }
+
+        self.client.send_to_sclang(OscMessage {
+            addr: "/read_scd".to_string(),
+            args: vec![
+                OscType::String(scd),
+                OscType::String(request_id.to_string()),
+            ],
+        });
+
+        Ok(())
+    }
+
+    /*
+        Handle the replies of sclang and scsynth to requests still pending, in scd_requests and
+            synthdef_loads. Called between received packets, so that nothing waits for SuperCollider.
+    */
+    pub fn handle_replies(&mut self) {
+        for msg in self.client.take_messages() {
+            match msg.addr.as_str() {
+                "/read_scd_done" => self.scd_done(&msg),
+                "/done" | "/fail" => self.synthdef_loaded(&msg),
+                _ => debug!("Ignored {} from SuperCollider", msg.addr),
+            }
         }
     }
 
-    fn interpret(&mut self, packet: OscPacket, sendTime: SystemTime) {
+    // Requests to sclang and scsynth whose replies have not been handled yet
+    pub fn pending_requests(&self) -> usize {
+        self.scd_requests.len() + self.synthdef_loads.len()
+    }
+
+    /*
+        Handle replies until no request is pending, for at most scd_reply_timeout_secs.
+        For replays, where the packets that follow may depend on e.g. a created synthdef.
+    */
+    pub fn await_pending_requests(&mut self) {
+        if !self.client.has_replies() {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(self.config.scd_reply_timeout_secs);
+
+        loop {
+            self.handle_replies();
+
+            if self.pending_requests() == 0 {
+                return;
+            }
+
+            if Instant::now() > deadline {
+                warn!(
+                    "Continuing with {} requests to SuperCollider pending",
+                    self.pending_requests()
+                );
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(self.config.poll_sleep_ms.max(1)));
+        }
+    }
+
+    /*
+        MSG IN: /read_scd_done, <request id>, <"ok"|"error">, <error message>
+        MSG OUT: /read_scd_done, <request id>, <"ok"|"error">, <error message>
+    */
+    fn scd_done(&mut self, msg: &OscMessage) {
+        let request_id = msg.get_string_at(0, "request id").unwrap_or_default();
+        let status = msg.get_string_at(1, "status").unwrap_or_default();
+        let message = msg.get_string_at(2, "error message").unwrap_or_default();
+
+        match self.scd_requests.remove(&request_id) {
+            Some(ScdRequest::Client) => {
+                if status != "ok" {
+                    warn!("scd string failed in sclang: {}", message);
+                }
+
+                self.client.send_out(OscMessage {
+                    addr: "/read_scd_done".to_string(),
+                    args: vec![
+                        OscType::String(request_id),
+                        OscType::String(status),
+                        OscType::String(message),
+                    ],
+                });
+            }
+            Some(ScdRequest::Compile(name, definition, origin)) => {
+                if status != "ok" {
+                    return self.finish_synthdef(origin, &name, Err(message));
+                }
+
+                match self.cached_synthdef(&definition) {
+                    Some(compiled) => self.load_synthdef(&name, &definition, compiled, origin),
+                    None => {
+                        let e = "sclang did not write a readable compiled synthdef".to_string();
+                        self.finish_synthdef(origin, &name, Err(e))
+                    }
+                }
+            }
+            None => debug!("No pending request {} for /read_scd_done", request_id),
+        }
+    }
+
+    /*
+        The first pending load of the command is the one scsynth replied to.
+        MSG IN: /done, <command>
+        MSG IN: /fail, <command>, <error message>
+    */
+    fn synthdef_loaded(&mut self, msg: &OscMessage) {
+        let command = msg.get_string_at(0, "command").unwrap_or_default();
+
+        let index = self
+            .synthdef_loads
+            .iter()
+            .position(|load| load.command == command);
+
+        let load = match index.and_then(|index| self.synthdef_loads.remove(index)) {
+            Some(load) => load,
+            None => {
+                debug!("No pending load for {} {}", msg.addr, command);
+                return;
+            }
+        };
+
+        if msg.addr == "/done" {
+            self.register_synthdef(load);
+        } else {
+            let e = msg.get_string_at(1, "error message").unwrap_or_default();
+            self.finish_synthdef(load.origin, &load.name, Err(e));
+        }
+    }
+
+    // Set once a /quit has been handled
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn interpret(&mut self, packet: OscPacket, sendTime: SystemTime) {
         match packet {
             OscPacket::Message(osc_message) => {
+                /*
+                    Messages that do not match their command in command_registry.rs are dropped
+                        instead of reaching the handlers below.
+                    MSG OUT: /jdw_sc_rejected, <addr>, <reason>
+                */
+                if let Err(reason) = command_registry::validate(&osc_message) {
+                    warn!("Rejected message: {}", reason);
+                    self.client.send_out(OscMessage {
+                        addr: "/jdw_sc_rejected".to_string(),
+                        args: vec![
+                            OscType::String(osc_message.addr.clone()),
+                            OscType::String(reason),
+                        ],
+                    });
+                    return;
+                }
+
                 match osc_message.addr.as_str() {
                     "/free_notes" => {
                         let regex = osc_message.get_string_at(0, "Regex string").unwrap();
@@ -98,17 +739,68 @@ impl Interpreter {
                             args: vec![OscType::String(msg), OscType::Time(osc_time)],
                         });
                     }
+                    /*
+                        Graceful shutdown. Optional arg overrides the configured fade out time.
+                        MSG: /quit, [fade_ms]
+                    */
+                    "/quit" => {
+                        let fade_ms = osc_message
+                            .get_int_at(0, "fade_ms")
+                            .map(|ms| ms.max(0) as u64)
+                            .unwrap_or(self.config.shutdown_fade_ms);
+
+                        let buffers: Vec<i32> = self
+                            .sample_pack_dict
+                            .get_all_samples()
+                            .iter()
+                            .map(|sample| sample.buffer_number)
+                            .collect();
+
+                        self.exit_code =
+                            Some(self.shutdown.run_sequence(&self.client, fade_ms, &buffers));
+                    }
                     "/set_bpm" => {
                         self.bpm = osc_message.get_int_at(0, "BPM value").unwrap();
                     }
                     "/note_on_timed" => {
-                        let processed_message = NoteOnTimedMessage::new(&osc_message).unwrap();
+                        let mut processed_message = NoteOnTimedMessage::new(&osc_message).unwrap();
+
+                        if self.shed_note(
+                            "/note_on_timed",
+                            &processed_message.external_id,
+                            &mut processed_message.args,
+                        ) {
+                            return;
+                        }
+
+                        processed_message.args = match self.validated_args(
+                            &processed_message.synth_name,
+                            &processed_message.args,
+                            &[],
+                        ) {
+                            Some(args) => args,
+                            None => return,
+                        };
+
+                        let note_off = internal_osc_conversion::note_off_strategy(
+                            &self.synthdefs,
+                            &processed_message.synth_name,
+                            &processed_message.args,
+                            self.config.gateless_fade_ms,
+                        );
 
                         match self.reg.create_node_id(&processed_message.external_id) {
                             Ok(node_id) => {
+                                self.reg.set_synth_name(node_id, &processed_message.synth_name);
                                 self.client.send_timed_packets_to_scsynth(
                                     processed_message.delay_ms,
-                                    processed_message.create_osc(node_id, self.bpm),
+                                    processed_message.create_osc(
+                                        node_id,
+                                        self.reg.group_for(&processed_message.external_id),
+                                        self.reg.placement(),
+                                        self.bpm,
+                                        &note_off,
+                                    ),
                                     sendTime,
                                 );
                             }
@@ -116,13 +808,35 @@ impl Interpreter {
                         }
                     }
                     "/note_on" => {
-                        let processed_message = NoteOnMessage::new(&osc_message).unwrap();
+                        let mut processed_message = NoteOnMessage::new(&osc_message).unwrap();
+
+                        if self.shed_note(
+                            "/note_on",
+                            &processed_message.external_id,
+                            &mut processed_message.args,
+                        ) {
+                            return;
+                        }
+
+                        processed_message.args = match self.validated_args(
+                            &processed_message.synth_name,
+                            &processed_message.args,
+                            &[],
+                        ) {
+                            Some(args) => args,
+                            None => return,
+                        };
 
                         match self.reg.create_node_id(&processed_message.external_id) {
                             Ok(node_id) => {
+                                self.reg.set_synth_name(node_id, &processed_message.synth_name);
                                 self.client.send_timed_packets_to_scsynth(
                                     processed_message.delay_ms,
-                                    processed_message.create_osc(node_id),
+                                    processed_message.create_osc(
+                                        node_id,
+                                        self.reg.group_for(&processed_message.external_id),
+                                        self.reg.placement(),
+                                    ),
                                     sendTime,
                                 );
                             }
@@ -130,7 +844,25 @@ impl Interpreter {
                         }
                     }
                     "/play_sample" => {
-                        if let Ok(processed_message) = PlaySampleMessage::new(&osc_message) {
+                        if let Ok(mut processed_message) = PlaySampleMessage::new(&osc_message) {
+                            if self.shed_note(
+                                "/play_sample",
+                                &processed_message.external_id,
+                                &mut processed_message.args,
+                            ) {
+                                return;
+                            }
+
+                            // buf is set internally from the sample lookup
+                            processed_message.args = match self.validated_args(
+                                SAMPLER_SYNTH_NAME,
+                                &processed_message.args,
+                                &["buf"],
+                            ) {
+                                Some(args) => args,
+                                None => return,
+                            };
+
                             let delay = processed_message.delay_ms;
                             let category =
                                 processed_message.category.clone().unwrap_or("".to_string());
@@ -147,10 +879,15 @@ impl Interpreter {
                                 let internal_msg = processed_message.prepare(buffer_number);
                                 match self.reg.create_node_id(&internal_msg.external_id) {
                                     Ok(node_id) => {
+                                        self.reg.set_synth_name(node_id, SAMPLER_SYNTH_NAME);
                                         // TODO: Adapt new osc conversion properly when everything is converted
                                         self.client.send_timed_packets_to_scsynth(
                                             delay,
-                                            internal_msg.create_osc(node_id),
+                                            internal_msg.create_osc(
+                                                node_id,
+                                                self.reg.group_for(&internal_msg.external_id),
+                                                self.reg.placement(),
+                                            ),
                                             sendTime,
                                         );
                                     }
@@ -166,26 +903,86 @@ impl Interpreter {
                         }
                     }
                     "/note_modify" => {
-                        let receive_time = SystemTime::now();
-
                         let processed_message = NoteModifyMessage::new(&osc_message).unwrap();
 
                         let node_ids = self
                             .reg
                             .regex_search_node_ids(&processed_message.external_id_regex);
 
-                        self.client.send_timed_packets_to_scsynth(
-                            processed_message.delay_ms,
-                            processed_message.create_osc(node_ids),
-                            receive_time,
-                        );
+                        // Args are validated separately for each synthdef among the matched nodes
+                        let mut nodes_by_synth: HashMap<Option<String>, Vec<i32>> = HashMap::new();
+                        for node_id in node_ids {
+                            nodes_by_synth
+                                .entry(self.reg.synth_name(node_id))
+                                .or_default()
+                                .push(node_id);
+                        }
+
+                        for (synth_name, synth_node_ids) in nodes_by_synth {
+                            let args = match synth_name {
+                                Some(name) => {
+                                    match self.validated_args(&name, &processed_message.args, &[]) {
+                                        Some(args) => args,
+                                        None => continue,
+                                    }
+                                }
+                                None => processed_message.args.clone(),
+                            };
+
+                            let synth_message = NoteModifyMessage {
+                                external_id_regex: processed_message.external_id_regex.clone(),
+                                delay_ms: processed_message.delay_ms,
+                                args,
+                            };
+
+                            self.client.send_timed_packets_to_scsynth(
+                                synth_message.delay_ms,
+                                synth_message.create_osc(synth_node_ids),
+                                sendTime,
+                            );
+                        }
                     }
+                    /*
+                        The reply is sent once sclang has run the string, see scd_done.
+                        MSG: /read_scd, <scd string>, [request id]
+                        MSG OUT: /read_scd_done, <request id>, <"ok"|"error">, <error message>
+                    */
                     "/read_scd" => {
-                        self.client.send_to_sclang(osc_message);
+                        let scd = osc_message.get_string_at(0, "scd string").unwrap();
+                        let request_id = osc_message
+                            .get_string_at(1, "request id")
+                            .unwrap_or_else(|_| self.next_request_id());
+
+                        match self.send_scd(scd, &request_id) {
+                            Ok(()) => {
+                                self.scd_requests.insert(request_id, ScdRequest::Client);
+                            }
+                            Err(e) => {
+                                warn!("scd string failed in sclang: {}", e);
+
+                                self.client.send_out(OscMessage {
+                                    addr: "/read_scd_done".to_string(),
+                                    args: vec![
+                                        OscType::String(request_id),
+                                        OscType::String("error".to_string()),
+                                        OscType::String(e),
+                                    ],
+                                });
+                            }
+                        }
                     }
                     "/load_sample" => {
                         let resolved = LoadSampleMessage::new(&osc_message).unwrap();
 
+                        let num_buffers = self.config.num_buffers;
+                        if resolved.buffer_number < 0 || resolved.buffer_number >= num_buffers {
+                            error!(
+                                "Buffer number {} for {} is outside of the {} server buffers (see num_buffers in config)",
+                                resolved.buffer_number, resolved.file_path, num_buffers
+                            );
+                            return;
+                        }
+
                         self.nrt_sample_pack_dict
                             .register_sample(resolved.clone())
                             .unwrap();
@@ -197,35 +994,258 @@ impl Interpreter {
                             sample.file_path, sample.tone_index, sample.category_tag
                         );
 
-                        self.client.send_to_sclang(OscMessage {
-                            addr: "/read_scd".to_string(),
-                            args: vec![OscType::String(sample.get_buffer_load_scd())],
-                        });
+                        if self.client.has_sclang() {
+                            self.client.send_to_sclang(OscMessage {
+                                addr: "/read_scd".to_string(),
+                                args: vec![OscType::String(sample.get_buffer_load_scd(&self.config.server_name))],
+                            });
+                        } else {
+                            self.client.send_to_scsynth_with_delay(
+                                OscPacket::Message(sample.get_buffer_alloc_read_msg()),
+                                0,
+                                sendTime,
+                            );
+                        }
                     }
                     "/clear_nrt" => {
                         self.nrt_preloads.clear();
-                        self.nrt_synthdef_snippets = vec![self.sampler_synth_snippet.clone()];
+                        self.nrt_synthdefs =
+                            SynthDefRegistry::with_sampler(&self.sampler_synth_snippet);
                         self.nrt_sample_pack_dict = SamplePackDict::new();
                     }
+                    /*
+                        Compile and add a synthdef in sclang. Definitions are keyed by their SynthDef
+                        name; re-sending a name replaces the old version. Only definitions that
+                        compiled are kept for later NRT scores. The reply is sent once sclang and
+                        scsynth are done with it, while other messages keep being handled.
+                        MSG OUT: /create_synthdef_finished, <"SUCCESS"|"FAILURE">, <synthdef name>, <error message>
+                    */
                     "/create_synthdef" => {
                         // save scd in state, run scd in sclang
                         let definition =
                             osc_message.get_string_at(0, "Synthdef scd string").unwrap();
 
-                        if !self.nrt_synthdef_snippets.contains(&definition) {
-                            self.nrt_synthdef_snippets.push(definition.clone());
+                        match scd_templating::parse_synthdef_name(&definition) {
+                            Some(name) => {
+                                self.create_synthdef(&name, &definition, SynthDefOrigin::Client)
+                            }
+                            None => self.finish_synthdef(
+                                SynthDefOrigin::Client,
+                                "",
+                                Err("Could not find a SynthDef name in definition".to_string()),
+                            ),
                         }
+                    }
+                    /*
+                        Add a synthdef from compiled SynthDef2 bytes, e.g. built with synthdef_builder.
+                        MSG: /create_synthdef_bytes, <scsyndef blob>
+                        MSG OUT: /create_synthdef_finished, <"SUCCESS"|"FAILURE">, <synthdef name>, <error message>
+                    */
+                    "/create_synthdef_bytes" => match osc_message.args.get(0) {
+                        Some(OscType::Blob(bytes)) => {
+                            self.create_compiled_synthdef(bytes, SynthDefOrigin::Client)
+                        }
+                        _ => self.finish_synthdef(
+                            SynthDefOrigin::Client,
+                            "",
+                            Err("Expected a blob of compiled synthdef bytes".to_string()),
+                        ),
+                    },
+                    /*
+                        Notes whose external id matches the regex are created in the named group
+                            instead of the default group. Parallel groups use /p_new, letting supernova
+                            spread their nodes over several cores (scsynth treats them as regular groups).
+                        Re-sending an existing name updates its regex.
+                        MSG: /create_group, <name>, <external id regex>, [<parallel 0|1, default 1>]
+                    */
+                    "/create_group" => {
+                        let name = osc_message.get_string_at(0, "Group name").unwrap();
+                        let regex = osc_message.get_string_at(1, "External id regex").unwrap();
+                        let parallel = osc_message.get_int_at(2, "Parallel").unwrap_or(1) != 0;
 
-                        if !self.synthef_snippets.contains(&definition) {
-                            self.synthef_snippets.push(definition.clone());
+                        match self.reg.create_group(&name, &regex, parallel) {
+                            Ok((group, true)) => {
+                                info!("Created group {} ({}) for ids matching {}", name, group.node_id, regex);
+                                self.client.send_to_scsynth_with_delay(
+                                    OscPacket::Message(group.create_message()),
+                                    0,
+                                    sendTime,
+                                );
+                            }
+                            Ok((_, false)) => info!("Updated group {} to ids matching {}", name, regex),
+                            Err(e) => error!("Failed to create group {}: {}", name, e),
+                        }
+                    }
+                    /*
+                        Free a group along with all notes playing in it.
+                        MSG: /free_group, <name>
+                    */
+                    "/free_group" => {
+                        let name = osc_message.get_string_at(0, "Group name").unwrap();
 
-                            let add_call = definition + ".add;";
-                            self.client.send_to_sclang(OscMessage {
-                                addr: "/read_scd".to_string(),
-                                args: vec![OscType::String(add_call)],
-                            });
+                        match self.reg.remove_group(&name) {
+                            Some(group) => {
+                                self.client.send_to_scsynth_with_delay(
+                                    OscPacket::Message(OscMessage {
+                                        addr: "/n_free".to_string(),
+                                        args: vec![OscType::Int(group.node_id)],
+                                    }),
+                                    0,
+                                    sendTime,
+                                );
+                            }
+                            None => warn!("Cannot free unknown group: {}", name),
+                        }
+                    }
+                    // MSG: /free_synthdef, <synthdef name>
+                    "/free_synthdef" => {
+                        let name = osc_message.get_string_at(0, "Synthdef name").unwrap();
+
+                        if let Err(e) = self.free_synthdef(&name, sendTime) {
+                            warn!("{}", e);
                         }
                     }
+                    /*
+                        Controls of a synthdef, as reported by sclang when it was created.
+                        MSG: /synthdef_info, <synthdef name>
+                        MSG OUT: /synthdef_info_reply, <name>, <"ok"|"unknown">, [<control name>, <default value>]...
+                    */
+                    "/synthdef_info" => {
+                        let name = osc_message.get_string_at(0, "Synthdef name").unwrap();
+
+                        let mut args = vec![OscType::String(name.clone())];
+
+                        match self.synthdefs.get(&name).and_then(|entry| entry.controls.clone()) {
+                            Some(controls) => {
+                                args.push(OscType::String("ok".to_string()));
+                                for control in controls {
+                                    args.push(OscType::String(control.name));
+                                    args.push(OscType::Float(control.default));
+                                }
+                            }
+                            None => args.push(OscType::String("unknown".to_string())),
+                        }
+
+                        self.client.send_out(OscMessage {
+                            addr: "/synthdef_info_reply".to_string(),
+                            args,
+                        });
+                    }
+                    /*
+                        List registered synthdefs and when each was last updated.
+                        MSG OUT: /list_synthdefs_reply, [<name>, <updated time>]...
+                    */
+                    "/list_synthdefs" => {
+                        let args = self
+                            .synthdefs
+                            .entries()
+                            .iter()
+                            .flat_map(|entry| {
+                                vec![
+                                    OscType::String(entry.name.clone()),
+                                    OscType::Time(OscTime::try_from(entry.updated).unwrap()),
+                                ]
+                            })
+                            .collect();
+
+                        self.client.send_out(OscMessage {
+                            addr: "/list_synthdefs_reply".to_string(),
+                            args,
+                        });
+                    }
+                    /*
+                        Priority of notes with matching external ids when shedding load.
+                        Notes below shed_keep_priority are dropped while the server is overloaded.
+                        MSG: /set_note_priority, <external id regex>, <priority>
+                    */
+                    "/set_note_priority" => {
+                        let regex = osc_message.get_string_at(0, "external id regex").unwrap();
+                        let priority = osc_message.get_int_at(1, "priority").unwrap();
+
+                        if let Err(e) = self.shedder.set_priority(&regex, priority) {
+                            warn!("Invalid note priority regex {}: {}", regex, e);
+                        }
+                    }
+                    /*
+                        Notes dropped by load shedding since boot or the last reset.
+                        MSG: /get_shed_stats, [<"reset">]
+                        MSG OUT: /get_shed_stats_reply, <total dropped>, [<msg addr>, <dropped>]...
+                    */
+                    "/get_shed_stats" => {
+                        let mut args = vec![OscType::Long(self.shedder.total_dropped() as i64)];
+                        for (addr, count) in self.shedder.dropped() {
+                            args.push(OscType::String(addr.clone()));
+                            args.push(OscType::Long(*count as i64));
+                        }
+
+                        self.client.send_out(OscMessage {
+                            addr: "/get_shed_stats_reply".to_string(),
+                            args,
+                        });
+
+                        if osc_message.get_string_at(0, "reset").is_ok_and(|arg| arg == "reset") {
+                            self.shedder.reset();
+                        }
+                    }
+                    /*
+                        Latest polled server status and a summary of the rolling history.
+                        MSG OUT: /get_status_reply, <"ok"|"unavailable">, [<ugens>, <synths>, <groups>, <synthdefs>, <avg cpu>, <peak cpu>, <nominal sample rate>, <actual sample rate>, <history length>, <max peak cpu>, <mean avg cpu>]
+                    */
+                    "/get_status" => {
+                        let history = self.status.history();
+
+                        let args = match history.last() {
+                            Some(latest) => {
+                                let max_peak = history
+                                    .iter()
+                                    .map(|status| status.peak_cpu)
+                                    .fold(0.0, f32::max);
+                                let mean_avg = history.iter().map(|status| status.avg_cpu).sum::<f32>()
+                                    / history.len() as f32;
+
+                                let mut args = vec![OscType::String("ok".to_string())];
+                                args.extend(latest.as_osc_args());
+                                args.push(OscType::Int(history.len() as i32));
+                                args.push(OscType::Float(max_peak));
+                                args.push(OscType::Float(mean_avg));
+                                args
+                            }
+                            None => vec![OscType::String("unavailable".to_string())],
+                        };
+
+                        self.client.send_out(OscMessage {
+                            addr: "/get_status_reply".to_string(),
+                            args,
+                        });
+                    }
+                    /*
+                        All supported commands and bundles with their args, see command_registry.rs.
+                        MSG OUT: /describe_reply, <addr>, <description>, <reply or "">, <in scores 0|1>, [<arg name>, <type>, <"required"|"optional"|"vararg">, <description>]...
+                        MSG OUT: /describe_bundle_reply, <tag>, <layout>, <description>
+                    */
+                    "/describe" => {
+                        for reply in command_registry::describe() {
+                            self.client.send_out(reply);
+                        }
+                    }
+                    /*
+                        Also send everything that goes to outgoing_port to the given port on
+                        application_ip, for the given number of seconds or until /unsubscribe.
+                        For clients that cannot take over outgoing_port, e.g. jdw-sc-ctl while
+                        it waits for a reply. Subscribing again renews the lifetime.
+                    */
+                    "/subscribe" => {
+                        let port = osc_message.get_int_at(0, "port").unwrap();
+                        let secs = osc_message.get_int_at(1, "lifetime").unwrap();
+
+                        self.client
+                            .subscribers()
+                            .add(port, Duration::from_secs(secs.max(0) as u64));
+                    }
+                    "/unsubscribe" => {
+                        let port = osc_message.get_int_at(0, "port").unwrap();
+                        self.client.subscribers().remove(port);
+                    }
                     _ => {}
                 }
             }
@@ -249,38 +1269,49 @@ impl Interpreter {
                                     }
                                 },
                                 "nrt_preload" => {
-                                    tagged_bundle
-                                        .contents
-                                        .iter()
-                                        .map(|packet| {
-                                            if let OscPacket::Bundle(bundle) = packet {
-                                                TaggedBundle::new(bundle).map(Some).unwrap_or(None)
-                                            } else {
-                                                None
-                                            }
-                                        })
-                                        .filter(|opt| opt.is_some())
-                                        .map(Option::unwrap)
-                                        .map(|bundle| {
-                                            TimedOSCPacket::from_bundle(bundle)
-                                                .map(Some)
-                                                .unwrap_or(None)
-                                        })
-                                        .filter(|opt| opt.is_some())
-                                        .map(Option::unwrap)
-                                        .for_each(|packet| self.nrt_preloads.push(packet.clone()));
-                                    info!("Preloaded nrt packets: {}", self.nrt_preloads.len());
+                                    match NRTPreloadMessage::from_bundle(tagged_bundle) {
+                                        Ok(preload) => {
+                                            self.nrt_preloads.extend(preload.messages);
+                                            info!("Preloaded nrt packets: {}", self.nrt_preloads.len());
+                                        }
+                                        Err(e) => {
+                                            warn!("Malformed nrt_preload: {}", e);
+                                        }
+                                    }
                                 }
                                 "nrt_record" => {
                                     match NRTRecordMessage::from_bundle(tagged_bundle) {
+                                        // Scores are rendered by sclang
+                                        Ok(nrt_record_msg) if !self.client.has_sclang() => {
+                                            error!("NRT recording requires sclang, which is not running");
+                                            self.client.send_out(OscMessage {
+                                                addr: "/nrt_record_finished".to_string(),
+                                                args: vec![
+                                                    OscType::String("FAILURE".to_string()),
+                                                    OscType::String(nrt_record_msg.file_name),
+                                                ],
+                                            });
+                                        }
                                         Ok(nrt_record_msg) => {
                                             // Begin building the score rows with the sythdef creation strings
+                                            // Compiled and cached bytes are embedded directly; uncached sources are compiled by sclang
                                             let mut score_rows: Vec<String> = self
-                                                .nrt_synthdef_snippets
+                                                .nrt_synthdefs
+                                                .entries()
                                                 .iter()
-                                                .map(|def| def.clone() + ".asBytes")
-                                                .map(|def| {
-                                                    return scd_templating::nrt_wrap_synthdef(&def);
+                                                .map(|entry| {
+                                                    match entry
+                                                        .compiled
+                                                        .clone()
+                                                        .or_else(|| synthdef_cache::read(&self.config, &entry.source))
+                                                    {
+                                                        Some(bytes) => scd_templating::nrt_wrap_synthdef(
+                                                            &scd_templating::int8_array_literal(&bytes),
+                                                        ),
+                                                        None => scd_templating::nrt_wrap_synthdef(
+                                                            &(entry.source.clone() + ".asBytes"),
+                                                        ),
+                                                    }
                                                 })
                                                 .collect();
 
@@ -288,13 +1319,32 @@ impl Interpreter {
                                             for sample in
                                                 self.nrt_sample_pack_dict.get_all_samples()
                                             {
-                                                score_rows.push(sample.get_nrt_scd_row());
+                                                score_rows.push(sample.get_nrt_scd_row(&self.config));
                                             }
 
                                             // Collect messages to be played as score rows along a timeline
                                             // TODO: Legacy internal osc conversion, but works for now and is a mess to clean up
                                             let reg_handle =
-                                                Arc::new(Mutex::new(NodeIDRegistry::new()));
+                                                Arc::new(Mutex::new(NodeIDRegistry::new(&self.config)));
+
+                                            // Recreate the live groups at the start of the score
+                                            for group in self.reg.groups() {
+                                                let created = reg_handle.lock().unwrap().create_group(
+                                                    &group.name,
+                                                    &group.external_id_regex,
+                                                    group.parallel,
+                                                );
+
+                                                if let Ok((nrt_group, _)) = created {
+                                                    score_rows.push(
+                                                        TimedOSCPacket {
+                                                            time: BigDecimal::zero(),
+                                                            packet: OscPacket::Message(nrt_group.create_message()),
+                                                        }
+                                                        .as_nrt_row(),
+                                                    );
+                                                }
+                                            }
                                             let dict_clone = self.nrt_sample_pack_dict.clone();
                                             let sample_pack_dict_arc =
                                                 Arc::new(Mutex::new(dict_clone));
@@ -316,6 +1366,9 @@ impl Interpreter {
                                                             internal_osc_conversion::resolve_msg(
                                                                 timed_packet.packet.clone(),
                                                                 sample_pack_dict_arc.clone(),
+                                                                &self.synthdefs,
+                                                                self.config.gateless_fade_ms,
+                                                                nrt_record_msg.bpm,
                                                             )
                                                             .map(|sc_msg| {
                                                                 sc_msg.as_nrt_osc(
@@ -343,6 +1396,7 @@ impl Interpreter {
                                             }
 
                                             let script = create_nrt_script(
+                                                &self.config,
                                                 nrt_record_msg.bpm,
                                                 &nrt_record_msg.file_name,
                                                 nrt_record_msg.end_beat,
@@ -382,7 +1436,7 @@ impl Interpreter {
                                             match self.client.await_internal_response(
                                                 "/nrt_done",
                                                 vec![OscType::String("ok".to_string())],
-                                                Duration::from_secs(config::Config::get().nrt_done_timeout_secs),
+                                                Duration::from_secs(self.config.nrt_done_timeout_secs),
                                             ) {
                                                 Err(e) => {
                                                     error!("Timed out waiting for NRT done {}", e);
@@ -430,25 +1484,177 @@ impl Interpreter {
     }
 }
 
-pub fn run(host_url: String, client: SCClient, sampler_snippet: String) {
-    let addr = match SocketAddrV4::from_str(&host_url) {
-        Ok(addr) => addr,
-        Err(e) => panic!("{}", e),
-    };
+// Send a packet to the daemon's public in-port (application_in_port), e.g. from a helper thread
+pub fn send_to_daemon(cfg: &Config, packet: &OscPacket) -> Result<(), String> {
+    let msg_buf = encoder::encode(packet).map_err(|e| e.to_string())?;
+    let socket = UdpSocket::bind(cfg.addr(0)).map_err(|e| e.to_string())?;
+
+    socket
+        .send_to(&msg_buf, cfg.addr(cfg.application_in_port))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn is_subscription(packet: &OscPacket) -> bool {
+    match packet {
+        OscPacket::Message(msg) => msg.addr == "/subscribe" || msg.addr == "/unsubscribe",
+        OscPacket::Bundle(_) => false,
+    }
+}
+
+// Journal a received packet unless it is a subscription, then interpret it
+fn receive<B: ScBackend>(
+    interpreter: &mut Interpreter<B>,
+    journal: &mut Option<JournalWriter>,
+    packet: OscPacket,
+    packet_bytes: &[u8],
+    receive_time: SystemTime,
+) {
+    // Subscribed ports belong to clients of this run only
+    if let Some(writer) = journal.as_mut().filter(|_| !is_subscription(&packet)) {
+        if let Err(e) = writer.append(receive_time, packet_bytes) {
+            warn!("Failed to write session journal: {}", e);
+        }
+    }
+
+    interpreter.interpret(packet, receive_time);
+}
+
+/*
+    Listen for messages on the socket bound to application_in_port until a /quit has been handled.
+    Packets passed in-process (JSON clients, JdwSc::send) arrive on packets instead, so that they
+        are not limited to the size of a UDP datagram.
+    A given replay is fed into the interpreter first, see journal.rs.
+    Returns the exit code of the shutdown sequence.
+*/
+pub fn run(
+    sock: UdpSocket,
+    packets: Receiver<OscPacket>,
+    client: SCClient,
+    sampler_snippet: String,
+    shutdown: ShutdownHandle,
+    replay: Option<Replay>,
+) -> i32 {
+    let status = StatusMonitor::new(client.config().status_history_size);
+    match client.try_clone() {
+        Ok(status_client) => status.spawn(status_client, shutdown.clone()),
+        Err(e) => warn!("Failed to start status polling: {}", e),
+    }
+
+    serve(
+        sock,
+        packets,
+        client,
+        sampler_snippet,
+        shutdown,
+        status,
+        replay,
+    )
+}
+
+/*
+    Same as run, for any backend. Status polling is left to the caller.
+*/
+pub fn serve<B: ScBackend>(
+    sock: UdpSocket,
+    packets: Receiver<OscPacket>,
+    client: B,
+    sampler_snippet: String,
+    shutdown: ShutdownHandle,
+    status: StatusMonitor,
+    replay: Option<Replay>,
+) -> i32 {
+    let cfg = client.config().clone();
+
+    let mut buf = vec![0u8; cfg.buffer_size];
+
+    let mut interpreter = Interpreter::new(
+        client,
+        sampler_snippet.clone(),
+        shutdown.clone(),
+        status,
+    );
+
+    interpreter.load_sampler();
 
-    let sock = UdpSocket::bind(addr).unwrap();
+    // Watcher changes are handled between received packets, see synthdef_library.rs
+    let (library_events, library_changes) = mpsc::channel();
 
-    let mut buf = vec![0u8; config::Config::get().buffer_size];
+    if let Some(dir) = synthdef_library::library_dir(&cfg) {
+        let files = synthdef_library::scan(&dir);
 
-    let mut interpreter = Interpreter::new(client, sampler_snippet);
+        for path in files.keys() {
+            interpreter.load_synthdef_file(&path.to_string_lossy());
+        }
+
+        synthdef_library::spawn_watcher(dir, files, cfg.clone(), shutdown.clone(), library_events);
+    }
+
+    if let Err(e) = sock.set_read_timeout(Some(Duration::from_millis(cfg.poll_sleep_ms.max(1)))) {
+        warn!("Failed to set in-port read timeout: {}", e);
+    }
+
+    // Every received packet is appended as-is, before interpretation
+    let mut journal = if cfg.journal_path.is_empty() {
+        None
+    } else {
+        JournalWriter::open(&cfg.journal_path)
+            .map_err(|e| warn!("Session journal disabled: {}", e))
+            .ok()
+    };
+
+    shutdown.mark_listening();
+
+    // Packets arriving on the socket meanwhile are handled once the replay is done
+    if let Some(replay) = replay {
+        if let Some(code) = replay.run(&mut interpreter) {
+            return code;
+        }
+    }
 
     loop {
+        for event in library_changes.try_iter() {
+            interpreter.library_event(event);
+        }
+
+        interpreter.handle_replies();
+
+        for packet in packets.try_iter() {
+            // Journaled in the same encoding as packets from the socket
+            match encoder::encode(&packet) {
+                Ok(packet_bytes) => receive(
+                    &mut interpreter,
+                    &mut journal,
+                    packet,
+                    &packet_bytes,
+                    SystemTime::now(),
+                ),
+                Err(e) => warn!("Failed to encode passed packet: {}", e),
+            }
+
+            if let Some(code) = interpreter.exit_code() {
+                return code;
+            }
+        }
+
         match sock.recv_from(&mut buf) {
             Ok((size, _)) => {
+                let receive_time = SystemTime::now();
                 let (_rem, packet) = rosc::decoder::decode_udp(&buf[..size]).unwrap();
 
-                interpreter.interpret(packet, SystemTime::now());
+                receive(
+                    &mut interpreter,
+                    &mut journal,
+                    packet,
+                    &buf[..size],
+                    receive_time,
+                );
+
+                if let Some(code) = interpreter.exit_code() {
+                    return code;
+                }
             }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
             Err(e) => {
                 warn!("Failed to receive from socket {}", e);
             }
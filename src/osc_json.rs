@@ -0,0 +1,269 @@
+/*
+   JSON form of OSC values, for serde on the osc_model types and for the JSON endpoint.
+
+   Args are plain JSON values: integers are sent as Int (Long if they do not fit), other numbers as
+       Float, strings as String, booleans as Bool and null as Nil. Blobs and times are objects:
+       {"blob": [1, 2, 3]}, {"time": [<seconds>, <fractional>]}.
+
+   Messages are {"addr": "/note_on", "args": [...]} and bundles {"timetag": [<seconds>, <fractional>],
+       "content": [...]}, where the timetag defaults to immediately. Timed packets (timed_msg) are
+       {"time": "0.5", "packet": <packet>}, with the time in beats as a string or a number.
+*/
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use jdw_osc_lib::model::TimedOSCPacket;
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use serde_json::{json, Value};
+
+pub fn arg_to_json(arg: &OscType) -> Value {
+    match arg {
+        OscType::Int(value) => json!(value),
+        OscType::Long(value) => json!(value),
+        OscType::Float(value) => json!(value),
+        OscType::Double(value) => json!(value),
+        OscType::String(value) => json!(value),
+        OscType::Bool(value) => json!(value),
+        OscType::Nil => Value::Null,
+        OscType::Blob(value) => json!({ "blob": value }),
+        OscType::Time(time) => json!({ "time": [time.seconds, time.fractional] }),
+        other => json!(format!("{:?}", other)),
+    }
+}
+
+pub fn arg_from_json(value: &Value) -> Result<OscType, String> {
+    match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(int) => Ok(i32::try_from(int)
+                .map(OscType::Int)
+                .unwrap_or(OscType::Long(int))),
+            None => number
+                .as_f64()
+                .map(|float| OscType::Float(float as f32))
+                .ok_or(format!("Unsupported number arg: {}", number)),
+        },
+        Value::String(string) => Ok(OscType::String(string.clone())),
+        Value::Bool(boolean) => Ok(OscType::Bool(*boolean)),
+        Value::Null => Ok(OscType::Nil),
+        Value::Object(object) => {
+            if let Some(blob) = object.get("blob") {
+                serde_json::from_value(blob.clone())
+                    .map(OscType::Blob)
+                    .map_err(|e| format!("Malformed blob arg: {}", e))
+            } else if let Some(time) = object.get("time") {
+                serde_json::from_value::<(u32, u32)>(time.clone())
+                    .map(|time| OscType::Time(OscTime::from(time)))
+                    .map_err(|e| format!("Malformed time arg: {}", e))
+            } else {
+                Err(format!("Unsupported object arg: {}", value))
+            }
+        }
+        Value::Array(_) => Err(format!("Unsupported array arg: {}", value)),
+    }
+}
+
+pub fn message_to_json(msg: &OscMessage) -> Value {
+    json!({
+        "addr": msg.addr,
+        "args": msg.args.iter().map(arg_to_json).collect::<Vec<Value>>(),
+    })
+}
+
+pub fn message_from_json(value: &Value) -> Result<OscMessage, String> {
+    let addr = value
+        .get("addr")
+        .and_then(Value::as_str)
+        .ok_or(format!("Message without addr: {}", value))?;
+
+    let args = match value.get("args") {
+        Some(Value::Array(args)) => args.iter().map(arg_from_json).collect::<Result<_, _>>()?,
+        Some(other) => return Err(format!("Args of {} are not a list: {}", addr, other)),
+        None => vec![],
+    };
+
+    Ok(OscMessage {
+        addr: addr.to_string(),
+        args,
+    })
+}
+
+pub fn packet_to_json(packet: &OscPacket) -> Value {
+    match packet {
+        OscPacket::Message(msg) => message_to_json(msg),
+        OscPacket::Bundle(bundle) => json!({
+            "timetag": [bundle.timetag.seconds, bundle.timetag.fractional],
+            "content": bundle.content.iter().map(packet_to_json).collect::<Vec<Value>>(),
+        }),
+    }
+}
+
+pub fn packet_from_json(value: &Value) -> Result<OscPacket, String> {
+    match value.get("content") {
+        Some(Value::Array(content)) => {
+            let timetag = match value.get("timetag") {
+                Some(timetag) => serde_json::from_value::<(u32, u32)>(timetag.clone())
+                    .map_err(|e| format!("Malformed timetag: {}", e))?,
+                None => (0, 1), // Immediately
+            };
+
+            Ok(OscPacket::Bundle(OscBundle {
+                timetag: OscTime::from(timetag),
+                content: content
+                    .iter()
+                    .map(packet_from_json)
+                    .collect::<Result<_, _>>()?,
+            }))
+        }
+        Some(other) => Err(format!("Bundle content is not a list: {}", other)),
+        None => message_from_json(value).map(OscPacket::Message),
+    }
+}
+
+pub fn decimal_from_json(value: &Value) -> Result<BigDecimal, String> {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => number.to_string(),
+        other => return Err(format!("Expected a decimal, got {}", other)),
+    };
+
+    BigDecimal::from_str(&text).map_err(|e| format!("Malformed decimal {}: {}", text, e))
+}
+
+pub fn timed_packet_to_json(timed: &TimedOSCPacket) -> Value {
+    json!({
+        "time": timed.time.to_string(),
+        "packet": packet_to_json(&timed.packet),
+    })
+}
+
+pub fn timed_packet_from_json(value: &Value) -> Result<TimedOSCPacket, String> {
+    Ok(TimedOSCPacket {
+        time: decimal_from_json(value.get("time").ok_or("Timed packet without time")?)?,
+        packet: packet_from_json(value.get("packet").ok_or("Timed packet without packet")?)?,
+    })
+}
+
+/*
+    Modules for #[serde(with = ...)] on fields of foreign types, in the forms above.
+*/
+
+pub mod args {
+    use rosc::OscType;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(args: &[OscType], serializer: S) -> Result<S::Ok, S::Error> {
+        let values: Vec<Value> = args.iter().map(super::arg_to_json).collect();
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<OscType>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .iter()
+            .map(super::arg_from_json)
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+pub mod decimal {
+    use bigdecimal::BigDecimal;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(value: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+        super::decimal_from_json(&Value::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+pub mod packet {
+    use rosc::OscPacket;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(packet: &OscPacket, serializer: S) -> Result<S::Ok, S::Error> {
+        super::packet_to_json(packet).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OscPacket, D::Error> {
+        super::packet_from_json(&Value::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+pub mod packets {
+    use rosc::OscPacket;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(
+        packets: &[OscPacket],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let values: Vec<Value> = packets.iter().map(super::packet_to_json).collect();
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<OscPacket>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .iter()
+            .map(super::packet_from_json)
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+pub mod timed_packets {
+    use jdw_osc_lib::model::TimedOSCPacket;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(
+        timed: &[TimedOSCPacket],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let values: Vec<Value> = timed.iter().map(super::timed_packet_to_json).collect();
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<TimedOSCPacket>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .iter()
+            .map(super::timed_packet_from_json)
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom)
+    }
+}
+
+// Seconds since the unix epoch, as a number
+pub mod unix_time {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        serializer.serialize_f64(seconds)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+
+        Duration::try_from_secs_f64(seconds)
+            .map(|since_epoch| UNIX_EPOCH + since_epoch)
+            .map_err(|e| D::Error::custom(format!("Malformed unix time {}: {}", seconds, e)))
+    }
+}
@@ -7,10 +7,66 @@ use std::{convert::TryFrom, time::SystemTime};
 use bigdecimal::BigDecimal;
 use jdw_osc_lib::model::{OscArgHandler, TaggedBundle, TimedOSCPacket};
 use log::{info, warn};
-use rosc::{OscMessage, OscPacket, OscType};
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use serde::{Deserialize, Serialize};
+
+use crate::osc_json;
+
+// Bundle as read by TaggedBundle::new: [/bundle_info, <tag>] followed by the contents
+pub fn tagged_bundle(tag: &str, mut contents: Vec<OscPacket>) -> OscPacket {
+    contents.insert(
+        0,
+        OscPacket::Message(OscMessage {
+            addr: "/bundle_info".to_string(),
+            args: vec![OscType::String(tag.to_string())],
+        }),
+    );
+
+    untagged_bundle(contents)
+}
+
+fn untagged_bundle(content: Vec<OscPacket>) -> OscPacket {
+    OscPacket::Bundle(OscBundle {
+        timetag: OscTime::from((0, 1)), // Immediately
+        content,
+    })
+}
+
+// [/bundle_info, "timed_msg"], [/timed_msg_info, <time>], <packet>; see TimedOSCPacket::from_bundle
+pub fn timed_msg_bundle(timed: &TimedOSCPacket) -> OscPacket {
+    tagged_bundle(
+        "timed_msg",
+        vec![
+            OscPacket::Message(OscMessage {
+                addr: "/timed_msg_info".to_string(),
+                args: vec![OscType::String(timed.time.to_string())],
+            }),
+            timed.packet.clone(),
+        ],
+    )
+}
 
+fn message(addr: &str, mut args: Vec<OscType>, named_args: &[OscType]) -> OscMessage {
+    args.extend(named_args.iter().cloned());
+
+    OscMessage {
+        addr: addr.to_string(),
+        args,
+    }
+}
+
+fn same_timed_packets(a: &[TimedOSCPacket], b: &[TimedOSCPacket]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.time == b.time && a.packet == b.packet)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RealTimePacket {
+    #[serde(with = "osc_json::packet")]
     pub packet: OscPacket,
+    #[serde(with = "osc_json::unix_time")]
     pub time: SystemTime,
 }
 
@@ -44,15 +100,107 @@ impl RealTimePacket {
             Err("Info message was a bundle".to_string())
         }
     }
+
+    pub fn to_osc(&self) -> OscPacket {
+        // Send times before 1900 or after 2036 have no OSC time
+        let time = OscTime::try_from(self.time).unwrap();
+
+        tagged_bundle(
+            "real_time_packet",
+            vec![
+                OscPacket::Message(message(
+                    "/real_time_packet_info",
+                    vec![OscType::Time(time)],
+                    &[],
+                )),
+                self.packet.clone(),
+            ],
+        )
+    }
+}
+
+// [/bundle_info, "batch-send"] followed by packets that are interpreted one by one on arrival
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchSendMessage {
+    #[serde(with = "osc_json::packets")]
+    pub packets: Vec<OscPacket>,
+}
+
+impl BatchSendMessage {
+    pub fn from_bundle(bundle: TaggedBundle) -> Result<BatchSendMessage, String> {
+        if &bundle.bundle_tag != "batch-send" {
+            return Err(format!(
+                "Attempted to parse {} as batch-send bundle",
+                &bundle.bundle_tag
+            ));
+        }
+
+        Ok(BatchSendMessage {
+            packets: bundle.contents,
+        })
+    }
+
+    pub fn to_osc(&self) -> OscPacket {
+        tagged_bundle("batch-send", self.packets.clone())
+    }
+}
+
+/*
+   [/bundle_info, "nrt_preload"] followed by timed_msg bundles, which are placed at the start of
+       every following nrt_record score. Contents that are not timed_msg bundles are skipped.
+*/
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NRTPreloadMessage {
+    #[serde(with = "osc_json::timed_packets")]
+    pub messages: Vec<TimedOSCPacket>,
+}
+
+impl NRTPreloadMessage {
+    pub fn from_bundle(bundle: TaggedBundle) -> Result<NRTPreloadMessage, String> {
+        if &bundle.bundle_tag != "nrt_preload" {
+            return Err(format!(
+                "Attempted to parse {} as nrt_preload bundle",
+                &bundle.bundle_tag
+            ));
+        }
+
+        let messages = bundle
+            .contents
+            .iter()
+            .filter_map(|packet| match packet {
+                OscPacket::Bundle(bundle) => TaggedBundle::new(bundle).ok(),
+                OscPacket::Message(_) => None,
+            })
+            .filter_map(|bundle| TimedOSCPacket::from_bundle(bundle).ok())
+            .collect();
+
+        Ok(NRTPreloadMessage { messages })
+    }
+
+    pub fn to_osc(&self) -> OscPacket {
+        tagged_bundle(
+            "nrt_preload",
+            self.messages.iter().map(timed_msg_bundle).collect(),
+        )
+    }
+}
+
+impl PartialEq for NRTPreloadMessage {
+    fn eq(&self, other: &Self) -> bool {
+        same_timed_packets(&self.messages, &other.messages)
+    }
 }
 
 // Initial structure below: (Note that we might want to expose other s_new args eventually)
 // ["/note_on_timed", "my_synth", "kb_my_synth_n33", 0.2, "arg1", 0.2, "arg2", 0.4, ...]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NoteOnTimedMessage {
     pub synth_name: String,  // The synth upon which to play the note.
     pub external_id: String, // Identifier for note to allow later modification.
+    #[serde(with = "osc_json::decimal")]
     pub gate_time: BigDecimal,
     pub delay_ms: u64,
+    #[serde(with = "osc_json::args", default)]
     pub args: Vec<OscType>, // Named args such as "bus" or "rel"
 }
 
@@ -75,9 +223,22 @@ impl NoteOnTimedMessage {
             args: named_args,
         })
     }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/note_on_timed",
+            vec![
+                OscType::String(self.synth_name.clone()),
+                OscType::String(self.external_id.clone()),
+                OscType::String(self.gate_time.to_string()),
+                OscType::Int(self.delay_ms as i32),
+            ],
+            &self.args,
+        )
+    }
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoadSampleMessage {
     pub file_path: String,
     pub sample_pack: String,
@@ -99,14 +260,30 @@ impl LoadSampleMessage {
             tone_index: msg.get_int_at(4, "tone_index")?,
         })
     }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/load_sample",
+            vec![
+                OscType::String(self.file_path.clone()),
+                OscType::String(self.sample_pack.clone()),
+                OscType::Int(self.buffer_number),
+                OscType::String(self.category_tag.clone()),
+                OscType::Int(self.tone_index),
+            ],
+            &[],
+        )
+    }
 }
 
 // ProscNoteCreateMessage
 // Non-timed regular s_new with external_id for later modifications
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NoteOnMessage {
     pub synth_name: String,  // The synth upon which to play the note.
     pub external_id: String, // Identifier for note to allow later modification.
     pub delay_ms: u64,
+    #[serde(with = "osc_json::args", default)]
     pub args: Vec<OscType>, // Named args such as "bus" or "rel"
 }
 
@@ -127,14 +304,28 @@ impl NoteOnMessage {
             args: named_args,
         })
     }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/note_on",
+            vec![
+                OscType::String(self.synth_name.clone()),
+                OscType::String(self.external_id.clone()),
+                OscType::Int(self.delay_ms as i32),
+            ],
+            &self.args,
+        )
+    }
 }
 
 // ProscNoteModifyMessage
 // n_set implementation with added external_id to allow modifying any note
 // NOTE: Note-off doesn't need its own message; it is simply an n_set with gate=0
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NoteModifyMessage {
     pub external_id_regex: String, // Modify all running external ids matching this regex
     pub delay_ms: u64,
+    #[serde(with = "osc_json::args", default)]
     pub args: Vec<OscType>, // Args to set (same as in SNewTimedGateMessage)
 }
 
@@ -153,16 +344,54 @@ impl NoteModifyMessage {
             args,
         })
     }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/note_modify",
+            vec![
+                OscType::String(self.external_id_regex.clone()),
+                OscType::Int(self.delay_ms as i32),
+            ],
+            &self.args,
+        )
+    }
+}
+
+// MSG: /free_notes, <external id regex>
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FreeNotesMessage {
+    pub external_id_regex: String, // Free all running external ids matching this regex
+}
+
+impl FreeNotesMessage {
+    pub fn new(message: &OscMessage) -> Result<FreeNotesMessage, String> {
+        message.expect_addr("/free_notes")?;
+        message.expect_args(1)?;
+
+        let external_id_regex = message.get_string_at(0, "Regex string")?;
+
+        Ok(FreeNotesMessage { external_id_regex })
+    }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/free_notes",
+            vec![OscType::String(self.external_id_regex.clone())],
+            &[],
+        )
+    }
 }
 
 // Example below of args in order with "" as category (= Empty)
 // ["/play_sample", "my_unique_id", "example", 2, "", "arg1", 0.2, "arg2", 0.4, ...]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PlaySampleMessage {
     pub external_id: String,
     pub sample_pack: String,      // The parent dir of the sample file
     pub index: usize, // Sample number - either as plain order in dir or in a given category
     pub category: Option<String>, // TODO: Arbitrary string codes... is there a better way?
     pub delay_ms: u64,
+    #[serde(with = "osc_json::args", default)]
     pub args: Vec<OscType>, // Args to set (same as in SNewTimedGateMessage)
 }
 
@@ -197,6 +426,20 @@ impl PlaySampleMessage {
             args,
         })
     }
+
+    pub fn to_osc(&self) -> OscMessage {
+        message(
+            "/play_sample",
+            vec![
+                OscType::String(self.external_id.clone()),
+                OscType::String(self.sample_pack.clone()),
+                OscType::Int(self.index as i32),
+                OscType::String(self.category.clone().unwrap_or_default()),
+                OscType::Int(self.delay_ms as i32),
+            ],
+            &self.args,
+        )
+    }
 }
 
 /*
@@ -205,9 +448,11 @@ impl PlaySampleMessage {
    [/nrt_record_info, <bpm: 120.0>, <file_name: "myfile.wav">, <end_beat: 44.0>]
    followed by untagged bundle: all contained timed messages
 */
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NRTRecordMessage {
     pub file_name: String,
     pub bpm: f32,
+    #[serde(with = "osc_json::timed_packets")]
     pub messages: Vec<TimedOSCPacket>,
     pub end_beat: f32,
 }
@@ -263,4 +508,31 @@ impl NRTRecordMessage {
             end_beat,
         })
     }
+
+    pub fn to_osc(&self) -> OscPacket {
+        tagged_bundle(
+            "nrt_record",
+            vec![
+                OscPacket::Message(message(
+                    "/nrt_record_info",
+                    vec![
+                        OscType::Float(self.bpm),
+                        OscType::String(self.file_name.clone()),
+                        OscType::Float(self.end_beat),
+                    ],
+                    &[],
+                )),
+                untagged_bundle(self.messages.iter().map(timed_msg_bundle).collect()),
+            ],
+        )
+    }
+}
+
+impl PartialEq for NRTRecordMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.file_name == other.file_name
+            && self.bpm == other.bpm
+            && self.end_beat == other.end_beat
+            && same_timed_packets(&self.messages, &other.messages)
+    }
 }
@@ -0,0 +1,197 @@
+/*
+   Crashes can leave sclang and scsynth running, which in turn makes the next start fail
+       to bind its ports. The pids of the managed processes are therefore written to
+       jdw-sc.pid in state_dir so that a later start can find and terminate them.
+
+   File format is one process per line: <role> <pid> <process name>
+*/
+use std::fs;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::config::Config;
+
+const PIDFILE_NAME: &str = "jdw-sc.pid";
+
+#[derive(Debug, Clone)]
+pub struct ManagedProcess {
+    pub role: String, // "sclang" or "scsynth"
+    pub pid: u32,
+    pub name: String, // Process name as reported by ps, used to avoid killing recycled pids
+}
+
+impl ManagedProcess {
+    pub fn new(role: &str, pid: u32) -> ManagedProcess {
+        ManagedProcess {
+            role: role.to_string(),
+            pid,
+            name: process_name(pid).unwrap_or_default(),
+        }
+    }
+}
+
+fn pidfile_path(cfg: &Config) -> Result<PathBuf, String> {
+    Ok(cfg.state_path()?.join(PIDFILE_NAME))
+}
+
+// Name of a running process, or None if no process with the pid is alive
+fn process_name(pid: u32) -> Option<String> {
+    let output = Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "comm="])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    // Some platforms report the full binary path
+    let name = name.rsplit('/').next().unwrap_or("").to_string();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+pub fn is_alive(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+fn signal(pid: u32, signal: &str) {
+    if let Err(e) = Command::new("kill")
+        .args([signal, &pid.to_string()])
+        .output()
+    {
+        warn!("Failed to send {} to pid {}: {}", signal, pid, e);
+    }
+}
+
+// Send SIGTERM, escalating to SIGKILL if the process is still alive after the timeout
+pub fn terminate(pid: u32, timeout: Duration) -> bool {
+    signal(pid, "-TERM");
+
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if !is_alive(pid) {
+            return true;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    warn!("Process {} did not exit after SIGTERM, sending SIGKILL", pid);
+    signal(pid, "-KILL");
+    sleep(Duration::from_millis(50));
+    !is_alive(pid)
+}
+
+pub fn read(cfg: &Config) -> Vec<ManagedProcess> {
+    let contents = match pidfile_path(cfg)
+        .and_then(|path| fs::read_to_string(path).map_err(|e| e.to_string()))
+    {
+        Ok(contents) => contents,
+        Err(_) => return vec![],
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let role = parts.next()?;
+            let pid = parts.next()?.parse::<u32>().ok()?;
+            let name = parts.next().unwrap_or("");
+            Some(ManagedProcess {
+                role: role.to_string(),
+                pid,
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn write(cfg: &Config, processes: &[ManagedProcess]) -> Result<(), String> {
+    let path = pidfile_path(cfg)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create state dir {}: {}", dir.display(), e))?;
+    }
+
+    let contents: String = processes
+        .iter()
+        .map(|p| format!("{} {} {}\n", p.role, p.pid, p.name))
+        .collect();
+
+    fs::write(&path, contents)
+        .map_err(|e| format!("Failed to write pidfile {}: {}", path.display(), e))
+}
+
+pub fn remove(cfg: &Config) {
+    let path = match pidfile_path(cfg) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove pidfile {:?}: {}", path, e);
+        }
+    }
+}
+
+/*
+    Terminate any processes left behind by a previous run. A pid is only considered stale
+        if the process running under it still carries the name recorded in the pidfile.
+*/
+pub fn cleanup_stale(cfg: &Config) {
+    for process in read(cfg) {
+        match process_name(process.pid) {
+            Some(name) if name == process.name => {
+                warn!(
+                    "Found orphaned {} process from a previous run (pid {}), terminating it",
+                    process.role, process.pid
+                );
+                if !terminate(process.pid, Duration::from_secs(2)) {
+                    warn!("Could not terminate orphaned pid {}", process.pid);
+                }
+            }
+            Some(name) => debug!(
+                "Pid {} from pidfile now belongs to '{}', leaving it alone",
+                process.pid, name
+            ),
+            None => debug!("Stale pid {} from pidfile is no longer running", process.pid),
+        }
+    }
+
+    remove(cfg);
+}
+
+/*
+    Make sure that the UDP ports used by jdw-sc and its managed processes are free.
+    Any port still held at this point belongs to a process that jdw-sc does not manage.
+*/
+pub fn check_ports(cfg: &Config, ports: &[(&str, i32)]) -> Result<(), String> {
+    for (name, port) in ports {
+        let addr = cfg.addr(*port);
+        if let Err(e) = UdpSocket::bind(&addr) {
+            return Err(format!(
+                "Port {} ({}) is already in use by another process: {}. \
+                 Stop the process holding it or configure a different port.",
+                port, name, e
+            ));
+        }
+    }
+
+    info!("All configured ports are free");
+    Ok(())
+}
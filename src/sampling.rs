@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use log::info;
+use rosc::{OscMessage, OscType};
 
-use crate::config;
+use crate::config::Config;
 use crate::osc_model::LoadSampleMessage;
 
 #[derive(Debug, Clone)]
@@ -24,17 +25,29 @@ pub struct SamplePackDict {
 }
 
 impl Sample {
-    pub fn get_buffer_load_scd(&self) -> String {
+    pub fn get_buffer_load_scd(&self, server_name: &str) -> String {
         format!(
             "Buffer.read({}, \"{}\", 0, -1, bufnum: {}); \n",
-            config::Config::get().server_name,
+            server_name,
             self.file_path,
             self.buffer_number
         )
     }
 
-    pub fn get_nrt_scd_row(&self) -> String {
-        let cfg = config::Config::get();
+    // Same as get_buffer_load_scd, sent directly to scsynth when running without sclang
+    pub fn get_buffer_alloc_read_msg(&self) -> OscMessage {
+        OscMessage {
+            addr: "/b_allocRead".to_string(),
+            args: vec![
+                OscType::Int(self.buffer_number),
+                OscType::String(self.file_path.clone()),
+                OscType::Int(0),
+                OscType::Int(-1),
+            ],
+        }
+    }
+
+    pub fn get_nrt_scd_row(&self, cfg: &Config) -> String {
         format!(
             "[0.0, (Buffer.new(server, {}, {}, bufnum: {})).allocReadMsg(\"{}\")]",
             cfg.sample_buffer_frames,
@@ -0,0 +1,301 @@
+/*
+   The interpreter's view of SuperCollider: sending to scsynth, sclang and clients, and awaiting
+       replies from the managed processes.
+
+   SCClient is the UDP implementation used when running for real. RecordingBackend keeps
+       everything in memory instead, so that interpreter logic can be tested without SuperCollider:
+       sent packets are recorded for inspection and awaited replies are taken from a queue.
+*/
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use jdw_osc_lib::model::TimedOSCPacket;
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+
+use crate::config::Config;
+
+pub trait ScBackend {
+    fn config(&self) -> &Arc<Config>;
+
+    // False when scsynth runs without sclang
+    fn has_sclang(&self) -> bool;
+
+    // Send the packet to scsynth, timetagged to execute delay_ms after receive_time
+    fn send_to_scsynth_with_delay(&self, msg: OscPacket, delay_ms: u64, receive_time: SystemTime);
+
+    fn send_to_sclang(&self, msg: OscMessage);
+
+    // Send a message to clients (outgoing_port and any subscribers)
+    fn send_out(&self, msg: OscMessage);
+
+    fn subscribers(&self) -> &Subscribers;
+
+    // Wait for a message from the managed processes with the given address that matches
+    fn await_message(
+        &self,
+        message_name: &str,
+        matches: &dyn Fn(&OscMessage) -> bool,
+        timeout: Duration,
+    ) -> Result<OscMessage, String>;
+
+    // Messages from the managed processes that no await has taken, oldest first, without blocking
+    fn take_messages(&self) -> Vec<OscMessage>;
+
+    // False when nothing ever replies, so that pending requests are not waited for
+    fn has_replies(&self) -> bool {
+        true
+    }
+
+    /*
+       Note on delay: supercollider execution time can vary by a few milliseconds.
+       By providing a delay, we remove this variation via specifying the exact time of execution.
+       This is important in precise sequencing but unimportant for direct human input.
+    */
+    fn send_timed_packets_to_scsynth(
+        &self,
+        delay_ms: u64,
+        msgs: Vec<TimedOSCPacket>,
+        receive_time: SystemTime,
+    ) {
+        for msg in msgs {
+            if msg.time == BigDecimal::zero() {
+                self.send_to_scsynth_with_delay(msg.packet, delay_ms, receive_time);
+            } else {
+                // Tell supercollider to execute the message after a delay
+                let time_in_ms = BigDecimal::from_str("1000.00").unwrap() * msg.time.clone();
+                let time_integer = time_in_ms.to_u64().unwrap();
+                self.send_to_scsynth_with_delay(msg.packet, delay_ms + time_integer, receive_time);
+            }
+        }
+    }
+
+    fn await_internal_response(
+        &self,
+        message_name: &str,
+        args: Vec<OscType>,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        log::info!(
+            ">> Waiting for message with name {} and args {:?} ...",
+            message_name,
+            args
+        );
+
+        self.await_message(message_name, &|msg| args == msg.args, timeout)
+            .map(|_| ())
+    }
+
+    /*
+        Same as await_internal_response, but accepts any args and returns the received message.
+    */
+    fn await_internal_message(
+        &self,
+        message_name: &str,
+        timeout: Duration,
+    ) -> Result<OscMessage, String> {
+        log::info!(">> Waiting for message with name {} ...", message_name);
+
+        self.await_message(message_name, &|_| true, timeout)
+    }
+
+    /*
+        Await a reply message whose first arg is the given request id.
+    */
+    fn await_reply(
+        &self,
+        message_name: &str,
+        request_id: &str,
+        timeout: Duration,
+    ) -> Result<OscMessage, String> {
+        self.await_message(
+            message_name,
+            &|msg| msg.args.get(0) == Some(&OscType::String(request_id.to_string())),
+            timeout,
+        )
+    }
+}
+
+/*
+    Ports registered with /subscribe, e.g. by jdw-sc-ctl while it waits for a reply. Everything
+        sent to outgoing_port is sent to them as well, until they unsubscribe or their lifetime
+        runs out. Clones share the same ports.
+*/
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    ports: Arc<Mutex<Vec<(i32, Instant)>>>, // Port and expiry
+}
+
+impl Subscribers {
+    // Subscribing again renews the lifetime
+    pub fn add(&self, port: i32, lifetime: Duration) {
+        let mut ports = self.ports.lock().unwrap();
+        ports.retain(|(subscribed, _)| *subscribed != port);
+        ports.push((port, Instant::now() + lifetime));
+    }
+
+    pub fn remove(&self, port: i32) {
+        self.ports
+            .lock()
+            .unwrap()
+            .retain(|(subscribed, _)| *subscribed != port);
+    }
+
+    // Ports still subscribed, dropping the expired ones
+    pub fn ports(&self) -> Vec<i32> {
+        let mut ports = self.ports.lock().unwrap();
+        let now = Instant::now();
+        ports.retain(|(_, expiry)| *expiry > now);
+        ports.iter().map(|(port, _)| *port).collect()
+    }
+
+    // Subscribers may be gone before they expire, so failed sends are ignored
+    pub fn send(&self, socket: &UdpSocket, cfg: &Config, msg_buf: &[u8]) {
+        for port in self.ports() {
+            socket.send_to(msg_buf, cfg.addr(port)).ok();
+        }
+    }
+}
+
+// Bundle executing the packet delay_ms after receive_time, as sent to scsynth
+pub fn timed_bundle(msg: OscPacket, delay_ms: u64, receive_time: SystemTime) -> OscPacket {
+    let target_time = receive_time + Duration::from_millis(delay_ms);
+
+    OscPacket::Bundle(OscBundle {
+        timetag: OscTime::try_from(target_time).unwrap(),
+        content: vec![msg],
+    })
+}
+
+#[derive(Default)]
+struct Recording {
+    scsynth: Vec<OscPacket>,
+    sclang: Vec<OscMessage>,
+    out: Vec<OscMessage>,
+    incoming: VecDeque<OscMessage>,
+}
+
+/*
+    In-memory backend. Clones share the same recording, so a clone kept by a test can inspect
+        what the interpreter sent.
+    Awaits take the first queued incoming message that matches and fail immediately if there is none.
+*/
+#[derive(Clone)]
+pub struct RecordingBackend {
+    config: Arc<Config>,
+    sclang: bool,
+    recording: Arc<Mutex<Recording>>,
+    subscribers: Subscribers,
+}
+
+impl RecordingBackend {
+    pub fn new(config: Config) -> RecordingBackend {
+        RecordingBackend {
+            config: Arc::new(config),
+            sclang: true,
+            recording: Arc::new(Mutex::new(Recording::default())),
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    // Behave as when running without sclang (boot_mode = "scsynth")
+    pub fn without_sclang(mut self) -> RecordingBackend {
+        self.sclang = false;
+        self
+    }
+
+    // Queue a message as if sent by scsynth or sclang, for the next matching await or take_messages
+    pub fn push_incoming(&self, msg: OscMessage) {
+        self.recording.lock().unwrap().incoming.push_back(msg);
+    }
+
+    // Timetagged bundles sent to scsynth, in order
+    pub fn scsynth_packets(&self) -> Vec<OscPacket> {
+        self.recording.lock().unwrap().scsynth.clone()
+    }
+
+    // Messages sent to scsynth with their execution time, bundles unwrapped
+    pub fn scsynth_messages(&self) -> Vec<(OscTime, OscMessage)> {
+        self.scsynth_packets()
+            .into_iter()
+            .flat_map(|packet| match packet {
+                OscPacket::Bundle(OscBundle { timetag, content }) => content
+                    .into_iter()
+                    .filter_map(|content| match content {
+                        OscPacket::Message(msg) => Some((timetag, msg)),
+                        OscPacket::Bundle(_) => None,
+                    })
+                    .collect(),
+                OscPacket::Message(_) => vec![],
+            })
+            .collect()
+    }
+
+    pub fn sclang_messages(&self) -> Vec<OscMessage> {
+        self.recording.lock().unwrap().sclang.clone()
+    }
+
+    pub fn out_messages(&self) -> Vec<OscMessage> {
+        self.recording.lock().unwrap().out.clone()
+    }
+
+    // Forget everything sent so far
+    pub fn clear(&self) {
+        let mut recording = self.recording.lock().unwrap();
+        recording.scsynth.clear();
+        recording.sclang.clear();
+        recording.out.clear();
+    }
+}
+
+impl ScBackend for RecordingBackend {
+    fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    fn has_sclang(&self) -> bool {
+        self.sclang
+    }
+
+    fn send_to_scsynth_with_delay(&self, msg: OscPacket, delay_ms: u64, receive_time: SystemTime) {
+        let packet = timed_bundle(msg, delay_ms, receive_time);
+        self.recording.lock().unwrap().scsynth.push(packet);
+    }
+
+    fn send_to_sclang(&self, msg: OscMessage) {
+        self.recording.lock().unwrap().sclang.push(msg);
+    }
+
+    fn send_out(&self, msg: OscMessage) {
+        self.recording.lock().unwrap().out.push(msg);
+    }
+
+    fn subscribers(&self) -> &Subscribers {
+        &self.subscribers
+    }
+
+    fn await_message(
+        &self,
+        message_name: &str,
+        matches: &dyn Fn(&OscMessage) -> bool,
+        _timeout: Duration,
+    ) -> Result<OscMessage, String> {
+        let mut recording = self.recording.lock().unwrap();
+
+        let index = recording
+            .incoming
+            .iter()
+            .position(|msg| msg.addr == message_name && matches(msg))
+            .ok_or(format!(">> Timed out waiting for {}", message_name))?;
+
+        Ok(recording.incoming.remove(index).unwrap())
+    }
+
+    fn take_messages(&self) -> Vec<OscMessage> {
+        self.recording.lock().unwrap().incoming.drain(..).collect()
+    }
+}
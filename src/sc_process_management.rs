@@ -1,16 +1,21 @@
-use crate::config;
+use crate::config::Config;
+use crate::pidfile::{self, ManagedProcess};
+use crate::sc_backend::{self, ScBackend, Subscribers};
 use crate::scd_templating;
-use bigdecimal::{BigDecimal, ToPrimitive, Zero};
-use jdw_osc_lib::model::TimedOSCPacket;
-use log::{debug, info};
-use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use crate::sclang_output;
+use crate::status_monitor::ServerStatus;
+use log::{debug, info, warn};
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Write;
 use std::net::{SocketAddrV4, UdpSocket};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::{Duration, Instant, SystemTime};
-use std::{fs, thread};
+use std::thread;
 use subprocess::{Popen, PopenConfig, Redirection};
 
 pub struct SCInitData {
@@ -18,64 +23,46 @@ pub struct SCInitData {
     pub process: Popen,
 }
 
-pub fn init() -> Result<SCInitData, Box<dyn std::error::Error>> {
-    // TODO: General temp folder management should be its own little util
-    // TODO: ... and use home folder instead
-
-    let cfg = config::Config::get();
-
-    info!("Generating boot script");
-
-    let templated = scd_templating::create_boot_script()?;
-
-    info!("Writing boot script to ~/.config/jdw-sc/");
-
-    let config_dir = home::home_dir()
-        .expect("Cannot determine home directory")
-        .join(".config")
-        .join("jdw-sc");
-    if !config_dir.exists() {
-        fs::create_dir_all(&config_dir)?;
-    }
-    let script_path = config_dir.join("start_server.scd");
-    fs::write(&script_path, templated.as_bytes())?;
-
-    info!("Starting supercollider with generated boot script");
-
-    let process = Popen::create(
-        &[
-            cfg.sclang_binary.as_str(),
-            script_path.to_str().unwrap(),
-            "-u",
-            &cfg.sclang_in_port.to_string(),
-        ],
-        PopenConfig {
-            stdout: Redirection::Merge,
-            ..Default::default()
-        },
-    )?;
+/*
+    Launch the managed process - sclang with the boot script, or scsynth directly if sclang is
+        disabled in config - and set up the client for talking to it.
+    Messages sent out by the client are also passed to events, if given.
+*/
+pub fn init(
+    cfg: Arc<Config>,
+    events: Option<Sender<OscMessage>>,
+) -> Result<SCInitData, Box<dyn std::error::Error>> {
+    pidfile::cleanup_stale(&cfg);
+
+    let sclang_enabled = cfg.sclang_enabled();
+
+    let mut process = if sclang_enabled {
+        launch_sclang(&cfg)?
+    } else {
+        launch_scsynth(&cfg)?
+    };
 
     // Note: this port is targeted by start_server.scd.template
     // Note: Technically the second UDP in socket managed by the application,
     // the other being the public in-port used to send messages to jdw-sc
-    let recv_addr = match SocketAddrV4::from_str(&config::get_addr(cfg.server_out_port)) {
+    let recv_addr = match SocketAddrV4::from_str(&cfg.addr(cfg.server_out_port)) {
         Ok(addr) => addr,
         Err(_) => panic!("Error binding incoming osc address"),
     };
 
     let incoming_socket = UdpSocket::bind(recv_addr)?;
 
-    let scsynth_addr = match SocketAddrV4::from_str(&config::get_addr(cfg.server_in_port)) {
+    let scsynth_addr = match SocketAddrV4::from_str(&cfg.addr(cfg.server_in_port)) {
         Ok(addr) => addr,
         Err(_) => panic!("Error binding scsynth address"),
     };
 
-    let sclang_addr = match SocketAddrV4::from_str(&config::get_addr(cfg.sclang_in_port)) {
+    let sclang_addr = match SocketAddrV4::from_str(&cfg.addr(cfg.sclang_in_port)) {
         Ok(addr) => addr,
         Err(_) => panic!("Error binding sclang address"),
     };
 
-    let out_addr = match SocketAddrV4::from_str(&config::get_addr(cfg.outgoing_port)) {
+    let out_addr = match SocketAddrV4::from_str(&cfg.addr(cfg.outgoing_port)) {
         Ok(addr) => addr,
         Err(_) => panic!("Error binding outgoing traffic address"),
     };
@@ -88,65 +75,295 @@ pub fn init() -> Result<SCInitData, Box<dyn std::error::Error>> {
         )))
         .unwrap();
 
+    let (received_sender, received) = mpsc::channel();
+    let incoming = Arc::new(Mutex::new(Incoming {
+        received,
+        unclaimed: VecDeque::new(),
+    }));
+    spawn_receiver(
+        incoming_socket.try_clone()?,
+        received_sender,
+        Arc::downgrade(&incoming),
+    );
+
     let client = SCClient {
         osc_socket: incoming_socket,
+        incoming,
         sclang_out_addr: sclang_addr,
         scsynth_out_addr: scsynth_addr,
         application_out_addr: out_addr,
+        last_sclang_command: Arc::new(Mutex::new(None)),
+        sclang_available: sclang_enabled,
+        events,
+        subscribers: Subscribers::default(),
+        config: cfg,
     };
 
+    if let Some(output) = process.stdout.take() {
+        let source = if sclang_enabled { "sclang" } else { "scsynth" };
+        sclang_output::spawn_reader(output, client.try_clone()?, source);
+    }
+
     Ok(SCInitData { client, process })
 }
 
+fn launch_sclang(cfg: &Config) -> Result<Popen, Box<dyn std::error::Error>> {
+    info!("Generating boot script");
+
+    let script_path = scd_templating::write_boot_script(cfg)?;
+
+    pidfile::check_ports(
+        cfg,
+        &[
+            ("server_in_port", cfg.server_in_port),
+            ("server_out_port", cfg.server_out_port),
+            ("sclang_in_port", cfg.sclang_in_port),
+        ],
+    )?;
+
+    info!("Starting supercollider with generated boot script");
+
+    let process = Popen::create(
+        &[
+            cfg.sclang_binary.as_str(),
+            script_path.to_str().unwrap(),
+            "-u",
+            &cfg.sclang_in_port.to_string(),
+        ],
+        PopenConfig {
+            stdout: Redirection::Pipe,
+            stderr: Redirection::Merge,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(pid) = process.pid() {
+        pidfile::write(cfg, &[ManagedProcess::new("sclang", pid)])?;
+    }
+
+    Ok(process)
+}
+
+/*
+    Start scsynth without sclang. Readiness is detected with /status polling (see await_scsynth_ready)
+        and synthdefs must come precompiled, from the synthdef cache or .scsyndef files.
+*/
+fn launch_scsynth(cfg: &Config) -> Result<Popen, Box<dyn std::error::Error>> {
+    pidfile::check_ports(
+        cfg,
+        &[
+            ("server_in_port", cfg.server_in_port),
+            ("server_out_port", cfg.server_out_port),
+        ],
+    )?;
+
+    info!("Starting {} without sclang", cfg.server_binary());
+
+    let args = scsynth_args(cfg);
+    let mut command = vec![cfg.server_binary()];
+    command.extend(args.iter().map(|arg| arg.as_str()));
+
+    let process = Popen::create(
+        &command,
+        PopenConfig {
+            stdout: Redirection::Pipe,
+            stderr: Redirection::Merge,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(pid) = process.pid() {
+        pidfile::write(cfg, &[ManagedProcess::new("scsynth", pid)])?;
+    }
+
+    Ok(process)
+}
+
+// Command line arguments for scsynth, equivalent to the ServerOptions set in the boot script
+pub fn scsynth_args(cfg: &Config) -> Vec<String> {
+    let mut args: Vec<String> = [
+        ("-u", cfg.server_in_port),
+        ("-m", cfg.supercollider_memory_bytes),
+        ("-o", cfg.num_output_bus_channels),
+        ("-i", cfg.num_input_bus_channels),
+        ("-z", cfg.block_size),
+        ("-b", cfg.num_buffers),
+        ("-n", cfg.max_nodes),
+        ("-w", cfg.num_wire_bufs),
+    ]
+    .iter()
+    .flat_map(|(flag, value)| vec![flag.to_string(), value.to_string()])
+    .collect();
+
+    args.extend(["-B".to_string(), cfg.application_ip.clone()]);
+
+    if cfg.sample_rate > 0 {
+        args.extend(["-S".to_string(), cfg.sample_rate.to_string()]);
+    }
+
+    if cfg.hardware_buffer_size > 0 {
+        args.extend(["-Z".to_string(), cfg.hardware_buffer_size.to_string()]);
+    }
+
+    /*
+        -H takes the input device, optionally followed by a different output device.
+        If only one of them is set, it is used for both, since -H cannot leave the input at the default.
+    */
+    if !cfg.in_device.is_empty() || !cfg.out_device.is_empty() {
+        let (in_device, out_device) = match (cfg.in_device.is_empty(), cfg.out_device.is_empty()) {
+            (true, _) => (&cfg.out_device, &cfg.out_device),
+            (_, true) => (&cfg.in_device, &cfg.in_device),
+            _ => (&cfg.in_device, &cfg.out_device),
+        };
+
+        args.push("-H".to_string());
+        args.push(in_device.clone());
+        if out_device != in_device {
+            args.push(out_device.clone());
+        }
+    }
+
+    args
+}
+
+/*
+    Messages received from the managed processes, read by the thread of spawn_receiver.
+    Messages read while awaiting another one are kept as unclaimed, instead of being dropped.
+*/
+struct Incoming {
+    received: Receiver<OscMessage>,
+    unclaimed: VecDeque<OscMessage>,
+}
+
+// Read messages sent to server_out_port until no client is left to take them
+fn spawn_receiver(socket: UdpSocket, received: Sender<OscMessage>, clients: Weak<Mutex<Incoming>>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; rosc::decoder::MTU];
+
+        // The read timeout (sc_server_incoming_read_timeout) lets the check come around
+        while clients.strong_count() > 0 {
+            let size = match socket.recv_from(&mut buf) {
+                Ok((size, _)) => size,
+                Err(_) => continue,
+            };
+
+            match rosc::decoder::decode_udp(&buf[..size]) {
+                Ok((_, OscPacket::Message(msg))) => {
+                    if received.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Ok((_, OscPacket::Bundle(_))) => {}
+                Err(e) => warn!("Failed to decode message from SuperCollider: {}", e),
+            }
+        }
+    });
+}
+
 pub struct SCClient {
     osc_socket: UdpSocket,
+    incoming: Arc<Mutex<Incoming>>, // Shared by clones
     sclang_out_addr: SocketAddrV4,
     scsynth_out_addr: SocketAddrV4,
     application_out_addr: SocketAddrV4,
+    last_sclang_command: Arc<Mutex<Option<String>>>, // Short description, used to attribute sclang errors
+    sclang_available: bool, // False when scsynth runs without sclang
+    events: Option<Sender<OscMessage>>, // Receives a copy of everything sent out, when embedded
+    subscribers: Subscribers,
+    config: Arc<Config>,
 }
 
 impl SCClient {
+    // Clone sharing the same underlying socket, for use in other threads
+    pub fn try_clone(&self) -> std::io::Result<SCClient> {
+        Ok(SCClient {
+            osc_socket: self.osc_socket.try_clone()?,
+            incoming: self.incoming.clone(),
+            sclang_out_addr: self.sclang_out_addr,
+            scsynth_out_addr: self.scsynth_out_addr,
+            application_out_addr: self.application_out_addr,
+            last_sclang_command: self.last_sclang_command.clone(),
+            sclang_available: self.sclang_available,
+            events: self.events.clone(),
+            subscribers: self.subscribers.clone(),
+            config: self.config.clone(),
+        })
+    }
+
+    pub fn last_sclang_command(&self) -> Option<String> {
+        self.last_sclang_command.lock().unwrap().clone()
+    }
+
     /*
-       Note on delay: supercollider execution time can vary by a few milliseconds.
-       By providing a delay, we remove this variation via specifying the exact time of execution.
-       This is important in precise sequencing but unimportant for direct human input.
+        Poll scsynth with /status until it replies, for use when booting without sclang.
     */
-    pub fn send_timed_packets_to_scsynth(
-        &self,
-        delay_ms: u64,
-        msgs: Vec<TimedOSCPacket>,
-        receive_time: SystemTime,
-    ) {
-        for msg in msgs {
-            if msg.time == BigDecimal::zero() {
-                self.send_to_scsynth_with_delay(msg.packet, delay_ms, receive_time);
-            } else {
-                // Tell supercollider to execute the message after a delay
-                let time_in_ms = BigDecimal::from_str("1000.00").unwrap() * msg.time.clone();
-                let time_integer = time_in_ms.to_u64().unwrap();
-                self.send_to_scsynth_with_delay(msg.packet, delay_ms + time_integer, receive_time);
+    pub fn await_scsynth_ready(&self, timeout: Duration) -> Result<(), String> {
+        let start_time = Instant::now();
+
+        while start_time.elapsed() < timeout {
+            if self.query_status(Duration::from_millis(500)).is_ok() {
+                return Ok(());
             }
+
+            // Refused immediately until scsynth has bound its port
+            thread::sleep(Duration::from_millis(self.config.poll_sleep_ms));
         }
+
+        Err(">> Timed out waiting for scsynth to reply to /status".to_string())
     }
 
-    pub fn send_to_scsynth_with_delay(
-        &self,
-        msg: OscPacket,
-        delay_ms: u64,
-        receive_time: SystemTime,
-    ) {
-        // TODO: Trying out some latency adjustments to fix desync issues
-        // This is not the optimal way - these operations are highly reliant on context
+    /*
+        Send /status to scsynth and await its /status.reply.
+        Uses a separate socket, so that it can run alongside awaits on the shared one (e.g. from another thread).
+    */
+    pub fn query_status(&self, timeout: Duration) -> Result<ServerStatus, String> {
+        let socket = UdpSocket::bind(self.config.addr(0)).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
 
-        let target_time = receive_time + Duration::from_millis(delay_ms);
+        let msg_buf = encoder::encode(&OscPacket::Message(OscMessage {
+            addr: "/status".to_string(),
+            args: vec![],
+        }))
+        .map_err(|e| e.to_string())?;
 
-        use std::convert::TryFrom;
-        let bundle = OscBundle {
-            timetag: OscTime::try_from(target_time).unwrap(),
-            content: vec![msg],
-        };
+        socket
+            .send_to(&msg_buf, self.scsynth_out_addr)
+            .map_err(|e| e.to_string())?;
+
+        let mut buf = [0u8; rosc::decoder::MTU];
+        let start_time = Instant::now();
+
+        while start_time.elapsed() < timeout {
+            let size = match socket.recv_from(&mut buf) {
+                Ok((size, _)) => size,
+                Err(e) => return Err(format!("No /status.reply from scsynth: {}", e)),
+            };
 
-        let packet = OscPacket::Bundle(bundle);
+            if let Ok((_, OscPacket::Message(msg))) = rosc::decoder::decode_udp(&buf[..size]) {
+                if msg.addr == "/status.reply" {
+                    return ServerStatus::from_reply(&msg);
+                }
+            }
+        }
+
+        Err("No /status.reply from scsynth".to_string())
+    }
+}
+
+impl ScBackend for SCClient {
+    fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    fn has_sclang(&self) -> bool {
+        self.sclang_available
+    }
+
+    fn send_to_scsynth_with_delay(&self, msg: OscPacket, delay_ms: u64, receive_time: SystemTime) {
+        // TODO: Trying out some latency adjustments to fix desync issues
+        // This is not the optimal way - these operations are highly reliant on context
+
+        let packet = sc_backend::timed_bundle(msg, delay_ms, receive_time);
 
         // NOTE: Used to just send &msg here
         let msg_buf = encoder::encode(&packet).unwrap();
@@ -156,14 +373,41 @@ impl SCClient {
             .unwrap();
     }
 
-    pub fn send_out(&self, msg: OscMessage) {
+    fn send_out(&self, msg: OscMessage) {
+        if let Some(events) = &self.events {
+            // The receiver being gone only means that nobody listens anymore
+            events.send(msg.clone()).ok();
+        }
+
         let msg_buf = encoder::encode(&OscPacket::Message(msg)).unwrap();
         self.osc_socket
             .send_to(&msg_buf, self.application_out_addr)
             .unwrap();
+        self.subscribers
+            .send(&self.osc_socket, &self.config, &msg_buf);
+    }
+
+    fn subscribers(&self) -> &Subscribers {
+        &self.subscribers
     }
 
-    pub fn send_to_sclang(&self, msg: OscMessage) {
+    /*
+        Messages for sclang are dropped when it is not running, and reported to clients instead.
+        MSG OUT: /sclang_unavailable, <command>
+    */
+    fn send_to_sclang(&self, msg: OscMessage) {
+        if !self.sclang_available {
+            let command = describe_command(&msg);
+            warn!("sclang is not running, dropped command: {}", command);
+            self.send_out(OscMessage {
+                addr: "/sclang_unavailable".to_string(),
+                args: vec![OscType::String(command)],
+            });
+            return;
+        }
+
+        *self.last_sclang_command.lock().unwrap() = Some(describe_command(&msg));
+
         let msg_buf = encoder::encode(&OscPacket::Message(msg)).unwrap();
 
         self.osc_socket
@@ -173,57 +417,59 @@ impl SCClient {
 
     /*
         Await an OSC message sent from the out_socket used by managed processes.
+        Other messages received meanwhile are kept for later awaits and take_messages.
     */
-    pub fn await_internal_response(
+    fn await_message(
         &self,
         message_name: &str,
-        args: Vec<OscType>,
+        matches: &dyn Fn(&OscMessage) -> bool,
         timeout: Duration,
-    ) -> Result<(), String> {
-        let start_time = Instant::now();
-        let mut buf = [0u8; rosc::decoder::MTU];
+    ) -> Result<OscMessage, String> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let is_awaited = |msg: &OscMessage| msg.addr == message_name && matches(msg);
 
-        info!(
-            ">> Waiting for message with name {} and args {:?} ...",
-            message_name, args
-        );
-
-        // Temporarily set read timeout so recv_from unblocks periodically.
-        // Without this, recv_from blocks forever when no messages arrive.
-        self.osc_socket.set_read_timeout(Some(Duration::from_secs(1))).ok();
-
-        let result = loop {
-            match self.osc_socket.recv_from(&mut buf) {
-                Ok((size, _addr)) => {
-                    let (_, packet) = rosc::decoder::decode_udp(&buf[..size]).unwrap();
-                    match packet {
-                        OscPacket::Message(msg) => {
-                            if msg.addr == message_name && args == msg.args {
-                                info!(">> Awaited message received! Continuing ...");
-                                break Ok(());
-                            } else {
-                                debug!("Received message not the waited for one, continuing wait...");
-                            }
-                        }
-                        _ => {}
-                    }
+        if let Some(index) = incoming.unclaimed.iter().position(is_awaited) {
+            return Ok(incoming.unclaimed.remove(index).unwrap());
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match incoming.received.recv_timeout(remaining) {
+                Ok(msg) if is_awaited(&msg) => {
+                    info!(">> Awaited message received! Continuing ...");
+                    return Ok(msg);
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
-                    // Read timeout — check elapsed time
+                Ok(msg) => {
+                    debug!("Received message not the waited for one, continuing wait...");
+                    incoming.unclaimed.push_back(msg);
                 }
-                Err(e) => {
-                    break Err(format!(">> Error receiving from socket: {}", e));
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(">> Stopped receiving from SuperCollider".to_string())
                 }
             }
+        }
 
-            if start_time.elapsed() > timeout {
-                break Err(format!(">> Timed out waiting for {}", message_name));
-            }
-            thread::sleep(Duration::from_millis(config::Config::get().poll_sleep_ms));
-        };
+        Err(format!(">> Timed out waiting for {}", message_name))
+    }
 
-        // Restore blocking mode for the main receive loop
-        self.osc_socket.set_read_timeout(None).ok();
-        result
+    fn take_messages(&self) -> Vec<OscMessage> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let mut messages: Vec<OscMessage> = incoming.unclaimed.drain(..).collect();
+        messages.extend(incoming.received.try_iter());
+        messages
     }
 }
+
+// E.g. '/read_scd SynthDef.new("example", ...'
+fn describe_command(msg: &OscMessage) -> String {
+    let first_arg = msg
+        .args
+        .get(0)
+        .and_then(|arg| arg.clone().string())
+        .map(|arg| arg.lines().next().unwrap_or("").chars().take(60).collect::<String>())
+        .unwrap_or_default();
+
+    format!("{} {}", msg.addr, first_arg).trim().to_string()
+}
@@ -1,4 +1,7 @@
-use crate::config;
+use crate::config::Config;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 // Embed all SCD assets at compile time so the library works regardless of
 // the working directory at runtime.
@@ -16,13 +19,12 @@ pub fn read_scd_file(template_name: &str) -> String {
 }
 
 pub fn create_nrt_script(
+    cfg: &Config,
     bpm: f32,
     file_name: &str,
     end_time: f32,
     message_scd_rows: Vec<String>,
 ) -> String {
-    let cfg = config::Config::get();
-
     let mut text = read_scd_file("nrt_record.scd.template");
 
     let score_row = message_scd_rows.join(",\n");
@@ -32,25 +34,131 @@ pub fn create_nrt_script(
     text = text.replace("{:score_rows}", &score_row);
     text = text.replace("{:end_time}", &format!("{}", end_time));
     text = text.replace("{:out_socket_name}", &cfg.server_osc_socket_name);
+    text = text.replace("{:server_options}", &server_option_lines(cfg, "opts", true));
+    text = text.replace("{:sample_format}", &cfg.nrt_sample_format);
+    text = text.replace("{:server_backend}", &server_backend_line(cfg));
 
     text
 }
 
-pub fn create_boot_script() -> Result<String, String> {
-    let cfg = config::Config::get();
+/*
+    ServerOptions assignments for the configured server options, e.g. "s.options.blockSize = 64;".
+    Options left at 0 or empty keep the SuperCollider defaults. Device settings are skipped for NRT,
+        which also always uses a fixed sample rate.
+*/
+pub fn server_option_lines(cfg: &Config, options_owner: &str, nrt: bool) -> String {
+    let sample_rate = if nrt {
+        cfg.nrt_sample_rate()
+    } else {
+        cfg.sample_rate
+    };
+
+    let mut options = vec![
+        ("memSize", cfg.supercollider_memory_bytes.to_string()),
+        ("numOutputBusChannels", cfg.num_output_bus_channels.to_string()),
+        ("numInputBusChannels", cfg.num_input_bus_channels.to_string()),
+        ("blockSize", cfg.block_size.to_string()),
+        ("numBuffers", cfg.num_buffers.to_string()),
+        ("maxNodes", cfg.max_nodes.to_string()),
+        ("numWireBufs", cfg.num_wire_bufs.to_string()),
+    ];
+
+    if sample_rate > 0 {
+        options.push(("sampleRate", sample_rate.to_string()));
+    }
+
+    if !nrt {
+        if cfg.hardware_buffer_size > 0 {
+            options.push(("hardwareBufferSize", cfg.hardware_buffer_size.to_string()));
+        }
+        if !cfg.in_device.is_empty() {
+            options.push(("inDevice", string_literal(&cfg.in_device)));
+        }
+        if !cfg.out_device.is_empty() {
+            options.push(("outDevice", string_literal(&cfg.out_device)));
+        }
+    }
+
+    options
+        .iter()
+        .map(|(option, value)| format!("{}.{} = {};", options_owner, option, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Selects the server program used for booting and NRT rendering
+fn server_backend_line(cfg: &Config) -> String {
+    if cfg.supernova() {
+        "Server.supernova;".to_string()
+    } else {
+        "Server.scsynth;".to_string()
+    }
+}
 
+pub fn create_boot_script(cfg: &Config) -> Result<String, String> {
     let mut text = read_scd_file("start_server.scd.template");
+    text = text.replace("{:server_backend}", &server_backend_line(cfg));
     text = text.replace("{:server_out_port}", &cfg.server_out_port.to_string());
     text = text.replace("{:server_in_port}", &cfg.server_in_port.to_string());
     text = text.replace("{:application_ip}", &cfg.application_ip);
     text = text.replace("{:server_name}", &cfg.server_name);
     text = text.replace("{:out_socket_name}", &cfg.server_osc_socket_name);
-    text = text.replace("{:memory_bytes}", &cfg.supercollider_memory_bytes.to_string());
+    text = text.replace(
+        "{:server_options}",
+        &server_option_lines(cfg, &format!("{}.options", cfg.server_name), false),
+    );
 
     Ok(text)
 }
 
+// Write the boot script to state_dir and return its path
+pub fn write_boot_script(cfg: &Config) -> Result<PathBuf, String> {
+    let dir = cfg.state_path()?;
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create state dir {}: {}", dir.display(), e))?;
+
+    let path = dir.join("start_server.scd");
+    fs::write(&path, create_boot_script(cfg)?)
+        .map_err(|e| format!("Failed to write boot script {}: {}", path.display(), e))?;
+
+    Ok(path)
+}
+
 // Take synthdef code and wrap it in an nrt score line
 pub fn nrt_wrap_synthdef(def_code: &str) -> String {
     format!("[0.0, ['/d_recv', {}]]", def_code)
 }
+
+// Interpret a SynthDef and write its compiled bytes to the given path
+pub fn cache_synthdef_scd(def_code: &str, path: &Path) -> String {
+    format!(
+        "File.use({}, \"wb\", {{ |file| file.write(({}).asBytes) }});",
+        string_literal(&path.to_string_lossy()),
+        def_code
+    )
+}
+
+// Value as a double quoted sclang string, with quotes and backslashes escaped
+pub fn string_literal(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Compiled synthdef bytes as an sclang literal, e.g. Int8Array[83, 67, 103, 102, ...]
+pub fn int8_array_literal(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|byte| (*byte as i8).to_string()).collect();
+    format!("Int8Array[{}]", values.join(", "))
+}
+
+// Name of the SynthDef declared in a definition, e.g. SynthDef.new("example", ...) or SynthDef(\example, ...)
+pub fn parse_synthdef_name(def_code: &str) -> Option<String> {
+    let regex = Regex::new(r#"SynthDef(?:\.new)?\s*\(\s*(?:\\(\w+)|"([^"]+)"|'([^']+)')"#).unwrap();
+
+    regex.captures(def_code).and_then(|captures| {
+        captures
+            .iter()
+            .skip(1)
+            .flatten()
+            .next()
+            .map(|name| name.as_str().to_string())
+    })
+}
@@ -0,0 +1,221 @@
+/*
+   Reader for the merged stdout/stderr of the sclang process (or scsynth, when running without sclang).
+
+   Every line is forwarded to the log at a level matching its content. Error dumps
+       (ERROR: ... and FAILURE IN SERVER) are collected into blocks and published as
+       /sclang_error events, tied to the last command that was sent to sclang.
+
+   MSG OUT: /sclang_error, <kind>, <command>, <message>, <details>
+*/
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::thread;
+
+use log::{debug, error, info, warn};
+use rosc::{OscMessage, OscType};
+
+use crate::sc_backend::ScBackend;
+use crate::sc_process_management::SCClient;
+
+// Error dumps are cut off after this many lines to keep events reasonably sized
+const MAX_BLOCK_LINES: usize = 64;
+
+// Trailer printed after a parse error dump, which has already been published by then
+const PARSE_FAILED_TRAILER: &str = "ERROR: Command line parse failed";
+
+// Non-indented lines that are still part of an ongoing error dump
+const DUMP_HEADERS: [&str; 5] = [
+    "RECEIVER:",
+    "ARGS:",
+    "CALL STACK:",
+    "PROTECTED CALL STACK:",
+    "PATH:",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineKind {
+    Error,
+    ServerFailure,
+    Warning,
+    Notice,
+    Plain,
+}
+
+#[derive(Debug, Clone)]
+pub struct SclangErrorEvent {
+    pub kind: String,
+    pub message: String,
+    pub details: Vec<String>,
+    pub command: Option<String>, // Last command sent to sclang before the error appeared
+}
+
+impl SclangErrorEvent {
+    pub fn as_osc(&self) -> OscMessage {
+        OscMessage {
+            addr: "/sclang_error".to_string(),
+            args: vec![
+                OscType::String(self.kind.clone()),
+                OscType::String(self.command.clone().unwrap_or_default()),
+                OscType::String(self.message.clone()),
+                OscType::String(self.details.join("\n")),
+            ],
+        }
+    }
+}
+
+pub fn classify(line: &str) -> LineKind {
+    let trimmed = line.trim_start();
+
+    if trimmed.starts_with("ERROR:") || trimmed.contains("exception in") {
+        LineKind::Error
+    } else if trimmed.starts_with("FAILURE IN SERVER") {
+        LineKind::ServerFailure
+    } else if trimmed.starts_with("WARNING:") || trimmed.contains("exited with exit code") {
+        LineKind::Warning
+    } else if trimmed.contains("server ready")
+        || trimmed.starts_with("Booting server")
+        || trimmed.starts_with("Client Port:")
+        || trimmed.starts_with("compile done")
+    {
+        LineKind::Notice
+    } else {
+        LineKind::Plain
+    }
+}
+
+fn is_block_end(line: &str) -> bool {
+    line.starts_with("^^") || line.starts_with("-----")
+}
+
+fn continues_block(line: &str) -> bool {
+    line.trim().is_empty()
+        || line.starts_with(char::is_whitespace)
+        || DUMP_HEADERS.iter().any(|header| line.starts_with(header))
+}
+
+/*
+    Collects error dump lines until the dump is complete.
+*/
+#[derive(Default)]
+pub struct BlockCollector {
+    current: Option<SclangErrorEvent>,
+}
+
+impl BlockCollector {
+    pub fn new() -> BlockCollector {
+        BlockCollector { current: None }
+    }
+
+    // Feed a line, returning any completed events
+    pub fn feed(&mut self, line: &str, command: Option<String>) -> Vec<SclangErrorEvent> {
+        let mut done = vec![];
+
+        if let Some(block) = self.current.as_mut() {
+            if is_block_end(line) {
+                block.details.push(line.to_string());
+                done.extend(self.current.take());
+                return done;
+            } else if continues_block(line) && block.details.len() < MAX_BLOCK_LINES {
+                block.details.push(line.to_string());
+                return done;
+            } else {
+                done.extend(self.current.take());
+            }
+        }
+
+        match classify(line) {
+            LineKind::Error if line.trim() == PARSE_FAILED_TRAILER => {}
+            LineKind::Error => {
+                self.current = Some(SclangErrorEvent {
+                    kind: "ERROR".to_string(),
+                    message: line.trim().to_string(),
+                    details: vec![],
+                    command,
+                });
+            }
+            LineKind::ServerFailure => {
+                // E.g. "FAILURE IN SERVER /s_new SynthDef not found" - the failed command is part of the line
+                let failed_command = line
+                    .trim()
+                    .trim_start_matches("FAILURE IN SERVER")
+                    .split_whitespace()
+                    .next()
+                    .map(|cmd| cmd.to_string());
+
+                done.push(SclangErrorEvent {
+                    kind: "FAILURE IN SERVER".to_string(),
+                    message: line.trim().to_string(),
+                    details: vec![],
+                    command: failed_command.or(command),
+                });
+            }
+            _ => {}
+        }
+
+        done
+    }
+
+    // The dump in progress, once the output has ended
+    pub fn flush(&mut self) -> Option<SclangErrorEvent> {
+        self.current.take()
+    }
+}
+
+fn log_line(source: &str, line: &str, in_block: bool) {
+    if in_block {
+        error!("[{}] {}", source, line);
+        return;
+    }
+
+    match classify(line) {
+        LineKind::Error | LineKind::ServerFailure => error!("[{}] {}", source, line),
+        LineKind::Warning => warn!("[{}] {}", source, line),
+        LineKind::Notice => info!("[{}] {}", source, line),
+        LineKind::Plain => debug!("[{}] {}", source, line),
+    }
+}
+
+fn publish(source: &str, client: &SCClient, event: SclangErrorEvent) {
+    error!(
+        "{} reported {} (command: {}): {}",
+        source,
+        event.kind,
+        event.command.clone().unwrap_or("unknown".to_string()),
+        event.message
+    );
+    client.send_out(event.as_osc());
+}
+
+/*
+    Start a thread reading process output until the pipe closes.
+    Continuous reading also keeps the process from blocking on a full pipe.
+*/
+pub fn spawn_reader(output: File, client: SCClient, source: &'static str) {
+    thread::spawn(move || {
+        let reader = BufReader::new(output);
+        let mut collector = BlockCollector::new();
+
+        for line in reader.split(b'\n') {
+            let line = match line {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).trim_end().to_string(),
+                Err(e) => {
+                    warn!("Failed to read {} output: {}", source, e);
+                    break;
+                }
+            };
+
+            let events = collector.feed(&line, client.last_sclang_command());
+            log_line(source, &line, collector.current.is_some());
+
+            for event in events {
+                publish(source, &client, event);
+            }
+        }
+
+        if let Some(event) = collector.flush() {
+            publish(source, &client, event);
+        }
+
+        info!("{} output closed", source);
+    });
+}
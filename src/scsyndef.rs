@@ -0,0 +1,329 @@
+/*
+   Compiled synthdef files (SCgf), as written by SynthDef.asBytes or synthdef_builder.
+   Versions 1 and 2 are decoded; encoding always produces version 2 (SynthDef2).
+   See "Synth Definition File Format" in the SuperCollider docs.
+*/
+use std::convert::TryFrom;
+
+use crate::synthdef_registry::SynthDefControl;
+
+const SYNTHDEF_VERSION: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledSynthDef {
+    pub name: String,
+    pub controls: Vec<SynthDefControl>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputSpec {
+    Constant(usize),    // Index into the constants
+    UGen(usize, usize), // UGen index, output index
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UGenSpec {
+    pub name: String,
+    pub rate: i8,
+    pub inputs: Vec<InputSpec>,
+    pub outputs: Vec<i8>, // Rate of each output
+    pub special_index: i16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub params: Vec<f32>,
+}
+
+// A single complete synth definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynthDefData {
+    pub name: String,
+    pub constants: Vec<f32>,
+    pub params: Vec<f32>,                  // Initial parameter values
+    pub param_names: Vec<(String, usize)>, // Name and index of the first param of each control
+    pub ugens: Vec<UGenSpec>,
+    pub variants: Vec<Variant>,
+}
+
+impl SynthDefData {
+    // Named controls in param order; array controls use their first value as default
+    pub fn controls(&self) -> Vec<SynthDefControl> {
+        let mut named_params = self.param_names.clone();
+        named_params.sort_by_key(|(_, index)| *index);
+
+        named_params
+            .into_iter()
+            .map(|(name, index)| SynthDefControl {
+                name,
+                default: self.params.get(index).cloned().unwrap_or(0.0),
+            })
+            .collect()
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(format!(
+                "Synthdef data ended unexpectedly at byte {}",
+                self.pos
+            ));
+        }
+
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn i8(&mut self) -> Result<i8, String> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, String> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        let bytes = self.take(4)?;
+        Ok(f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn pstring(&mut self) -> Result<String, String> {
+        let len = self.take(1)?[0] as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    // Counts and indices are int16 in version 1 and int32 in version 2
+    fn varint(&mut self, version: i32) -> Result<i32, String> {
+        if version == 1 {
+            Ok(self.i16()? as i32)
+        } else {
+            self.i32()
+        }
+    }
+
+    fn count(&mut self, version: i32) -> Result<usize, String> {
+        let count = self.varint(version)?;
+        usize::try_from(count).map_err(|_| format!("Invalid count {} in synthdef data", count))
+    }
+
+    fn f32s(&mut self, count: usize) -> Result<Vec<f32>, String> {
+        (0..count).map(|_| self.f32()).collect()
+    }
+}
+
+// Name and controls of the first definition in a file
+pub fn decode(bytes: &[u8]) -> Result<CompiledSynthDef, String> {
+    let definition = decode_all(bytes)?
+        .into_iter()
+        .next()
+        .ok_or("Synthdef file contains no definitions".to_string())?;
+
+    Ok(CompiledSynthDef {
+        name: definition.name.clone(),
+        controls: definition.controls(),
+    })
+}
+
+pub fn decode_all(bytes: &[u8]) -> Result<Vec<SynthDefData>, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(4)? != b"SCgf" {
+        return Err("Not a compiled synthdef (missing SCgf header)".to_string());
+    }
+
+    let version = reader.i32()?;
+    if version != 1 && version != 2 {
+        return Err(format!("Unsupported synthdef file version {}", version));
+    }
+
+    let definition_count = reader.i16()?;
+    (0..definition_count)
+        .map(|_| decode_definition(&mut reader, version))
+        .collect()
+}
+
+fn decode_definition(reader: &mut Reader, version: i32) -> Result<SynthDefData, String> {
+    let name = reader.pstring()?;
+
+    let constant_count = reader.count(version)?;
+    let constants = reader.f32s(constant_count)?;
+
+    let param_count = reader.count(version)?;
+    let params = reader.f32s(param_count)?;
+
+    let name_count = reader.count(version)?;
+    let mut param_names = vec![];
+    for _ in 0..name_count {
+        let param_name = reader.pstring()?;
+        param_names.push((param_name, reader.count(version)?));
+    }
+
+    let ugen_count = reader.count(version)?;
+    let mut ugens = vec![];
+    for _ in 0..ugen_count {
+        let ugen_name = reader.pstring()?;
+        let rate = reader.i8()?;
+        let input_count = reader.count(version)?;
+        let output_count = reader.count(version)?;
+        let special_index = reader.i16()?;
+
+        let mut inputs = vec![];
+        for _ in 0..input_count {
+            let source = reader.varint(version)?;
+            let index = reader.count(version)?;
+
+            inputs.push(if source == -1 {
+                InputSpec::Constant(index)
+            } else {
+                let ugen_index = usize::try_from(source)
+                    .map_err(|_| format!("Invalid input source {} in synthdef data", source))?;
+                InputSpec::UGen(ugen_index, index)
+            });
+        }
+
+        let outputs = (0..output_count)
+            .map(|_| reader.i8())
+            .collect::<Result<Vec<i8>, String>>()?;
+
+        ugens.push(UGenSpec {
+            name: ugen_name,
+            rate,
+            inputs,
+            outputs,
+            special_index,
+        });
+    }
+
+    let variant_count = reader.i16()?;
+    let mut variants = vec![];
+    for _ in 0..variant_count {
+        let variant_name = reader.pstring()?;
+        variants.push(Variant {
+            name: variant_name,
+            params: reader.f32s(param_count)?,
+        });
+    }
+
+    Ok(SynthDefData {
+        name,
+        constants,
+        params,
+        param_names,
+        ugens,
+        variants,
+    })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn i8(&mut self, value: i8) {
+        self.bytes.push(value as u8);
+    }
+
+    fn i16(&mut self, value: i16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn count(&mut self, value: usize) {
+        self.i32(value as i32);
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Names longer than 255 bytes are truncated, as the length is a single byte
+    fn pstring(&mut self, value: &str) {
+        let bytes = &value.as_bytes()[..value.len().min(255)];
+        self.bytes.push(bytes.len() as u8);
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+// Encode definitions into a SynthDef2 file, e.g. for /d_recv
+pub fn encode(definitions: &[SynthDefData]) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: b"SCgf".to_vec(),
+    };
+
+    writer.i32(SYNTHDEF_VERSION);
+    writer.i16(definitions.len() as i16);
+
+    for definition in definitions {
+        writer.pstring(&definition.name);
+
+        writer.count(definition.constants.len());
+        for constant in &definition.constants {
+            writer.f32(*constant);
+        }
+
+        writer.count(definition.params.len());
+        for param in &definition.params {
+            writer.f32(*param);
+        }
+
+        writer.count(definition.param_names.len());
+        for (name, index) in &definition.param_names {
+            writer.pstring(name);
+            writer.count(*index);
+        }
+
+        writer.count(definition.ugens.len());
+        for ugen in &definition.ugens {
+            writer.pstring(&ugen.name);
+            writer.i8(ugen.rate);
+            writer.count(ugen.inputs.len());
+            writer.count(ugen.outputs.len());
+            writer.i16(ugen.special_index);
+
+            for input in &ugen.inputs {
+                match input {
+                    InputSpec::Constant(index) => {
+                        writer.i32(-1);
+                        writer.count(*index);
+                    }
+                    InputSpec::UGen(ugen_index, output) => {
+                        writer.count(*ugen_index);
+                        writer.count(*output);
+                    }
+                }
+            }
+
+            for output in &ugen.outputs {
+                writer.i8(*output);
+            }
+        }
+
+        writer.i16(definition.variants.len() as i16);
+        for variant in &definition.variants {
+            writer.pstring(&variant.name);
+            for param in &variant.params {
+                writer.f32(*param);
+            }
+        }
+    }
+
+    writer.bytes
+}
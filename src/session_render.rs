@@ -0,0 +1,212 @@
+/*
+   Offline bounce of a recorded session: the note stream of a session journal (see journal.rs) is
+       turned into an nrt_record bundle with the original timing, and rendered by the usual
+       nrt_record handling once the synthdefs, samples and groups of the session are set up again.
+
+   Notes are placed at their receive time plus delay_ms, counted from the first note. The score is
+       rendered at 60 bpm so that score beats are seconds; gate times of /note_on_timed are converted
+       from beats with the bpm set by /set_bpm at the time.
+*/
+use std::time::{Duration, SystemTime};
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use jdw_osc_lib::model::{OscArgHandler, TaggedBundle, TimedOSCPacket};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::journal::{self, JournalEntry, Replay, ReplayTiming};
+use crate::osc_model::{
+    FreeNotesMessage, NRTRecordMessage, NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage,
+    PlaySampleMessage, RealTimePacket,
+};
+
+const RENDER_BPM: i32 = 60;
+
+// Rendered after the last note has ended, for releases and effects to ring out
+const TAIL_SECS: i64 = 2;
+
+// Messages that set up state the notes depend on, replayed ahead of the render
+const SETUP_MESSAGES: [&str; 4] = [
+    "/create_synthdef",
+    "/create_synthdef_bytes",
+    "/load_sample",
+    "/create_group",
+];
+
+pub struct SessionScore {
+    pub setup: Vec<OscPacket>, // Setup messages of the session, in the order they were received
+    pub record: OscPacket,     // nrt_record bundle of all notes
+    pub notes: usize,
+}
+
+/*
+    Replay that sets up the session, renders it to file_name and then quits.
+    default_bpm applies until the journal sets another one.
+*/
+pub fn load(journal_path: &str, file_name: &str, default_bpm: i32) -> Result<Replay, String> {
+    let score = session_score(&journal::read(journal_path)?, file_name, default_bpm);
+
+    if score.notes == 0 {
+        return Err(format!("No notes to render in journal {}", journal_path));
+    }
+
+    let now = SystemTime::now();
+    let quit = OscPacket::Message(OscMessage {
+        addr: "/quit".to_string(),
+        args: vec![],
+    });
+
+    let entries = score
+        .setup
+        .into_iter()
+        .chain([score.record, quit])
+        .map(|packet| JournalEntry {
+            receive_time: now,
+            packet,
+        })
+        .collect();
+
+    Ok(Replay {
+        entries,
+        timing: ReplayTiming::Fast,
+    })
+}
+
+pub fn session_score(entries: &[JournalEntry], file_name: &str, default_bpm: i32) -> SessionScore {
+    let mut bpm = default_bpm;
+    let mut setup = vec![];
+    let mut notes: Vec<(SystemTime, OscMessage)> = vec![];
+    let mut gate_ends: Vec<(SystemTime, BigDecimal)> = vec![];
+
+    for (time, mut msg) in messages(entries) {
+        let addr = msg.addr.clone();
+
+        let delay_ms = match addr.as_str() {
+            "/set_bpm" => {
+                // Rejected by the daemon as well, see command_registry::validate
+                bpm = msg
+                    .get_int_at(0, "BPM value")
+                    .ok()
+                    .filter(|bpm| *bpm > 0)
+                    .unwrap_or(bpm);
+                None
+            }
+            "/note_on" => NoteOnMessage::new(&msg).ok().map(|note| note.delay_ms),
+            "/note_on_timed" => match NoteOnTimedMessage::new(&msg) {
+                Ok(note) => {
+                    let gate_secs = (note.gate_time * BigDecimal::from(RENDER_BPM)
+                        / BigDecimal::from(bpm))
+                    .with_scale(6);
+
+                    msg.args[2] = OscType::String(gate_secs.to_string());
+                    gate_ends.push((time + Duration::from_millis(note.delay_ms), gate_secs));
+                    Some(note.delay_ms)
+                }
+                Err(_) => None,
+            },
+            "/note_modify" => NoteModifyMessage::new(&msg).ok().map(|note| note.delay_ms),
+            "/play_sample" => PlaySampleMessage::new(&msg).ok().map(|note| note.delay_ms),
+            "/free_notes" => FreeNotesMessage::new(&msg).ok().map(|_| 0),
+            addr => {
+                if SETUP_MESSAGES.contains(&addr) {
+                    setup.push(OscPacket::Message(msg));
+                    continue;
+                }
+                None
+            }
+        };
+
+        if let Some(delay_ms) = delay_ms {
+            notes.push((time + Duration::from_millis(delay_ms), msg));
+        }
+    }
+
+    // Stable, so that notes at the same time keep the order they were received in
+    notes.sort_by_key(|(time, _)| *time);
+
+    let start = notes
+        .first()
+        .map(|(time, _)| *time)
+        .unwrap_or(SystemTime::now());
+    let offsets: Vec<BigDecimal> = notes
+        .iter()
+        .map(|(time, _)| seconds(start, *time))
+        .collect();
+
+    // Each row waits until the next note before moving on
+    let rows: Vec<TimedOSCPacket> = notes
+        .iter()
+        .enumerate()
+        .map(|(i, (_, msg))| TimedOSCPacket {
+            time: offsets
+                .get(i + 1)
+                .map(|next| next - &offsets[i])
+                .unwrap_or_else(BigDecimal::zero),
+            packet: OscPacket::Message(msg.clone()),
+        })
+        .collect();
+
+    let end = gate_ends
+        .iter()
+        .map(|(time, gate_secs)| seconds(start, *time) + gate_secs)
+        .chain(offsets.last().cloned())
+        .max()
+        .unwrap_or_else(BigDecimal::zero)
+        + BigDecimal::from(TAIL_SECS);
+
+    let record = NRTRecordMessage {
+        file_name: file_name.to_string(),
+        bpm: RENDER_BPM as f32,
+        messages: rows,
+        end_beat: end.to_f32().unwrap_or(0.0),
+    }
+    .to_osc();
+
+    SessionScore {
+        setup,
+        record,
+        notes: notes.len(),
+    }
+}
+
+// Messages of the journal with their receive time, unpacking batch-send and real_time_packet bundles
+fn messages(entries: &[JournalEntry]) -> Vec<(SystemTime, OscMessage)> {
+    let mut messages = vec![];
+
+    for entry in entries {
+        unpack(entry.packet.clone(), entry.receive_time, &mut messages);
+    }
+
+    messages
+}
+
+fn unpack(packet: OscPacket, time: SystemTime, messages: &mut Vec<(SystemTime, OscMessage)>) {
+    match packet {
+        OscPacket::Message(msg) => messages.push((time, msg)),
+        OscPacket::Bundle(osc_bundle) => {
+            if let Ok(tagged_bundle) = TaggedBundle::new(&osc_bundle) {
+                match tagged_bundle.bundle_tag.as_str() {
+                    "batch-send" => {
+                        for packet in tagged_bundle.contents {
+                            unpack(packet, time, messages);
+                        }
+                    }
+                    "real_time_packet" => {
+                        if let Ok(real_time) = RealTimePacket::new(tagged_bundle) {
+                            unpack(real_time.packet, real_time.time, messages);
+                        }
+                    }
+                    // nrt bundles are not part of the live note stream
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn seconds(start: SystemTime, time: SystemTime) -> BigDecimal {
+    let micros = time
+        .duration_since(start)
+        .unwrap_or(Duration::ZERO)
+        .as_micros() as i64;
+    BigDecimal::from(micros) / BigDecimal::from(1_000_000)
+}
@@ -0,0 +1,291 @@
+/*
+   Shared shutdown sequence for the /quit message and termination signals.
+
+   Signal handlers do not have access to interpreter state (loaded buffers etc.), so when the
+       daemon is listening they ask it to quit via its own in-port and only fall back to a bare
+       process teardown if the daemon does not finish in time.
+*/
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{error, info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+use subprocess::Popen;
+
+use crate::config::Config;
+use crate::osc_daemon;
+use crate::pidfile;
+use crate::sc_backend::ScBackend;
+
+// Exit codes for the process after shutdown
+pub const EXIT_CLEAN: i32 = 0; // All processes exited on request
+pub const EXIT_FORCED: i32 = 1; // At least one process had to be killed
+pub const EXIT_FAILED: i32 = 2; // At least one process could not be stopped
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    process: Arc<Mutex<Option<Popen>>>, // None when no process was launched (e.g. in tests)
+    listening: Arc<AtomicBool>,
+    started: Arc<AtomicBool>,
+    exit_code: Arc<Mutex<Option<i32>>>, // Set once the shutdown sequence has completed
+    config: Arc<Config>,
+}
+
+impl ShutdownHandle {
+    pub fn new(process: Popen, config: Arc<Config>) -> ShutdownHandle {
+        ShutdownHandle {
+            process: Arc::new(Mutex::new(Some(process))),
+            listening: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(AtomicBool::new(false)),
+            exit_code: Arc::new(Mutex::new(None)),
+            config,
+        }
+    }
+
+    // Handle for an interpreter that does not own any processes, e.g. one driving a RecordingBackend
+    pub fn without_process(config: Arc<Config>) -> ShutdownHandle {
+        ShutdownHandle {
+            process: Arc::new(Mutex::new(None)),
+            listening: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(AtomicBool::new(false)),
+            exit_code: Arc::new(Mutex::new(None)),
+            config,
+        }
+    }
+
+    // Outcome of a completed shutdown sequence, if any
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock().unwrap()
+    }
+
+    // Pid of the launched process: sclang, or scsynth when running without sclang
+    pub fn child_pid(&self) -> Option<u32> {
+        self.process.lock().unwrap().as_ref().and_then(|process| process.pid())
+    }
+
+    // The pidfile belongs to whoever launched the processes
+    fn owns_processes(&self) -> bool {
+        self.process.lock().unwrap().is_some()
+    }
+
+    // scsynth processes from the pidfile that are not the child process itself
+    fn detached_servers(&self) -> Vec<pidfile::ManagedProcess> {
+        if !self.owns_processes() {
+            return vec![];
+        }
+
+        let child_pid = self.child_pid();
+        pidfile::read(&self.config)
+            .into_iter()
+            .filter(|p| p.role == "scsynth" && Some(p.pid) != child_pid)
+            .collect()
+    }
+
+    // Called by the daemon once it is ready to receive /quit
+    pub fn mark_listening(&self) {
+        self.listening.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    // True from the start of the shutdown sequence, for background threads to stop on
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /*
+        Entry point for termination signals: delegate to the daemon's /quit if possible,
+            otherwise tear down the processes without talking to the server.
+    */
+    pub fn handle_signal(&self) -> i32 {
+        if self.listening.load(Ordering::SeqCst) {
+            if !self.started.load(Ordering::SeqCst) {
+                info!("Requesting graceful shutdown from daemon");
+                self.request_quit();
+            }
+
+            let grace = Duration::from_millis(self.config.shutdown_fade_ms)
+                + Duration::from_secs(self.config.shutdown_timeout_secs * 2);
+            let start = Instant::now();
+
+            while start.elapsed() < grace {
+                if let Some(code) = self.exit_code() {
+                    return code;
+                }
+                sleep(Duration::from_millis(100));
+            }
+
+            warn!("Daemon did not shut down in time, terminating processes directly");
+        }
+
+        self.terminate_processes()
+    }
+
+    /*
+        Full shutdown: fade out and free all nodes, free buffers, quit the server via sclang,
+            then make sure that both scsynth and sclang have exited.
+    */
+    pub fn run_sequence(&self, client: &impl ScBackend, fade_ms: u64, buffers: &[i32]) -> i32 {
+        if self.started.swap(true, Ordering::SeqCst) {
+            warn!("Shutdown already in progress");
+            return EXIT_CLEAN;
+        }
+
+        let cfg = &self.config;
+
+        info!("Shutting down ...");
+
+        if fade_ms > 0 {
+            info!("Releasing all notes, waiting {}ms for fade out", fade_ms);
+            send_now(client, "/n_set", vec![
+                OscType::Int(cfg.group_id),
+                OscType::String("gate".to_string()),
+                OscType::Float(0.0),
+            ]);
+            sleep(Duration::from_millis(fade_ms));
+        }
+
+        send_now(client, "/g_freeAll", vec![OscType::Int(cfg.group_id)]);
+
+        for buffer in buffers {
+            send_now(client, "/b_free", vec![OscType::Int(*buffer)]);
+        }
+
+        let timeout = Duration::from_secs(cfg.shutdown_timeout_secs);
+        let mut code = EXIT_CLEAN;
+
+        if client.has_sclang() {
+            client.send_to_sclang(OscMessage {
+                addr: "/read_scd".to_string(),
+                args: vec![OscType::String(format!("{}.quit;", cfg.server_name))],
+            });
+
+            for process in self.detached_servers() {
+                code = code.max(await_exit(process.pid, timeout));
+            }
+        } else {
+            send_now(client, "/quit", vec![]);
+        }
+
+        code = code.max(self.terminate_child(timeout, !client.has_sclang()));
+
+        if code != EXIT_FAILED && self.owns_processes() {
+            pidfile::remove(&self.config);
+        }
+
+        info!("Shutdown completed with status {}", code);
+        *self.exit_code.lock().unwrap() = Some(code);
+        code
+    }
+
+    // Bare teardown without server interaction, used when the daemon is not available
+    fn terminate_processes(&self) -> i32 {
+        let timeout = Duration::from_secs(self.config.shutdown_timeout_secs);
+        let mut code = self.terminate_child(timeout, false);
+
+        for process in self.detached_servers() {
+            if pidfile::is_alive(process.pid) {
+                code = code.max(if pidfile::terminate(process.pid, timeout) {
+                    EXIT_FORCED
+                } else {
+                    EXIT_FAILED
+                });
+            }
+        }
+
+        if code != EXIT_FAILED && self.owns_processes() {
+            pidfile::remove(&self.config);
+        }
+
+        *self.exit_code.lock().unwrap() = Some(code);
+        code
+    }
+
+    // Send /quit to the daemon's public in-port
+    fn request_quit(&self) {
+        let quit = OscPacket::Message(OscMessage {
+            addr: "/quit".to_string(),
+            args: vec![],
+        });
+
+        if let Err(e) = osc_daemon::send_to_daemon(&self.config, &quit) {
+            warn!("Failed to send /quit to daemon: {}", e);
+        }
+    }
+
+    // Stop the launched process, waiting for it to exit on its own first if it was asked to quit
+    fn terminate_child(&self, timeout: Duration, quit_requested: bool) -> i32 {
+        let mut guard = self.process.lock().unwrap();
+
+        let process = match guard.as_mut() {
+            Some(process) => process,
+            None => return EXIT_CLEAN,
+        };
+
+        if process.poll().is_some() {
+            return EXIT_CLEAN;
+        }
+
+        if quit_requested {
+            if let Ok(Some(_)) = process.wait_timeout(timeout) {
+                return EXIT_CLEAN;
+            }
+        }
+
+        if let Err(e) = process.terminate() {
+            warn!("Failed to terminate child process: {}", e);
+        }
+
+        match process.wait_timeout(timeout) {
+            Ok(Some(_)) => EXIT_CLEAN,
+            _ => {
+                warn!("Child process did not exit in time, killing it");
+                if let Err(e) = process.kill() {
+                    warn!("Failed to kill child process: {}", e);
+                }
+
+                match process.wait_timeout(timeout) {
+                    Ok(Some(_)) => EXIT_FORCED,
+                    _ => {
+                        error!("Failed to kill child process");
+                        EXIT_FAILED
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn send_now(client: &impl ScBackend, addr: &str, args: Vec<OscType>) {
+    client.send_to_scsynth_with_delay(
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        }),
+        0,
+        SystemTime::now(),
+    );
+}
+
+// Wait for a process to exit on its own, escalating to a kill after the timeout
+fn await_exit(pid: u32, timeout: Duration) -> i32 {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if !pidfile::is_alive(pid) {
+            return EXIT_CLEAN;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    warn!("scsynth (pid {}) did not exit in time, terminating it", pid);
+    if pidfile::terminate(pid, timeout) {
+        EXIT_FORCED
+    } else {
+        error!("Failed to stop scsynth (pid {})", pid);
+        EXIT_FAILED
+    }
+}
@@ -0,0 +1,232 @@
+/*
+   Periodic scsynth /status polling with a rolling history and threshold alerts.
+
+   Every poll is reported as /jdw_sc_status. Alerts are sent when a metric crosses its
+       configured threshold (0 disables a threshold), and cleared when it drops back below.
+
+   MSG OUT: /jdw_sc_status, <ugens>, <synths>, <groups>, <synthdefs>, <avg cpu>, <peak cpu>, <nominal sample rate>, <actual sample rate>
+   MSG OUT: /jdw_sc_status_alert, <metric>, <value>, <threshold>
+   MSG OUT: /jdw_sc_status_alert_cleared, <metric>, <value>, <threshold>
+*/
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use jdw_osc_lib::model::OscArgHandler;
+use log::{info, warn};
+use rosc::{OscMessage, OscType};
+
+use crate::config::Config;
+use crate::sc_backend::ScBackend;
+use crate::sc_process_management::SCClient;
+use crate::shutdown::ShutdownHandle;
+
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub time: SystemTime,
+    pub ugens: i32,
+    pub synths: i32,
+    pub groups: i32,
+    pub synthdefs: i32,
+    pub avg_cpu: f32,
+    pub peak_cpu: f32,
+    pub nominal_sample_rate: f64,
+    pub actual_sample_rate: f64,
+}
+
+// Sample rates are doubles in /status.reply, but accept floats as well
+fn get_f64_at(msg: &OscMessage, index: usize) -> Result<f64, String> {
+    match msg.args.get(index) {
+        Some(OscType::Double(value)) => Ok(*value),
+        Some(OscType::Float(value)) => Ok(*value as f64),
+        other => Err(format!(
+            "Expected a number at index {} of {}, got {:?}",
+            index, msg.addr, other
+        )),
+    }
+}
+
+impl ServerStatus {
+    // MSG: /status.reply, 1, <ugens>, <synths>, <groups>, <synthdefs>, <avg cpu>, <peak cpu>, <nominal sr>, <actual sr>
+    pub fn from_reply(msg: &OscMessage) -> Result<ServerStatus, String> {
+        msg.expect_addr("/status.reply")?;
+        msg.expect_args(9)?;
+
+        Ok(ServerStatus {
+            time: SystemTime::now(),
+            ugens: msg.get_int_at(1, "ugens")?,
+            synths: msg.get_int_at(2, "synths")?,
+            groups: msg.get_int_at(3, "groups")?,
+            synthdefs: msg.get_int_at(4, "synthdefs")?,
+            avg_cpu: msg.get_float_at(5, "avg cpu")?,
+            peak_cpu: msg.get_float_at(6, "peak cpu")?,
+            nominal_sample_rate: get_f64_at(msg, 7)?,
+            actual_sample_rate: get_f64_at(msg, 8)?,
+        })
+    }
+
+    pub fn as_osc_args(&self) -> Vec<OscType> {
+        vec![
+            OscType::Int(self.ugens),
+            OscType::Int(self.synths),
+            OscType::Int(self.groups),
+            OscType::Int(self.synthdefs),
+            OscType::Float(self.avg_cpu),
+            OscType::Float(self.peak_cpu),
+            OscType::Double(self.nominal_sample_rate),
+            OscType::Double(self.actual_sample_rate),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub metric: &'static str,
+    pub limit: f64,
+}
+
+impl Threshold {
+    pub fn value(&self, status: &ServerStatus) -> f64 {
+        match self.metric {
+            "avg_cpu" => status.avg_cpu as f64,
+            "peak_cpu" => status.peak_cpu as f64,
+            "synths" => status.synths as f64,
+            _ => status.ugens as f64,
+        }
+    }
+}
+
+// Configured thresholds that are enabled (above 0)
+pub fn configured_thresholds(cfg: &Config) -> Vec<Threshold> {
+    vec![
+        Threshold {
+            metric: "avg_cpu",
+            limit: cfg.status_alert_avg_cpu,
+        },
+        Threshold {
+            metric: "peak_cpu",
+            limit: cfg.status_alert_peak_cpu,
+        },
+        Threshold {
+            metric: "synths",
+            limit: cfg.status_alert_synths as f64,
+        },
+        Threshold {
+            metric: "ugens",
+            limit: cfg.status_alert_ugens as f64,
+        },
+    ]
+    .into_iter()
+    .filter(|threshold| threshold.limit > 0.0)
+    .collect()
+}
+
+/*
+    Shared handle to the polled statuses. Cloning shares the same history.
+*/
+#[derive(Clone)]
+pub struct StatusMonitor {
+    history: Arc<Mutex<VecDeque<ServerStatus>>>,
+    capacity: usize,
+}
+
+impl StatusMonitor {
+    pub fn new(capacity: usize) -> StatusMonitor {
+        StatusMonitor {
+            history: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn latest(&self) -> Option<ServerStatus> {
+        self.history.lock().unwrap().back().cloned()
+    }
+
+    // Oldest first
+    pub fn history(&self) -> Vec<ServerStatus> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn record(&self, status: ServerStatus) {
+        let mut history = self.history.lock().unwrap();
+
+        history.push_back(status);
+        while history.len() > self.capacity {
+            history.pop_front();
+        }
+    }
+
+    /*
+        Start polling in a separate thread, unless status_poll_interval_ms is 0.
+        Polling stops once a shutdown has started.
+    */
+    pub fn spawn(&self, client: SCClient, shutdown: ShutdownHandle) {
+        let cfg = client.config().clone();
+
+        if cfg.status_poll_interval_ms == 0 {
+            info!("Status polling disabled");
+            return;
+        }
+
+        let interval = Duration::from_millis(cfg.status_poll_interval_ms);
+        let monitor = self.clone();
+
+        thread::spawn(move || {
+            let thresholds = configured_thresholds(&cfg);
+            let mut alerting = vec![false; thresholds.len()];
+
+            loop {
+                thread::sleep(interval);
+
+                if shutdown.is_started() {
+                    break;
+                }
+
+                let status = match client.query_status(interval) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        warn!("Status poll failed: {}", e);
+                        continue;
+                    }
+                };
+
+                client.send_out(OscMessage {
+                    addr: "/jdw_sc_status".to_string(),
+                    args: status.as_osc_args(),
+                });
+
+                for (threshold, active) in thresholds.iter().zip(alerting.iter_mut()) {
+                    let value = threshold.value(&status);
+                    let above = value > threshold.limit;
+
+                    if above != *active {
+                        *active = above;
+
+                        let addr = if above {
+                            warn!(
+                                "Server {} at {} is above {}",
+                                threshold.metric, value, threshold.limit
+                            );
+                            "/jdw_sc_status_alert"
+                        } else {
+                            info!("Server {} back below {}", threshold.metric, threshold.limit);
+                            "/jdw_sc_status_alert_cleared"
+                        };
+
+                        client.send_out(OscMessage {
+                            addr: addr.to_string(),
+                            args: vec![
+                                OscType::String(threshold.metric.to_string()),
+                                OscType::Double(value),
+                                OscType::Double(threshold.limit),
+                            ],
+                        });
+                    }
+                }
+
+                monitor.record(status);
+            }
+        });
+    }
+}
@@ -0,0 +1,431 @@
+/*
+   Builder for simple UGen graphs, encoded as SynthDef2 without going through sclang.
+
+   UGens are added in order and may only use signals created before them, so the graph is
+       always topologically sorted. Controls are collected into a single Control UGen at the
+       start of the definition. Constants are deduplicated on build.
+
+   Example, equivalent to SynthDef(\ping, { |freq=440, amp=0.5| Out.ar(0, SinOsc.ar(freq) * EnvGen.kr(Env.perc, doneAction: 2) * amp) }):
+
+       let mut def = SynthDefBuilder::new("ping");
+       let freq = def.control("freq", 440.0);
+       let amp = def.control("amp", 0.5);
+       let osc = def.sin_osc(Rate::Audio, freq, 0.0);
+       let env = def.env_gen(&Envelope::perc(0.01, 1.0), 1.0, DONE_FREE_SELF);
+       let sig = def.mul(osc, env);
+       let sig = def.mul(sig, amp);
+       def.out(0.0, &[sig]);
+       let bytes = def.encode();
+*/
+use crate::scsyndef::{self, InputSpec, SynthDefData, UGenSpec};
+
+// EnvGen doneAction that frees the synth when the envelope ends (Done.freeSelf)
+pub const DONE_FREE_SELF: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Rate {
+    Scalar = 0,
+    Control = 1,
+    Audio = 2,
+}
+
+// BinaryOpUGen special indices
+const OP_ADD: i16 = 0;
+const OP_MUL: i16 = 2;
+
+// Env curve shape ids
+const SHAPE_LINEAR: f32 = 1.0;
+const SHAPE_CURVE: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Constant(f32),
+    Control(usize), // Index of the control
+    UGen {
+        index: usize,
+        output: usize,
+        rate: Rate,
+    },
+}
+
+impl Signal {
+    pub fn rate(&self) -> Rate {
+        match self {
+            Signal::Constant(_) => Rate::Scalar,
+            Signal::Control(_) => Rate::Control,
+            Signal::UGen { rate, .. } => *rate,
+        }
+    }
+}
+
+impl From<f32> for Signal {
+    fn from(value: f32) -> Self {
+        Signal::Constant(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvStage {
+    pub level: f32,
+    pub time: f32,
+    pub curve: Option<f32>, // None for linear
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub init_level: f32,
+    pub stages: Vec<EnvStage>,
+    pub release_node: Option<usize>,
+}
+
+impl Envelope {
+    // Same as Env.perc(attack, release) in sclang
+    pub fn perc(attack: f32, release: f32) -> Envelope {
+        Envelope {
+            init_level: 0.0,
+            stages: vec![
+                EnvStage {
+                    level: 1.0,
+                    time: attack,
+                    curve: Some(-4.0),
+                },
+                EnvStage {
+                    level: 0.0,
+                    time: release,
+                    curve: Some(-4.0),
+                },
+            ],
+            release_node: None,
+        }
+    }
+
+    // Same as Env.asr(attack, level, release) in sclang; holds until gate is set to 0
+    pub fn asr(attack: f32, level: f32, release: f32) -> Envelope {
+        Envelope {
+            init_level: 0.0,
+            stages: vec![
+                EnvStage {
+                    level,
+                    time: attack,
+                    curve: Some(-4.0),
+                },
+                EnvStage {
+                    level: 0.0,
+                    time: release,
+                    curve: Some(-4.0),
+                },
+            ],
+            release_node: Some(1),
+        }
+    }
+
+    // Flattened into EnvGen inputs, as done by Env.asArray in sclang
+    fn as_inputs(&self) -> Vec<Signal> {
+        let mut inputs = vec![
+            self.init_level,
+            self.stages.len() as f32,
+            self.release_node.map(|node| node as f32).unwrap_or(-99.0),
+            -99.0, // No loop node
+        ];
+
+        for stage in &self.stages {
+            let (shape, curve) = match stage.curve {
+                Some(curve) => (SHAPE_CURVE, curve),
+                None => (SHAPE_LINEAR, 0.0),
+            };
+            inputs.extend([stage.level, stage.time, shape, curve]);
+        }
+
+        inputs.into_iter().map(Signal::Constant).collect()
+    }
+}
+
+struct UGenNode {
+    name: String,
+    rate: Rate,
+    inputs: Vec<Signal>,
+    output_count: usize,
+    special_index: i16,
+}
+
+pub struct SynthDefBuilder {
+    name: String,
+    controls: Vec<(String, f32)>,
+    ugens: Vec<UGenNode>,
+}
+
+impl SynthDefBuilder {
+    pub fn new(name: &str) -> SynthDefBuilder {
+        SynthDefBuilder {
+            name: name.to_string(),
+            controls: vec![],
+            ugens: vec![],
+        }
+    }
+
+    // Named control-rate parameter, settable with /s_new and /n_set
+    pub fn control(&mut self, name: &str, default: f32) -> Signal {
+        self.controls.push((name.to_string(), default));
+        Signal::Control(self.controls.len() - 1)
+    }
+
+    /*
+        Add any UGen by its class name. Returns one signal per output.
+        The builder does not check input counts; refer to the UGen's sclang class for the input order.
+    */
+    pub fn ugen(
+        &mut self,
+        name: &str,
+        rate: Rate,
+        inputs: &[Signal],
+        output_count: usize,
+        special_index: i16,
+    ) -> Vec<Signal> {
+        self.ugens.push(UGenNode {
+            name: name.to_string(),
+            rate,
+            inputs: inputs.to_vec(),
+            output_count,
+            special_index,
+        });
+
+        let index = self.ugens.len() - 1;
+        (0..output_count)
+            .map(|output| Signal::UGen {
+                index,
+                output,
+                rate,
+            })
+            .collect()
+    }
+
+    fn single(&mut self, name: &str, rate: Rate, inputs: &[Signal]) -> Signal {
+        self.ugen(name, rate, inputs, 1, 0)[0]
+    }
+
+    fn binary_op(&mut self, op: i16, a: Signal, b: Signal) -> Signal {
+        let rate = if a.rate() > b.rate() {
+            a.rate()
+        } else {
+            b.rate()
+        };
+        self.ugen("BinaryOpUGen", rate, &[a, b], 1, op)[0]
+    }
+
+    pub fn add(&mut self, a: impl Into<Signal>, b: impl Into<Signal>) -> Signal {
+        self.binary_op(OP_ADD, a.into(), b.into())
+    }
+
+    pub fn mul(&mut self, a: impl Into<Signal>, b: impl Into<Signal>) -> Signal {
+        self.binary_op(OP_MUL, a.into(), b.into())
+    }
+
+    pub fn sin_osc(
+        &mut self,
+        rate: Rate,
+        freq: impl Into<Signal>,
+        phase: impl Into<Signal>,
+    ) -> Signal {
+        self.single("SinOsc", rate, &[freq.into(), phase.into()])
+    }
+
+    pub fn saw(&mut self, rate: Rate, freq: impl Into<Signal>) -> Signal {
+        self.single("Saw", rate, &[freq.into()])
+    }
+
+    pub fn pulse(
+        &mut self,
+        rate: Rate,
+        freq: impl Into<Signal>,
+        width: impl Into<Signal>,
+    ) -> Signal {
+        self.single("Pulse", rate, &[freq.into(), width.into()])
+    }
+
+    pub fn white_noise(&mut self, rate: Rate) -> Signal {
+        self.single("WhiteNoise", rate, &[])
+    }
+
+    pub fn lpf(&mut self, input: Signal, freq: impl Into<Signal>) -> Signal {
+        self.single("LPF", input.rate(), &[input, freq.into()])
+    }
+
+    pub fn hpf(&mut self, input: Signal, freq: impl Into<Signal>) -> Signal {
+        self.single("HPF", input.rate(), &[input, freq.into()])
+    }
+
+    pub fn rlpf(
+        &mut self,
+        input: Signal,
+        freq: impl Into<Signal>,
+        rq: impl Into<Signal>,
+    ) -> Signal {
+        self.single("RLPF", input.rate(), &[input, freq.into(), rq.into()])
+    }
+
+    // Control-rate envelope; pass a gate control for sustained envelopes such as Envelope::asr
+    pub fn env_gen(
+        &mut self,
+        envelope: &Envelope,
+        gate: impl Into<Signal>,
+        done_action: f32,
+    ) -> Signal {
+        let mut inputs = vec![
+            gate.into(),
+            Signal::Constant(1.0), // levelScale
+            Signal::Constant(0.0), // levelBias
+            Signal::Constant(1.0), // timeScale
+            Signal::Constant(done_action),
+        ];
+        inputs.extend(envelope.as_inputs());
+
+        self.single("EnvGen", Rate::Control, &inputs)
+    }
+
+    pub fn pan2(
+        &mut self,
+        input: Signal,
+        pos: impl Into<Signal>,
+        level: impl Into<Signal>,
+    ) -> (Signal, Signal) {
+        let outputs = self.ugen(
+            "Pan2",
+            Rate::Audio,
+            &[input, pos.into(), level.into()],
+            2,
+            0,
+        );
+        (outputs[0], outputs[1])
+    }
+
+    // Audio-rate Out to consecutive buses starting at bus
+    pub fn out(&mut self, bus: impl Into<Signal>, channels: &[Signal]) {
+        let mut inputs = vec![bus.into()];
+        inputs.extend_from_slice(channels);
+        self.ugen("Out", Rate::Audio, &inputs, 0, 0);
+    }
+
+    pub fn build(&self) -> SynthDefData {
+        let mut constants: Vec<f32> = vec![];
+
+        // The Control UGen holding all parameters is placed first, shifting all other UGens by one
+        let offset = if self.controls.is_empty() { 0 } else { 1 };
+
+        let mut ugens = vec![];
+
+        if !self.controls.is_empty() {
+            ugens.push(UGenSpec {
+                name: "Control".to_string(),
+                rate: Rate::Control as i8,
+                inputs: vec![],
+                outputs: vec![Rate::Control as i8; self.controls.len()],
+                special_index: 0,
+            });
+        }
+
+        for node in &self.ugens {
+            let inputs = node
+                .inputs
+                .iter()
+                .map(|input| match input {
+                    Signal::Constant(value) => {
+                        let position = constants
+                            .iter()
+                            .position(|constant| constant.to_bits() == value.to_bits());
+
+                        InputSpec::Constant(position.unwrap_or_else(|| {
+                            constants.push(*value);
+                            constants.len() - 1
+                        }))
+                    }
+                    Signal::Control(index) => InputSpec::UGen(0, *index),
+                    Signal::UGen { index, output, .. } => InputSpec::UGen(index + offset, *output),
+                })
+                .collect();
+
+            ugens.push(UGenSpec {
+                name: node.name.clone(),
+                rate: node.rate as i8,
+                inputs,
+                outputs: vec![node.rate as i8; node.output_count],
+                special_index: node.special_index,
+            });
+        }
+
+        SynthDefData {
+            name: self.name.clone(),
+            constants,
+            params: self.controls.iter().map(|(_, default)| *default).collect(),
+            param_names: self
+                .controls
+                .iter()
+                .enumerate()
+                .map(|(index, (name, _))| (name.clone(), index))
+                .collect(),
+            ugens,
+            variants: vec![],
+        }
+    }
+
+    // SynthDef2 file contents, e.g. for /create_synthdef_bytes or /d_recv
+    pub fn encode(&self) -> Vec<u8> {
+        scsyndef::encode(&[self.build()])
+    }
+}
+
+/*
+    Same graph as scd/sampler.scd, for loading the sampler when sclang is not available to compile it.
+    The envelope levels [0, amp, amp, 0] are expressed as [0, 1, 1, 0] with amp as levelScale.
+*/
+pub fn sampler() -> SynthDefBuilder {
+    let constant = Signal::Constant;
+
+    let mut def = SynthDefBuilder::new("sampler");
+    let out = def.control("out", 0.0);
+    let start = def.control("start", 0.0);
+    let sus = def.control("sus", 10.0);
+    let amp = def.control("amp", 1.0);
+    let rate = def.control("rate", 1.0);
+    let buf = def.control("buf", 0.0);
+    let pan = def.control("pan", 0.0);
+    let ofs = def.control("ofs", 0.05);
+
+    let rate_scale = def.single("BufRateScale", Rate::Control, &[buf]);
+    let playback_rate = def.mul(rate_scale, rate);
+    let trigger = constant(1.0);
+    let no_loop = constant(0.0);
+    let done_nothing = constant(0.0);
+    let osc = def.single(
+        "PlayBuf",
+        Rate::Audio,
+        &[buf, playback_rate, trigger, start, no_loop, done_nothing],
+    );
+
+    // Sample amp usually lands way lower than any synth amp
+    let level = def.mul(amp, constant(2.0));
+    let hold = def.add(sus, constant(-0.05));
+
+    let mut env_inputs = vec![
+        constant(1.0), // gate
+        level,         // levelScale
+        constant(0.0), // levelBias
+        constant(1.0), // timeScale
+        constant(DONE_FREE_SELF),
+        constant(0.0),   // Initial level
+        constant(3.0),   // Stages
+        constant(-99.0), // No release node
+        constant(-99.0), // No loop node
+    ];
+
+    for (level, time) in [(1.0, ofs), (1.0, hold), (0.0, constant(0.05))] {
+        env_inputs.extend([constant(level), time, constant(SHAPE_LINEAR), constant(0.0)]);
+    }
+
+    let env = def.single("EnvGen", Rate::Audio, &env_inputs);
+
+    let sig = def.mul(osc, env);
+    let (left, right) = def.pan2(sig, pan, constant(1.0));
+    def.out(out, &[left, right]);
+
+    def
+}
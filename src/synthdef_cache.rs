@@ -0,0 +1,44 @@
+/*
+   On-disk cache of compiled synthdefs: <state_dir>/synthdef_cache/<source hash>.scsyndef
+
+   Files are written by sclang the first time a source is seen and reused on later boots and
+       NRT renders, so that unchanged definitions never have to be interpreted again.
+*/
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+// FNV-1a, chosen over DefaultHasher since keys must stay stable across builds and runs
+pub fn source_hash(source: &str) -> String {
+    let hash = source.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+pub fn cache_dir(cfg: &Config) -> Result<PathBuf, String> {
+    Ok(cfg.state_path()?.join("synthdef_cache"))
+}
+
+pub fn cache_path(cfg: &Config, source: &str) -> Result<PathBuf, String> {
+    Ok(cache_dir(cfg)?.join(format!("{}.scsyndef", source_hash(source))))
+}
+
+// Compiled bytes for the source, if cached
+pub fn read(cfg: &Config, source: &str) -> Option<Vec<u8>> {
+    fs::read(cache_path(cfg, source).ok()?).ok()
+}
+
+// Drop a cache entry, e.g. if it turned out to be unreadable
+pub fn invalidate(cfg: &Config, source: &str) {
+    if let Ok(path) = cache_path(cfg, source) {
+        fs::remove_file(path).ok();
+    }
+}
+
+pub fn ensure_dir(cfg: &Config) -> Result<(), String> {
+    fs::create_dir_all(cache_dir(cfg)?)
+        .map_err(|e| format!("Failed to create synthdef cache dir: {}", e))
+}
@@ -0,0 +1,144 @@
+/*
+   Directory of synthdef files (config: synthdef_dir), loaded at boot after the sampler.
+
+   Each .scd file holds a single SynthDef expression without ".add", in the same format as sampler.scd.
+   Precompiled .scsyndef files are loaded as-is, which also works without sclang.
+   The directory is polled for changes (unless synthdef_watch_interval_ms is 0); added, edited and
+       removed files are passed to the daemon over a channel that it drains between received packets,
+       so that all synthdef handling stays on the interpreter thread. They are not part of the OSC
+       protocol, so clients cannot make the daemon load files from elsewhere.
+*/
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::config::Config;
+use crate::shutdown::ShutdownHandle;
+
+// Last modification time of each .scd file in the directory
+pub type LibrarySnapshot = BTreeMap<PathBuf, SystemTime>;
+
+// Change found by the watcher
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibraryEvent {
+    Changed(PathBuf), // Added or edited
+    Removed(PathBuf),
+}
+
+// Configured library directory, None if disabled or missing
+pub fn library_dir(cfg: &Config) -> Option<PathBuf> {
+    let configured = &cfg.synthdef_dir;
+
+    if configured.is_empty() {
+        return None;
+    }
+
+    let dir = match configured.strip_prefix("~/") {
+        Some(relative) => home::home_dir()?.join(relative),
+        None => PathBuf::from(configured),
+    };
+
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        warn!("Synthdef dir {} does not exist, skipping", dir.display());
+        None
+    }
+}
+
+pub fn scan(dir: &Path) -> LibrarySnapshot {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read synthdef dir {}: {}", dir.display(), e);
+            return LibrarySnapshot::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "scd" || ext == "scsyndef")
+        })
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect()
+}
+
+// True if path resolves to a file inside dir, following symlinks and ".."
+pub fn contains(dir: &Path, path: &Path) -> bool {
+    match (dir.canonicalize(), path.canonicalize()) {
+        (Ok(dir), Ok(path)) => path.starts_with(dir),
+        _ => false,
+    }
+}
+
+pub fn is_compiled(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "scsyndef")
+}
+
+// Definition as it can be passed to create_synthdef (trailing semicolons removed)
+pub fn read_definition(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|contents| contents.trim().trim_end_matches(';').trim_end().to_string())
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+/*
+    Poll the directory and send changes compared to the given snapshot to events, until a shutdown
+        has started or the daemon has stopped. Does nothing if synthdef_watch_interval_ms is 0.
+*/
+pub fn spawn_watcher(
+    dir: PathBuf,
+    initial: LibrarySnapshot,
+    cfg: Arc<Config>,
+    shutdown: ShutdownHandle,
+    events: Sender<LibraryEvent>,
+) {
+    if cfg.synthdef_watch_interval_ms == 0 {
+        info!("Synthdef dir watching disabled");
+        return;
+    }
+
+    let interval = Duration::from_millis(cfg.synthdef_watch_interval_ms);
+
+    info!("Watching synthdef dir {}", dir.display());
+
+    thread::spawn(move || {
+        let mut known = initial;
+
+        while !shutdown.is_started() {
+            thread::sleep(interval);
+
+            let current = scan(&dir);
+
+            let changed = current
+                .iter()
+                .filter(|(path, modified)| known.get(*path) != Some(modified))
+                .map(|(path, _)| LibraryEvent::Changed(path.clone()));
+
+            let removed = known
+                .keys()
+                .filter(|path| !current.contains_key(*path))
+                .map(|path| LibraryEvent::Removed(path.clone()));
+
+            for event in changed.chain(removed) {
+                if events.send(event).is_err() {
+                    return;
+                }
+            }
+
+            known = current;
+        }
+    });
+}
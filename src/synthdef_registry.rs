@@ -0,0 +1,182 @@
+/*
+   Created synthdefs, keyed by the SynthDef name declared in their source.
+   Re-sending a definition with a known name replaces the previous version, so that
+       NRT scores never contain conflicting definitions for the same name.
+*/
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use log::warn;
+use rosc::OscType;
+
+// Name of the synthdef in sampler.scd
+pub const SAMPLER_SYNTH_NAME: &str = "sampler";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynthDefControl {
+    pub name: String,
+    pub default: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SynthDefEntry {
+    pub name: String,
+    pub source: String, // Empty for definitions created from compiled bytes
+    pub compiled: Option<Vec<u8>>, // Set for definitions created from compiled bytes
+    pub updated: SystemTime,
+    pub controls: Option<Vec<SynthDefControl>>, // None until introspection has completed
+}
+
+/*
+    How to treat note args that do not match a control of the target synthdef.
+    Args are only validated for synthdefs with known controls.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValidationMode {
+    Off,
+    Warn,   // Log unknown args, send them anyway
+    Strip,  // Remove unknown args
+    Reject, // Drop the whole message
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterOutcome {
+    Added,
+    Replaced,
+    Unchanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct SynthDefRegistry {
+    definitions: BTreeMap<String, SynthDefEntry>,
+}
+
+impl SynthDefRegistry {
+    pub fn new() -> SynthDefRegistry {
+        SynthDefRegistry {
+            definitions: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_sampler(sampler_source: &str) -> SynthDefRegistry {
+        let mut registry = SynthDefRegistry::new();
+        registry.register(SAMPLER_SYNTH_NAME, sampler_source);
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, source: &str) -> RegisterOutcome {
+        self.insert(name, source.to_string(), None)
+    }
+
+    // Register a definition that was compiled outside of sclang, e.g. by synthdef_builder
+    pub fn register_compiled(&mut self, name: &str, bytes: &[u8]) -> RegisterOutcome {
+        self.insert(name, String::new(), Some(bytes.to_vec()))
+    }
+
+    fn insert(&mut self, name: &str, source: String, compiled: Option<Vec<u8>>) -> RegisterOutcome {
+        let outcome = match self.definitions.get(name) {
+            Some(existing) if existing.source == source && existing.compiled == compiled => {
+                return RegisterOutcome::Unchanged
+            }
+            Some(_) => RegisterOutcome::Replaced,
+            None => RegisterOutcome::Added,
+        };
+
+        self.definitions.insert(
+            name.to_string(),
+            SynthDefEntry {
+                name: name.to_string(),
+                source,
+                compiled,
+                updated: SystemTime::now(),
+                controls: None,
+            },
+        );
+
+        outcome
+    }
+
+    // True if the exact same source is already registered under the name
+    pub fn is_current(&self, name: &str, source: &str) -> bool {
+        self.definitions
+            .get(name)
+            .is_some_and(|entry| entry.compiled.is_none() && entry.source == source)
+    }
+
+    pub fn is_current_compiled(&self, name: &str, bytes: &[u8]) -> bool {
+        self.definitions
+            .get(name)
+            .is_some_and(|entry| entry.compiled.as_deref() == Some(bytes))
+    }
+
+    pub fn set_controls(&mut self, name: &str, controls: Vec<SynthDefControl>) {
+        if let Some(entry) = self.definitions.get_mut(name) {
+            entry.controls = Some(controls);
+        }
+    }
+
+    /*
+        Validate named args ("freq", 440.0, ...) against the controls of a synthdef.
+        Names in reserved are treated as unknown even if the synthdef has them (e.g. "buf" for the sampler).
+        Returns the args to send, or Err if the message should be dropped.
+    */
+    pub fn validate_args(
+        &self,
+        synth_name: &str,
+        args: &[OscType],
+        reserved: &[&str],
+        mode: &ArgValidationMode,
+    ) -> Result<Vec<OscType>, String> {
+        let controls = match self.get(synth_name).and_then(|entry| entry.controls.as_ref()) {
+            Some(controls) if *mode != ArgValidationMode::Off => controls,
+            _ => return Ok(args.to_vec()),
+        };
+
+        let mut valid_args = vec![];
+        let mut unknown = vec![];
+
+        for pair in args.chunks(2) {
+            // Non-string names (control indices) are passed on as-is
+            match pair[0].clone().string() {
+                Some(name)
+                    if reserved.contains(&name.as_str())
+                        || !controls.iter().any(|control| control.name == name) =>
+                {
+                    unknown.push(name)
+                }
+                _ => valid_args.extend(pair.iter().cloned()),
+            }
+        }
+
+        if unknown.is_empty() {
+            return Ok(args.to_vec());
+        }
+
+        match mode {
+            ArgValidationMode::Reject => Err(format!(
+                "Args {:?} are not controls of synthdef {}",
+                unknown, synth_name
+            )),
+            ArgValidationMode::Strip => {
+                warn!("Stripped args {:?} not known by synthdef {}", unknown, synth_name);
+                Ok(valid_args)
+            }
+            _ => {
+                warn!("Args {:?} are not known by synthdef {}", unknown, synth_name);
+                Ok(args.to_vec())
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SynthDefEntry> {
+        self.definitions.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<SynthDefEntry> {
+        self.definitions.remove(name)
+    }
+
+    pub fn entries(&self) -> Vec<&SynthDefEntry> {
+        self.definitions.values().collect()
+    }
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use std::time::SystemTime;
+
+use jdw_sc::command_registry::{self, BUNDLES, COMMANDS};
+use jdw_sc::config::Config;
+use jdw_sc::json_endpoint::JsonCommand;
+use jdw_sc::sc_backend::RecordingBackend;
+use rosc::{OscPacket, OscType};
+
+use common::{interpreter, msg, string};
+
+#[test]
+fn accepts_messages_matching_their_command() {
+    let messages = vec![
+        msg(
+            "/note_on_timed",
+            vec![
+                string("default"),
+                string("lead_1"),
+                OscType::Float(0.5),
+                OscType::Int(0),
+                string("freq"),
+                OscType::Float(440.0),
+            ],
+        ),
+        msg(
+            "/note_on",
+            vec![string("default"), string("lead_1"), OscType::Long(0)],
+        ),
+        msg("/quit", vec![]),
+        msg("/quit", vec![OscType::Int(500)]),
+        msg("/get_status", vec![]),
+        msg("/some_other_daemon_message", vec![OscType::Nil]),
+    ];
+
+    for message in messages {
+        assert_eq!(
+            command_registry::validate(&message),
+            Ok(()),
+            "{:?}",
+            message
+        );
+    }
+}
+
+#[test]
+fn rejects_wrong_types_and_counts() {
+    let messages = vec![
+        msg("/set_bpm", vec![string("fast")]),
+        msg("/set_bpm", vec![]),
+        msg("/set_bpm", vec![OscType::Int(120), OscType::Int(4)]),
+        msg("/set_bpm", vec![OscType::Int(0)]),
+        msg("/set_bpm", vec![OscType::Long(-120)]),
+        msg("/note_on", vec![string("default"), string("lead_1")]),
+        msg(
+            "/note_on_timed",
+            vec![
+                string("default"),
+                string("lead_1"),
+                OscType::Nil,
+                OscType::Int(0),
+            ],
+        ),
+        msg("/create_synthdef_bytes", vec![string("not a blob")]),
+    ];
+
+    for message in messages {
+        assert!(
+            command_registry::validate(&message).is_err(),
+            "{:?}",
+            message
+        );
+    }
+}
+
+#[test]
+fn interpreter_rejects_invalid_messages() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    interpreter.interpret(
+        OscPacket::Message(msg("/note_on", vec![string("default"), string("fast")])),
+        SystemTime::now(),
+    );
+
+    assert!(backend.scsynth_messages().is_empty());
+
+    let out = backend.out_messages();
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].addr, "/jdw_sc_rejected");
+    assert_eq!(out[0].args[0], string("/note_on"));
+}
+
+#[test]
+fn describe_replies_with_every_command_and_bundle() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    interpreter.interpret(
+        OscPacket::Message(msg("/describe", vec![])),
+        SystemTime::now(),
+    );
+
+    let out = backend.out_messages();
+    assert_eq!(out.len(), COMMANDS.len() + BUNDLES.len());
+
+    let play_sample = out
+        .iter()
+        .find(|reply| reply.addr == "/describe_reply" && reply.args[0] == string("/play_sample"))
+        .unwrap();
+
+    // addr, description, reply, in scores, then four per arg
+    assert_eq!(play_sample.args[3], OscType::Int(1));
+    assert_eq!(play_sample.args.len(), 4 + 4 * 6);
+    assert_eq!(play_sample.args[4], string("external_id"));
+    assert_eq!(play_sample.args[5], string("string"));
+    assert_eq!(play_sample.args[6], string("required"));
+
+    assert!(out.iter().any(
+        |reply| reply.addr == "/describe_bundle_reply" && reply.args[0] == string("nrt_record")
+    ));
+}
+
+#[test]
+fn json_description_matches_registry() {
+    let json = command_registry::to_json();
+
+    let note_on_timed = json["commands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|command| command["addr"] == "/note_on_timed")
+        .unwrap();
+
+    assert_eq!(note_on_timed["in_scores"], true);
+    assert_eq!(note_on_timed["args"][2]["name"], "gate_time");
+    assert_eq!(note_on_timed["args"][2]["type"], "decimal");
+    assert_eq!(note_on_timed["args"][4]["kind"], "vararg");
+    assert_eq!(json["bundles"].as_array().unwrap().len(), BUNDLES.len());
+
+    assert!(JsonCommand::parse(r#"{"command": "describe"}"#).unwrap() == JsonCommand::Describe);
+}
+
+#[test]
+fn protocol_docs_cover_every_command() {
+    let doc = command_registry::protocol_markdown();
+
+    for command in COMMANDS {
+        assert!(
+            doc.contains(&format!("### {}\n", command.addr)),
+            "{}",
+            command.addr
+        );
+    }
+
+    for bundle in BUNDLES {
+        assert!(
+            doc.contains(&format!("### {}\n", bundle.tag)),
+            "{}",
+            bundle.tag
+        );
+    }
+}
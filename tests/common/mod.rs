@@ -0,0 +1,52 @@
+/*
+   Fixtures shared by the integration tests, included with `mod common;`.
+   Not every test uses every helper.
+*/
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use jdw_sc::config::Config;
+use jdw_sc::osc_daemon::Interpreter;
+use jdw_sc::sc_backend::RecordingBackend;
+use jdw_sc::shutdown::ShutdownHandle;
+use jdw_sc::status_monitor::StatusMonitor;
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+
+pub const SAMPLER_SNIPPET: &str =
+    "SynthDef(\"sampler\", { |buf = 0| Out.ar(0, PlayBuf.ar(2, buf)) })";
+
+pub fn interpreter(backend: &RecordingBackend) -> Interpreter<RecordingBackend> {
+    Interpreter::new(
+        backend.clone(),
+        SAMPLER_SNIPPET.to_string(),
+        ShutdownHandle::without_process(Arc::new(Config::default())),
+        StatusMonitor::new(10),
+    )
+}
+
+pub fn msg(addr: &str, args: Vec<OscType>) -> OscMessage {
+    OscMessage {
+        addr: addr.to_string(),
+        args,
+    }
+}
+
+pub fn string(value: &str) -> OscType {
+    OscType::String(value.to_string())
+}
+
+pub fn bundle(content: Vec<OscPacket>) -> OscPacket {
+    OscPacket::Bundle(OscBundle {
+        timetag: OscTime::from((0, 1)),
+        content,
+    })
+}
+
+pub fn tagged(tag: &str, mut content: Vec<OscPacket>) -> OscPacket {
+    content.insert(
+        0,
+        OscPacket::Message(msg("/bundle_info", vec![string(tag)])),
+    );
+    bundle(content)
+}
@@ -0,0 +1,61 @@
+use jdw_sc::config::Config;
+
+#[test]
+fn default_config_is_valid() {
+    assert_eq!(Config::default().validate(), Ok(()));
+}
+
+#[test]
+fn cpu_thresholds_are_percentages() {
+    let config = Config {
+        status_alert_avg_cpu: 100.0,
+        status_alert_peak_cpu: 120.0,
+        shed_peak_cpu: -1.0,
+        shed_avg_cpu: 50.0,
+        ..Config::default()
+    };
+
+    let errors = config.validate().unwrap_err();
+
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+    assert!(errors[0].starts_with("status_alert_peak_cpu must be between 0 and 100"));
+    assert!(errors[1].starts_with("shed_peak_cpu must be between 0 and 100"));
+}
+
+#[test]
+fn unknown_modes_are_rejected() {
+    let config = Config {
+        boot_mode: "scsyth".to_string(),
+        arg_validation: "strict".to_string(),
+        ..Config::default()
+    };
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            "boot_mode must be sclang or scsynth, was scsyth".to_string(),
+            "arg_validation must be off, warn, strip or reject, was strict".to_string(),
+        ])
+    );
+
+    let config = Config {
+        boot_mode: "SCSynth".to_string(),
+        arg_validation: "Reject".to_string(),
+        ..Config::default()
+    };
+
+    assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+fn default_bpm_must_be_positive() {
+    let config = Config {
+        default_bpm: 0,
+        ..Config::default()
+    };
+
+    assert_eq!(
+        config.validate(),
+        Err(vec!["default_bpm must be above 0, was 0".to_string()])
+    );
+}
@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use jdw_sc::ctl::{self, Command};
+use rosc::{OscPacket, OscType};
+
+fn command(line: &str) -> Command {
+    Command::parse(line.split_whitespace())
+}
+
+fn score_file(test_name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jdw-sc-test-{}-ctl", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join(format!("{}.score", test_name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn addr(packet: &OscPacket) -> &str {
+    match packet {
+        OscPacket::Message(msg) => &msg.addr,
+        OscPacket::Bundle(_) => panic!("Expected a message"),
+    }
+}
+
+#[test]
+fn flags_are_split_from_words() {
+    let command = command("note example lead_1 --gate=0.5 freq 440 --delay=20");
+
+    assert_eq!(
+        command.words,
+        vec!["note", "example", "lead_1", "freq", "440"]
+    );
+    assert_eq!(command.flag::<String>("gate"), Ok(Some("0.5".to_string())));
+    assert_eq!(command.delay_ms(), Ok(20));
+    assert_eq!(command.flag::<u64>("timeout"), Ok(None));
+    assert!(command.flag::<u64>("gate").is_err());
+}
+
+#[test]
+fn named_args_parse_numbers_as_floats() {
+    let command = command("modify lead_.* freq 440 pan -0.5 wave saw");
+
+    assert_eq!(
+        command.named_args(2),
+        Ok(vec![
+            OscType::String("freq".to_string()),
+            OscType::Float(440.0),
+            OscType::String("pan".to_string()),
+            OscType::Float(-0.5),
+            OscType::String("wave".to_string()),
+            OscType::String("saw".to_string()),
+        ])
+    );
+
+    assert_eq!(command.named_args(8), Ok(vec![]));
+}
+
+#[test]
+fn named_args_need_a_value_each() {
+    assert_eq!(
+        command("modify lead_.* freq 440 amp").named_args(2),
+        Err("Arg amp has no value".to_string())
+    );
+}
+
+#[test]
+fn score_rows_wait_the_given_beats() {
+    let path = score_file(
+        "rows",
+        "# beats  command\n\
+         1.0      note example nrt_1 --gate=0.5 freq 130\n\
+         \n\
+         0.25     play-sample drum_1 example 0 amp 1.0\n\
+         0.5      modify nrt_.* freq 110\n\
+         0        free nrt_.*\n",
+    );
+
+    let (rows, total) = ctl::read_score(&path.to_string_lossy()).unwrap();
+
+    let times: Vec<BigDecimal> = rows.iter().map(|row| row.time.clone()).collect();
+    let addrs: Vec<&str> = rows.iter().map(|row| addr(&row.packet)).collect();
+
+    assert_eq!(
+        times,
+        vec![
+            BigDecimal::from_str("1.0").unwrap(),
+            BigDecimal::from_str("0.25").unwrap(),
+            BigDecimal::from_str("0.5").unwrap(),
+            BigDecimal::from(0),
+        ]
+    );
+    assert_eq!(
+        addrs,
+        vec![
+            "/note_on_timed",
+            "/play_sample",
+            "/note_modify",
+            "/free_notes"
+        ]
+    );
+    assert_eq!(total, BigDecimal::from_str("1.75").unwrap());
+}
+
+#[test]
+fn score_errors_name_the_line() {
+    let path = score_file("errors", "# beats  command\n1.0 note example\n");
+    let err = ctl::read_score(&path.to_string_lossy()).unwrap_err();
+    assert!(err.ends_with("line 2: Missing <external id>"), "{}", err);
+
+    let path = score_file("no_wait", "note example nrt_1\n");
+    let err = ctl::read_score(&path.to_string_lossy()).unwrap_err();
+    assert!(
+        err.ends_with("line 1: Expected the beats to wait first"),
+        "{}",
+        err
+    );
+
+    let path = score_file("status", "1.0 status\n");
+    let err = ctl::read_score(&path.to_string_lossy()).unwrap_err();
+    assert!(err.contains("line 1: Unknown command: status"), "{}", err);
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jdw_sc::config::Config;
+use jdw_sc::dry_run::{self, DryRunFormat};
+use jdw_sc::shutdown::EXIT_CLEAN;
+use jdw_sc::JdwSc;
+use rosc::{OscMessage, OscPacket, OscTime, OscType};
+
+use common::msg;
+
+fn execute_at() -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)
+}
+
+fn s_new() -> OscMessage {
+    msg(
+        "/s_new",
+        vec![
+            OscType::String("default".to_string()),
+            OscType::Int(1000),
+            OscType::Int(0),
+            OscType::Int(100),
+            OscType::String("freq".to_string()),
+            OscType::Float(440.0),
+        ],
+    )
+}
+
+#[test]
+fn formats_format_names() {
+    assert_eq!("json".parse(), Ok(DryRunFormat::Json));
+    assert_eq!("readable".parse(), Ok(DryRunFormat::Readable));
+    assert!("yaml".parse::<DryRunFormat>().is_err());
+}
+
+#[test]
+fn node_ids_of_server_commands() {
+    assert_eq!(dry_run::node_ids(&s_new()), vec![1000]);
+    assert_eq!(
+        dry_run::node_ids(&msg(
+            "/n_set",
+            vec![
+                OscType::Int(1001),
+                OscType::String("gate".to_string()),
+                OscType::Float(0.0)
+            ]
+        )),
+        vec![1001]
+    );
+    assert_eq!(
+        dry_run::node_ids(&msg(
+            "/g_new",
+            vec![
+                OscType::Int(100),
+                OscType::Int(0),
+                OscType::Int(0),
+                OscType::Int(101),
+                OscType::Int(1),
+                OscType::Int(100)
+            ]
+        )),
+        vec![100, 101]
+    );
+    assert!(dry_run::node_ids(&msg("/b_free", vec![OscType::Int(3)])).is_empty());
+}
+
+#[test]
+fn readable_scsynth_line() {
+    let timetag = OscTime::try_from(execute_at()).unwrap();
+
+    assert_eq!(
+        dry_run::format_scsynth(DryRunFormat::Readable, &s_new(), timetag, execute_at(), 250),
+        "scsynth @1700000000.250 (+250ms, timetag 3908988800.1073741824) /s_new \"default\" 1000 0 100 \"freq\" 440 [nodes 1000]"
+    );
+}
+
+#[test]
+fn json_scsynth_line() {
+    let timetag = OscTime::try_from(execute_at()).unwrap();
+    let line = dry_run::format_scsynth(DryRunFormat::Json, &s_new(), timetag, execute_at(), 250);
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+    assert_eq!(value["target"], "scsynth");
+    assert_eq!(value["time"], 1_700_000_000.25);
+    assert_eq!(value["delay_ms"], 250);
+    assert_eq!(
+        value["timetag"],
+        serde_json::json!([3_908_988_800u32, 1_073_741_824u32])
+    );
+    assert_eq!(value["addr"], "/s_new");
+    assert_eq!(
+        value["args"],
+        serde_json::json!(["default", 1000, 0, 100, "freq", 440.0])
+    );
+    assert_eq!(value["nodes"], serde_json::json!([1000]));
+}
+
+#[test]
+fn json_sclang_line() {
+    let line = dry_run::format_sclang(
+        DryRunFormat::Json,
+        &msg("/read_scd", vec![OscType::String("s.quit;".to_string())]),
+    );
+
+    assert_eq!(
+        line,
+        r#"{"addr":"/read_scd","args":["s.quit;"],"target":"sclang"}"#
+    );
+}
+
+#[test]
+fn runs_without_supercollider() {
+    let config = Config {
+        application_in_port: 47400,
+        outgoing_port: 47401,
+        ..Config::default()
+    };
+
+    let mut sc = JdwSc::builder(config)
+        .dry_run(Some(DryRunFormat::Readable))
+        .build();
+    let events = sc.events().unwrap();
+    sc.start().unwrap();
+
+    sc.send(OscPacket::Message(msg("/set_bpm", vec![OscType::Int(90)])))
+        .unwrap();
+    sc.send(OscPacket::Message(msg("/get_shed_stats", vec![])))
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let mut replied = false;
+    while !replied && start.elapsed() < Duration::from_secs(10) {
+        if let Ok(event) = events.recv_timeout(Duration::from_millis(100)) {
+            replied = event.addr == "/get_shed_stats_reply";
+        }
+    }
+    assert!(replied);
+
+    assert_eq!(sc.shutdown().unwrap(), EXIT_CLEAN);
+}
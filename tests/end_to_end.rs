@@ -0,0 +1,361 @@
+/*
+   Runs the whole daemon against the jdw-sc-fake-server binary in place of sclang/scsynth.
+   Each test gets its own ports and state_dir, so they can run in parallel.
+*/
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use jdw_sc::config::Config;
+use jdw_sc::shutdown::EXIT_CLEAN;
+use jdw_sc::JdwSc;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+
+use common::{bundle, msg, string, tagged};
+
+const FAKE_SERVER: &str = env!("CARGO_BIN_EXE_jdw-sc-fake-server");
+const CTL: &str = env!("CARGO_BIN_EXE_jdw-sc-ctl");
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_PORTS: AtomicI32 = AtomicI32::new(0);
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("jdw-sc-e2e-{}", std::process::id()))
+}
+
+// Config with its own ports, booting the fake server as sclang or as scsynth
+fn config(boot_mode: &str) -> Config {
+    let base = 47100 + NEXT_PORTS.fetch_add(1, Ordering::SeqCst) * 10;
+
+    Config {
+        application_in_port: base,
+        outgoing_port: base + 1,
+        server_in_port: base + 2,
+        server_out_port: base + 3,
+        sclang_in_port: base + 4,
+        sclang_binary: FAKE_SERVER.to_string(),
+        scsynth_binary: FAKE_SERVER.to_string(),
+        boot_mode: boot_mode.to_string(),
+        // Keeps the pidfile, boot script and synthdef cache out of the real home directory
+        state_dir: temp_dir()
+            .join(format!("state-{}", base))
+            .to_string_lossy()
+            .to_string(),
+        sc_server_incoming_read_timeout: 1,
+        status_poll_interval_ms: 100,
+        shutdown_timeout_secs: 2,
+        ..Config::default()
+    }
+}
+
+struct Harness {
+    sc: JdwSc,
+    events: Receiver<OscMessage>,
+    config: Config,
+}
+
+impl Harness {
+    fn start(config: Config) -> Harness {
+        let mut sc = JdwSc::builder(config.clone()).build();
+        let events = sc.events().unwrap();
+        sc.start().unwrap();
+
+        Harness { sc, events, config }
+    }
+
+    fn send(&self, packet: OscPacket) {
+        self.sc.send(packet).unwrap();
+    }
+
+    fn send_msg(&self, addr: &str, args: Vec<OscType>) {
+        self.send(OscPacket::Message(msg(addr, args)));
+    }
+
+    // Next event with the address, skipping any others
+    fn expect(&self, addr: &str) -> OscMessage {
+        let start = Instant::now();
+
+        while start.elapsed() < TIMEOUT {
+            if let Ok(event) = self.events.recv_timeout(Duration::from_millis(100)) {
+                if event.addr == addr {
+                    return event;
+                }
+            }
+        }
+
+        panic!("No {} received", addr);
+    }
+
+    // Socket registered for node notifications directly with the (fake) server
+    fn notifications(&self) -> UdpSocket {
+        let socket = UdpSocket::bind(self.config.addr(0)).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        let buf =
+            encoder::encode(&OscPacket::Message(msg("/notify", vec![OscType::Int(1)]))).unwrap();
+        socket
+            .send_to(&buf, self.config.addr(self.config.server_in_port))
+            .unwrap();
+
+        assert_eq!(receive(&socket).addr, "/done");
+        socket
+    }
+
+    fn shutdown(mut self) -> i32 {
+        self.sc.shutdown().unwrap()
+    }
+}
+
+fn receive(socket: &UdpSocket) -> OscMessage {
+    let mut buf = [0u8; rosc::decoder::MTU];
+    let (size, _) = socket.recv_from(&mut buf).unwrap();
+
+    match rosc::decoder::decode_udp(&buf[..size]).unwrap().1 {
+        OscPacket::Message(message) => message,
+        OscPacket::Bundle(_) => panic!("Unexpected bundle"),
+    }
+}
+
+// Run jdw-sc-ctl against the harness, with its ports in a central config
+fn ctl(config: &Config, args: &[&str]) -> Output {
+    let central = temp_dir().join(format!("jdw-{}.toml", config.application_in_port));
+    std::fs::create_dir_all(temp_dir()).unwrap();
+    std::fs::write(
+        &central,
+        format!(
+            "[sc]\napplication_in_port = {}\noutgoing_port = {}\n",
+            config.application_in_port, config.outgoing_port
+        ),
+    )
+    .unwrap();
+
+    Command::new(CTL)
+        .env("JDW_CONFIG", &central)
+        .arg("--config=")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn boots_with_sclang_and_shuts_down_cleanly() {
+    let harness = Harness::start(config("sclang"));
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn boots_without_sclang_and_shuts_down_cleanly() {
+    let harness = Harness::start(config("scsynth"));
+
+    harness.expect("/jdw_sc_status");
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn taken_in_port_fails_start() {
+    let config = config("scsynth");
+    let _taken = UdpSocket::bind(config.addr(config.application_in_port)).unwrap();
+
+    let mut sc = JdwSc::builder(config).build();
+    let err = sc.start().unwrap_err();
+
+    assert!(err.contains("application_in_port"), "{}", err);
+    assert!(!sc.is_running());
+}
+
+#[test]
+fn created_synthdef_plays_notes() {
+    let harness = Harness::start(config("sclang"));
+    let notifications = harness.notifications();
+
+    harness.send_msg(
+        "/create_synthdef",
+        vec![string(
+            "SynthDef(\"ping\", { |freq = 440, amp = 0.5, gate = 1| Out.ar(0, SinOsc.ar(freq) * amp) })",
+        )],
+    );
+
+    assert_eq!(
+        harness.expect("/create_synthdef_finished").args,
+        vec![string("SUCCESS"), string("ping"), string("")]
+    );
+
+    harness.send_msg(
+        "/note_on_timed",
+        vec![
+            string("ping"),
+            string("ping_1"),
+            string("0.25"),
+            OscType::Int(0),
+            string("freq"),
+            OscType::Float(330.0),
+        ],
+    );
+
+    let started = receive(&notifications);
+    assert_eq!(started.addr, "/n_go");
+
+    // Released by the gate off of the timed note
+    let ended = receive(&notifications);
+    assert_eq!(ended.addr, "/n_end");
+    assert_eq!(ended.args[0], started.args[0]);
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn nrt_record_renders_wav() {
+    let harness = Harness::start(config("sclang"));
+
+    let file_name = temp_dir()
+        .join("nrt_record_renders_wav.wav")
+        .to_string_lossy()
+        .to_string();
+
+    let note = tagged(
+        "timed_msg",
+        vec![
+            OscPacket::Message(msg("/timed_msg_info", vec![string("0.5")])),
+            OscPacket::Message(msg(
+                "/note_on_timed",
+                vec![
+                    string("default"),
+                    string("nrt_1"),
+                    string("0.25"),
+                    OscType::Int(0),
+                ],
+            )),
+        ],
+    );
+
+    harness.send(tagged(
+        "nrt_record",
+        vec![
+            OscPacket::Message(msg(
+                "/nrt_record_info",
+                vec![
+                    OscType::Float(120.0),
+                    string(&file_name),
+                    OscType::Float(1.0),
+                ],
+            )),
+            bundle(vec![note]),
+        ],
+    ));
+
+    assert_eq!(
+        harness.expect("/nrt_record_finished").args,
+        vec![string("SUCCESS"), string(&file_name)]
+    );
+
+    let wav = std::fs::read(&file_name).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn status_is_polled_from_server() {
+    let harness = Harness::start(config("sclang"));
+
+    harness.expect("/jdw_sc_status");
+    harness.send_msg("/get_status", vec![]);
+
+    let reply = harness.expect("/get_status_reply");
+    assert_eq!(reply.args[0], string("ok"));
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn ctl_renders_score_file() {
+    let harness = Harness::start(config("sclang"));
+
+    let score = temp_dir().join("ctl_renders_score_file.score");
+    let file_name = temp_dir()
+        .join("ctl_renders_score_file.wav")
+        .to_string_lossy()
+        .to_string();
+
+    std::fs::write(
+        &score,
+        "# beats  command\n0.5 note default ctl_1 --gate=0.25 freq 330\n",
+    )
+    .unwrap();
+
+    // ctl waits on a port of its own, next to whatever listens on outgoing_port
+    let _sequencer = UdpSocket::bind(harness.config.addr(harness.config.outgoing_port)).unwrap();
+
+    let output = ctl(
+        &harness.config,
+        &["nrt-render", &score.to_string_lossy(), &file_name],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("/nrt_record_finished \"SUCCESS\""),
+        "{}",
+        stdout
+    );
+
+    let wav = std::fs::read(&file_name).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
+
+#[test]
+fn json_nrt_record_renders_wav() {
+    let config = config("sclang");
+    let harness = Harness::start(Config {
+        json_port: config.application_in_port + 5,
+        ..config
+    });
+
+    let file_name = temp_dir()
+        .join("json_nrt_record_renders_wav.wav")
+        .to_string_lossy()
+        .to_string();
+
+    let mut client = TcpStream::connect(harness.config.addr(harness.config.json_port)).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+
+    let command = serde_json::json!({
+        "command": "nrt_record",
+        "file_name": file_name,
+        "bpm": 120.0,
+        "end_beat": 1.0,
+        "messages": [{
+            "time": 0.5,
+            "packet": { "addr": "/note_on_timed", "args": ["default", "json_1", "0.25", 0] },
+        }],
+    });
+    writeln!(client, "{}", command).unwrap();
+
+    // Other events, e.g. status reports, are streamed as well
+    let reply = loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        if event["addr"] == "/nrt_record_finished" {
+            break event;
+        }
+    };
+
+    assert_eq!(reply["args"], serde_json::json!(["SUCCESS", file_name]));
+    assert_eq!(&std::fs::read(&file_name).unwrap()[0..4], b"RIFF");
+
+    assert_eq!(harness.shutdown(), EXIT_CLEAN);
+}
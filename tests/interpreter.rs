@@ -0,0 +1,767 @@
+mod common;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jdw_sc::config::Config;
+use jdw_sc::osc_daemon::Interpreter;
+use jdw_sc::sc_backend::{RecordingBackend, ScBackend};
+use jdw_sc::shutdown::EXIT_CLEAN;
+use jdw_sc::synthdef_builder::{Rate, SynthDefBuilder};
+use jdw_sc::synthdef_cache;
+use rosc::{OscMessage, OscPacket, OscTime, OscType};
+
+use common::{bundle, interpreter, msg, string, tagged};
+
+fn received() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+fn at(ms: u64) -> OscTime {
+    OscTime::try_from(received() + Duration::from_millis(ms)).unwrap()
+}
+
+fn send(interpreter: &mut Interpreter<RecordingBackend>, message: OscMessage) {
+    interpreter.interpret(OscPacket::Message(message), received());
+}
+
+fn note_on(external_id: &str, delay_ms: i32) -> OscMessage {
+    msg(
+        "/note_on",
+        vec![
+            string("default"),
+            string(external_id),
+            OscType::Int(delay_ms),
+            string("freq"),
+            OscType::Float(440.0),
+        ],
+    )
+}
+
+fn note_on_timed(external_id: &str, gate_time: &str, delay_ms: i32) -> OscMessage {
+    msg(
+        "/note_on_timed",
+        vec![
+            string("default"),
+            string(external_id),
+            string(gate_time),
+            OscType::Int(delay_ms),
+            string("freq"),
+            OscType::Float(220.0),
+        ],
+    )
+}
+
+fn load_sample(buffer_number: i32) -> OscMessage {
+    msg(
+        "/load_sample",
+        vec![
+            string("/samples/hat.wav"),
+            string("drums"),
+            OscType::Int(buffer_number),
+            string(""),
+            OscType::Int(0),
+        ],
+    )
+}
+
+// Messages sorted by node id (first arg), for nodes found through a regex in no particular order
+fn by_node_id(mut messages: Vec<(OscTime, OscMessage)>) -> Vec<(OscTime, OscMessage)> {
+    messages.sort_by_key(|(_, message)| match message.args.get(0) {
+        Some(OscType::Int(node_id)) => *node_id,
+        _ => 0,
+    });
+    messages
+}
+
+#[test]
+fn note_on_creates_synth_at_delay() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, note_on("lead_1", 20));
+
+    assert_eq!(
+        backend.scsynth_messages(),
+        vec![(
+            at(20),
+            msg(
+                "/s_new",
+                vec![
+                    string("default"),
+                    OscType::Int(101),
+                    OscType::Int(0),
+                    OscType::Int(0),
+                    string("freq"),
+                    OscType::Float(440.0),
+                ],
+            ),
+        )]
+    );
+}
+
+#[test]
+fn note_on_timed_releases_gate_after_gate_time() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    // Half a beat at the default 120 bpm
+    send(&mut interpreter, note_on_timed("lead_1", "0.5", 10));
+
+    assert_eq!(
+        backend.scsynth_messages(),
+        vec![
+            (
+                at(10),
+                msg(
+                    "/s_new",
+                    vec![
+                        string("default"),
+                        OscType::Int(101),
+                        OscType::Int(0),
+                        OscType::Int(0),
+                        string("freq"),
+                        OscType::Float(220.0),
+                    ],
+                ),
+            ),
+            (
+                at(260),
+                msg(
+                    "/n_set",
+                    vec![OscType::Int(101), string("gate"), OscType::Float(0.0)],
+                ),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn set_bpm_changes_gate_time() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, msg("/set_bpm", vec![OscType::Int(60)]));
+    send(&mut interpreter, note_on_timed("lead_1", "0.5", 0));
+
+    let messages = backend.scsynth_messages();
+
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].0, at(500));
+    assert_eq!(messages[1].1.addr, "/n_set");
+}
+
+#[test]
+fn note_modify_sets_args_on_matching_nodes() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, note_on("drum_1", 0));
+    send(&mut interpreter, note_on("lead_1", 0));
+    send(&mut interpreter, note_on("drum_2", 0));
+    backend.clear();
+
+    send(
+        &mut interpreter,
+        msg(
+            "/note_modify",
+            vec![
+                string("drum_.*"),
+                OscType::Int(5),
+                string("amp"),
+                OscType::Float(0.5),
+            ],
+        ),
+    );
+
+    assert_eq!(
+        by_node_id(backend.scsynth_messages()),
+        vec![
+            (
+                at(5),
+                msg(
+                    "/n_set",
+                    vec![OscType::Int(101), string("amp"), OscType::Float(0.5)],
+                ),
+            ),
+            (
+                at(5),
+                msg(
+                    "/n_set",
+                    vec![OscType::Int(103), string("amp"), OscType::Float(0.5)],
+                ),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn free_notes_frees_matching_nodes_once() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, note_on("drum_1", 0));
+    send(&mut interpreter, note_on("lead_1", 0));
+    send(&mut interpreter, note_on("drum_2", 0));
+    backend.clear();
+
+    send(
+        &mut interpreter,
+        msg("/free_notes", vec![string("drum_.*")]),
+    );
+
+    assert_eq!(
+        by_node_id(backend.scsynth_messages()),
+        vec![
+            (at(0), msg("/n_free", vec![OscType::Int(101)])),
+            (at(0), msg("/n_free", vec![OscType::Int(103)])),
+        ]
+    );
+
+    backend.clear();
+    send(
+        &mut interpreter,
+        msg("/free_notes", vec![string("drum_.*")]),
+    );
+
+    assert!(backend.scsynth_messages().is_empty());
+}
+
+#[test]
+fn notes_matching_group_are_created_in_it() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(
+        &mut interpreter,
+        msg(
+            "/create_group",
+            vec![string("drums"), string("drum_.*"), OscType::Int(0)],
+        ),
+    );
+    send(&mut interpreter, note_on("drum_1", 0));
+    send(&mut interpreter, note_on("lead_1", 0));
+
+    let messages: Vec<OscMessage> = backend
+        .scsynth_messages()
+        .into_iter()
+        .map(|(_, message)| message)
+        .collect();
+
+    assert_eq!(
+        messages,
+        vec![
+            msg(
+                "/g_new",
+                vec![OscType::Int(101), OscType::Int(1), OscType::Int(0)],
+            ),
+            msg(
+                "/s_new",
+                vec![
+                    string("default"),
+                    OscType::Int(102),
+                    OscType::Int(101),
+                    OscType::Int(0),
+                    string("freq"),
+                    OscType::Float(440.0),
+                ],
+            ),
+            msg(
+                "/s_new",
+                vec![
+                    string("default"),
+                    OscType::Int(103),
+                    OscType::Int(0),
+                    OscType::Int(0),
+                    string("freq"),
+                    OscType::Float(440.0),
+                ],
+            ),
+        ]
+    );
+}
+
+#[test]
+fn freed_group_forgets_its_notes() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(
+        &mut interpreter,
+        msg("/create_group", vec![string("drums"), string("drum_.*")]),
+    );
+    send(&mut interpreter, note_on("drum_1", 0));
+    send(&mut interpreter, note_on("lead_1", 0));
+    send(&mut interpreter, msg("/free_group", vec![string("drums")]));
+    backend.clear();
+
+    send(&mut interpreter, msg("/free_notes", vec![string(".*")]));
+
+    assert_eq!(
+        backend.scsynth_messages(),
+        vec![(at(0), msg("/n_free", vec![OscType::Int(103)]))]
+    );
+}
+
+#[test]
+fn played_sample_uses_loaded_buffer() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, load_sample(3));
+
+    assert_eq!(
+        backend.sclang_messages(),
+        vec![msg(
+            "/read_scd",
+            vec![string(
+                "Buffer.read(s, \"/samples/hat.wav\", 0, -1, bufnum: 3); \n"
+            )],
+        )]
+    );
+
+    send(
+        &mut interpreter,
+        msg(
+            "/play_sample",
+            vec![
+                string("hat_1"),
+                string("drums"),
+                OscType::Int(0),
+                string(""),
+                OscType::Int(15),
+                string("amp"),
+                OscType::Float(1.0),
+            ],
+        ),
+    );
+
+    assert_eq!(
+        backend.scsynth_messages(),
+        vec![(
+            at(15),
+            msg(
+                "/s_new",
+                vec![
+                    string("sampler"),
+                    OscType::Int(101),
+                    OscType::Int(0),
+                    OscType::Int(0),
+                    string("amp"),
+                    OscType::Float(1.0),
+                    string("buf"),
+                    OscType::Int(3),
+                ],
+            ),
+        )]
+    );
+}
+
+#[test]
+fn sampler_is_built_in_without_sclang() {
+    let backend = RecordingBackend::new(Config::default()).without_sclang();
+    backend.push_incoming(msg("/done", vec![string("/d_recv")]));
+    let mut interpreter = interpreter(&backend);
+
+    interpreter.load_sampler();
+    interpreter.handle_replies();
+
+    let sent = backend.scsynth_messages();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1.addr, "/d_recv");
+    assert!(backend.sclang_messages().is_empty());
+    assert_eq!(interpreter.pending_requests(), 0);
+}
+
+const PING_SNIPPET: &str = "SynthDef(\"ping\", { |freq = 440| Out.ar(0, SinOsc.ar(freq)) })";
+
+fn ping_bytes() -> Vec<u8> {
+    let mut def = SynthDefBuilder::new("ping");
+    let freq = def.control("freq", 440.0);
+    let osc = def.sin_osc(Rate::Audio, freq, 0.0);
+    def.out(0.0, &[osc]);
+    def.encode()
+}
+
+fn state_dir_config(test_name: &str) -> Config {
+    let mut config = Config::default();
+    config.state_dir = std::env::temp_dir()
+        .join(format!("jdw-sc-test-{}-{}", std::process::id(), test_name))
+        .to_string_lossy()
+        .to_string();
+    config
+}
+
+#[test]
+fn create_synthdef_finishes_once_sclang_and_scsynth_replied() {
+    let config = state_dir_config("create-synthdef");
+    let backend = RecordingBackend::new(config.clone());
+    let mut interpreter = interpreter(&backend);
+
+    send(
+        &mut interpreter,
+        msg("/create_synthdef", vec![string(PING_SNIPPET)]),
+    );
+
+    let sclang = backend.sclang_messages();
+    assert_eq!(sclang.len(), 1);
+    assert_eq!(sclang[0].addr, "/read_scd");
+    assert_eq!(sclang[0].args[1], string("jdw_sc_1"));
+    assert!(backend.out_messages().is_empty());
+
+    // Other messages are handled while sclang compiles
+    send(&mut interpreter, note_on("lead_1", 0));
+    assert!(!backend.scsynth_messages().is_empty());
+    backend.clear();
+
+    // As written by cache_synthdef_scd
+    let path = synthdef_cache::cache_path(&config, PING_SNIPPET).unwrap();
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, ping_bytes()).unwrap();
+
+    backend.push_incoming(msg(
+        "/read_scd_done",
+        vec![string("jdw_sc_1"), string("ok"), string("")],
+    ));
+    interpreter.handle_replies();
+
+    let sent = backend.scsynth_messages();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1.addr, "/d_load");
+    assert!(backend.out_messages().is_empty());
+
+    backend.push_incoming(msg("/done", vec![string("/d_load")]));
+    interpreter.handle_replies();
+
+    assert_eq!(
+        backend.out_messages(),
+        vec![msg(
+            "/create_synthdef_finished",
+            vec![string("SUCCESS"), string("ping"), string("")]
+        )]
+    );
+    assert_eq!(interpreter.pending_requests(), 0);
+
+    fs::remove_dir_all(config.state_path().unwrap()).ok();
+}
+
+#[test]
+fn create_synthdef_reports_sclang_error() {
+    let config = state_dir_config("create-synthdef-error");
+    let backend = RecordingBackend::new(config.clone());
+    let mut interpreter = interpreter(&backend);
+
+    send(
+        &mut interpreter,
+        msg("/create_synthdef", vec![string(PING_SNIPPET)]),
+    );
+    backend.push_incoming(msg(
+        "/read_scd_done",
+        vec![string("jdw_sc_1"), string("error"), string("Parse error")],
+    ));
+    interpreter.handle_replies();
+
+    assert_eq!(
+        backend.out_messages(),
+        vec![msg(
+            "/create_synthdef_finished",
+            vec![string("FAILURE"), string("ping"), string("Parse error")]
+        )]
+    );
+    assert!(backend.scsynth_messages().is_empty());
+
+    fs::remove_dir_all(config.state_path().unwrap()).ok();
+}
+
+#[test]
+fn read_scd_reply_is_passed_on_when_sclang_replies() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(
+        &mut interpreter,
+        msg("/read_scd", vec![string("s.boot"), string("boot")]),
+    );
+    assert!(backend.out_messages().is_empty());
+
+    backend.push_incoming(msg(
+        "/read_scd_done",
+        vec![string("unrelated"), string("ok"), string("")],
+    ));
+    backend.push_incoming(msg(
+        "/read_scd_done",
+        vec![string("boot"), string("ok"), string("")],
+    ));
+    interpreter.handle_replies();
+
+    assert_eq!(
+        backend.out_messages(),
+        vec![msg(
+            "/read_scd_done",
+            vec![string("boot"), string("ok"), string("")]
+        )]
+    );
+}
+
+#[test]
+fn samples_are_read_by_scsynth_without_sclang() {
+    let backend = RecordingBackend::new(Config::default()).without_sclang();
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, load_sample(3));
+
+    assert!(backend.sclang_messages().is_empty());
+    assert_eq!(
+        backend.scsynth_messages(),
+        vec![(
+            at(0),
+            msg(
+                "/b_allocRead",
+                vec![
+                    OscType::Int(3),
+                    string("/samples/hat.wav"),
+                    OscType::Int(0),
+                    OscType::Int(-1),
+                ],
+            ),
+        )]
+    );
+}
+
+#[test]
+fn real_time_packet_is_timed_from_its_send_time() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    let sent = received() - Duration::from_millis(40);
+
+    interpreter.interpret(
+        tagged(
+            "real_time_packet",
+            vec![
+                OscPacket::Message(msg(
+                    "/real_time_packet_info",
+                    vec![OscType::Time(OscTime::try_from(sent).unwrap())],
+                )),
+                OscPacket::Message(note_on("lead_1", 100)),
+            ],
+        ),
+        received(),
+    );
+
+    let messages = backend.scsynth_messages();
+
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].0, at(60));
+}
+
+fn timed(time: &str, message: OscMessage) -> OscPacket {
+    tagged(
+        "timed_msg",
+        vec![
+            OscPacket::Message(msg("/timed_msg_info", vec![string(time)])),
+            OscPacket::Message(message),
+        ],
+    )
+}
+
+fn nrt_record(file_name: &str, rows: Vec<OscPacket>) -> OscPacket {
+    tagged(
+        "nrt_record",
+        vec![
+            OscPacket::Message(msg(
+                "/nrt_record_info",
+                vec![
+                    OscType::Float(120.0),
+                    string(file_name),
+                    OscType::Float(4.0),
+                ],
+            )),
+            bundle(rows),
+        ],
+    )
+}
+
+fn nrt_file_name(test_name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("jdw-sc-test-{}-{}", std::process::id(), test_name))
+        .join("score.wav")
+        .to_string_lossy()
+        .to_string()
+}
+
+#[test]
+fn nrt_record_writes_score_and_reports_success() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, load_sample(3));
+    backend.push_incoming(msg("/nrt_done", vec![string("ok")]));
+
+    let file_name = nrt_file_name("success");
+
+    interpreter.interpret(
+        nrt_record(
+            &file_name,
+            vec![
+                timed("1.0", note_on("lead_1", 0)),
+                timed(
+                    "0.5",
+                    msg(
+                        "/play_sample",
+                        vec![
+                            string("hat_1"),
+                            string("drums"),
+                            OscType::Int(0),
+                            string(""),
+                            OscType::Int(0),
+                        ],
+                    ),
+                ),
+            ],
+        ),
+        received(),
+    );
+
+    let script = fs::read_to_string(file_name.clone() + ".scd").unwrap();
+    fs::remove_dir_all(std::path::Path::new(&file_name).parent().unwrap()).ok();
+
+    // Sample buffers are read at the start of the score
+    assert!(script.contains(
+        "[0.0, (Buffer.new(server, 352800, 2, bufnum: 3)).allocReadMsg(\"/samples/hat.wav\")]"
+    ));
+    // Rows are placed by the accumulated time of the packets before them
+    assert!(script.contains("[ 0.00000, [\"/s_new\",\"default\",101,0,0,\"freq\",440.00000] ]"));
+    assert!(script.contains("[ 1.00000, [\"/s_new\",\"sampler\",102,0,0,\"buf\",3] ]"));
+
+    assert_eq!(
+        backend.sclang_messages().last(),
+        Some(&msg(
+            "/read_scd_file",
+            vec![string(&(file_name.clone() + ".scd"))],
+        ))
+    );
+    assert_eq!(
+        backend.out_messages(),
+        vec![msg(
+            "/nrt_record_finished",
+            vec![string("SUCCESS"), string(&file_name)],
+        )]
+    );
+}
+
+#[test]
+fn nrt_record_reports_failure_without_nrt_done() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    let file_name = nrt_file_name("failure");
+
+    interpreter.interpret(
+        nrt_record(&file_name, vec![timed("1.0", note_on("lead_1", 0))]),
+        received(),
+    );
+
+    fs::remove_dir_all(std::path::Path::new(&file_name).parent().unwrap()).ok();
+
+    assert_eq!(
+        backend.out_messages(),
+        vec![msg(
+            "/nrt_record_finished",
+            vec![string("FAILURE"), string(&file_name)],
+        )]
+    );
+}
+
+#[test]
+fn nrt_fade_of_gateless_synth_is_timed_in_beats() {
+    let mut config = Config::default();
+    config.gateless_fade_ms = 800;
+    let backend = RecordingBackend::new(config);
+    let mut interpreter = interpreter(&backend);
+
+    let mut def = SynthDefBuilder::new("drone");
+    let amp = def.control("amp", 0.5);
+    let osc = def.sin_osc(Rate::Audio, 220.0, 0.0);
+    let sig = def.mul(osc, amp);
+    def.out(0.0, &[sig]);
+
+    send(
+        &mut interpreter,
+        msg("/create_synthdef_bytes", vec![OscType::Blob(def.encode())]),
+    );
+    backend.push_incoming(msg("/done", vec![string("/d_recv")]));
+    interpreter.handle_replies();
+
+    backend.push_incoming(msg("/nrt_done", vec![string("ok")]));
+
+    let file_name = nrt_file_name("fade");
+    let drone = msg(
+        "/note_on_timed",
+        vec![
+            string("drone"),
+            string("drone_1"),
+            string("1.0"),
+            OscType::Int(0),
+        ],
+    );
+
+    interpreter.interpret(
+        nrt_record(&file_name, vec![timed("0.0", drone)]),
+        received(),
+    );
+
+    let script = fs::read_to_string(file_name.clone() + ".scd").unwrap();
+    fs::remove_dir_all(std::path::Path::new(&file_name).parent().unwrap()).ok();
+
+    // The 0.8 second fade starts after the one beat gate time and lasts 1.6 beats at 120 bpm
+    assert!(script.contains("[ 1.00000, [\"/n_set\",101,\"amp\",0.43750] ]"));
+    assert!(script.contains("[ 2.60000, [\"/n_free\",101] ]"));
+}
+
+#[test]
+fn quit_frees_nodes_and_buffers_then_quits_server() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    send(&mut interpreter, load_sample(3));
+    send(&mut interpreter, note_on("lead_1", 0));
+    backend.clear();
+
+    send(&mut interpreter, msg("/quit", vec![OscType::Int(0)]));
+
+    assert_eq!(interpreter.exit_code(), Some(EXIT_CLEAN));
+    assert_eq!(
+        backend.scsynth_messages().len(),
+        2,
+        "Expected /g_freeAll and /b_free"
+    );
+    assert_eq!(
+        backend.scsynth_messages()[1].1,
+        msg("/b_free", vec![OscType::Int(3)])
+    );
+    assert_eq!(
+        backend.sclang_messages(),
+        vec![msg("/read_scd", vec![string("s.quit;")])]
+    );
+}
+
+#[test]
+fn subscriptions_last_until_unsubscribed_or_expired() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    for (port, secs) in [(47990, 60), (47991, 60), (47990, 60), (47992, 0)] {
+        let subscribe = msg("/subscribe", vec![OscType::Int(port), OscType::Int(secs)]);
+        send(&mut interpreter, subscribe);
+    }
+
+    // Subscribing again renews rather than adds, and a lifetime of 0 has already run out
+    assert_eq!(backend.subscribers().ports(), vec![47991, 47990]);
+
+    let unsubscribe = msg("/unsubscribe", vec![OscType::Int(47990)]);
+    send(&mut interpreter, unsubscribe);
+
+    assert_eq!(backend.subscribers().ports(), vec![47991]);
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jdw_sc::config::Config;
+use jdw_sc::journal::{self, JournalWriter, Replay, ReplayTiming};
+use jdw_sc::sc_backend::RecordingBackend;
+use jdw_sc::shutdown::EXIT_CLEAN;
+use rosc::{encoder, OscMessage, OscPacket, OscType};
+
+use common::interpreter;
+
+fn journal_path(test_name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("jdw-sc-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join(format!("{}.jdwj", test_name));
+    fs::remove_file(&path).ok();
+    path.to_string_lossy().to_string()
+}
+
+fn received(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + ms)
+}
+
+fn message(addr: &str, args: Vec<OscType>) -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: addr.to_string(),
+        args,
+    })
+}
+
+fn note_on(external_id: &str) -> OscPacket {
+    message(
+        "/note_on",
+        vec![
+            OscType::String("default".to_string()),
+            OscType::String(external_id.to_string()),
+            OscType::Int(0),
+        ],
+    )
+}
+
+fn write_journal(path: &str, packets: &[(u64, OscPacket)]) {
+    let mut writer = JournalWriter::open(path).unwrap();
+
+    for (ms, packet) in packets {
+        writer
+            .append(received(*ms), &encoder::encode(packet).unwrap())
+            .unwrap();
+    }
+}
+
+#[test]
+fn parses_replay_timings() {
+    assert_eq!("original".parse(), Ok(ReplayTiming::Original));
+    assert_eq!("fast".parse(), Ok(ReplayTiming::Fast));
+    assert!("slow".parse::<ReplayTiming>().is_err());
+}
+
+#[test]
+fn reads_back_appended_packets() {
+    let path = journal_path("reads_back");
+    write_journal(&path, &[(0, note_on("lead_1"))]);
+    // Reopening appends instead of starting over
+    write_journal(&path, &[(250, note_on("lead_2"))]);
+
+    let entries = journal::read(&path).unwrap();
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].receive_time, received(0));
+    assert_eq!(entries[0].packet, note_on("lead_1"));
+    assert_eq!(entries[1].receive_time, received(250));
+    assert_eq!(entries[1].packet, note_on("lead_2"));
+}
+
+#[test]
+fn skips_truncated_last_record() {
+    let path = journal_path("truncated");
+    write_journal(&path, &[(0, note_on("lead_1"))]);
+
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 0, 1]).unwrap();
+
+    assert_eq!(journal::read(&path).unwrap().len(), 1);
+}
+
+#[test]
+fn rejects_files_that_are_not_journals() {
+    let path = journal_path("not_a_journal");
+    fs::write(&path, "freq = 440").unwrap();
+
+    assert!(journal::read(&path).is_err());
+}
+
+#[test]
+fn fast_replay_keeps_original_spacing() {
+    let path = journal_path("fast_replay");
+    write_journal(&path, &[(0, note_on("lead_1")), (500, note_on("lead_2"))]);
+
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    let replay = Replay::load(&path, ReplayTiming::Fast).unwrap();
+    assert_eq!(replay.run(&mut interpreter), None);
+
+    let times: Vec<SystemTime> = backend
+        .scsynth_messages()
+        .into_iter()
+        .map(|(time, _)| SystemTime::from(time))
+        .collect();
+
+    assert_eq!(times.len(), 2);
+    let spacing = times[1].duration_since(times[0]).unwrap();
+    assert!(
+        spacing.as_millis().abs_diff(500) <= 1,
+        "Expected 500ms between notes, got {:?}",
+        spacing
+    );
+}
+
+#[test]
+fn replayed_quit_stops_replay() {
+    let path = journal_path("replayed_quit");
+    write_journal(
+        &path,
+        &[
+            (0, message("/quit", vec![OscType::Int(0)])),
+            (100, note_on("lead_1")),
+        ],
+    );
+
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    let replay = Replay::load(&path, ReplayTiming::Fast).unwrap();
+
+    assert_eq!(replay.run(&mut interpreter), Some(EXIT_CLEAN));
+    assert!(backend
+        .scsynth_messages()
+        .iter()
+        .all(|(_, msg)| msg.addr != "/s_new"));
+}
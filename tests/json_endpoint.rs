@@ -0,0 +1,229 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use bigdecimal::BigDecimal;
+use jdw_osc_lib::model::TaggedBundle;
+use jdw_sc::config::Config;
+use jdw_sc::json_endpoint::{self, JsonCommand};
+use jdw_sc::osc_model::{NRTRecordMessage, NoteOnTimedMessage};
+use jdw_sc::sc_backend::RecordingBackend;
+use rosc::{OscMessage, OscPacket, OscType};
+use serde_json::Value;
+
+use common::{interpreter, string};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn read_json(reader: &mut BufReader<TcpStream>) -> Value {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[test]
+fn commands_are_interpreted() {
+    let command = JsonCommand::parse(
+        r#"{"command": "note_on_timed", "synth_name": "default", "external_id": "lead_1",
+            "gate_time": 0.5, "delay_ms": 0, "args": ["freq", 440.0]}"#,
+    )
+    .unwrap();
+
+    let backend = RecordingBackend::new(Config::default());
+    interpreter(&backend).interpret(command.to_osc(), SystemTime::now());
+
+    let started: Vec<OscMessage> = backend
+        .scsynth_messages()
+        .into_iter()
+        .map(|(_, msg)| msg)
+        .filter(|msg| msg.addr == "/s_new")
+        .collect();
+
+    assert_eq!(started.len(), 1);
+    assert_eq!(started[0].args[0], string("default"));
+    assert!(started[0].args.contains(&OscType::Float(440.0)));
+}
+
+#[test]
+fn json_fields_match_osc_model() {
+    let command = JsonCommand::parse(
+        r#"{"command": "note_on_timed", "synth_name": "default", "external_id": "lead_1",
+            "gate_time": "0.25", "delay_ms": 10}"#,
+    )
+    .unwrap();
+
+    let expected = NoteOnTimedMessage {
+        synth_name: "default".to_string(),
+        external_id: "lead_1".to_string(),
+        gate_time: BigDecimal::from_str("0.25").unwrap(),
+        delay_ms: 10,
+        args: vec![],
+    };
+
+    assert!(command == JsonCommand::NoteOnTimed(expected));
+}
+
+#[test]
+fn nrt_record_takes_json_timeline() {
+    let command = JsonCommand::parse(
+        r#"{"command": "nrt_record", "file_name": "out.wav", "bpm": 120.0, "end_beat": 4.0,
+            "messages": [
+                {"time": 1, "packet": {"addr": "/note_on_timed", "args": ["default", "n_1", "0.5", 0]}},
+                {"time": "0.5", "packet": {"addr": "/note_on", "args": ["default", "n_2", 0, "amp", 0.2]}}
+            ]}"#,
+    )
+    .unwrap();
+
+    let record = match command.to_osc() {
+        OscPacket::Bundle(bundle) => {
+            NRTRecordMessage::from_bundle(TaggedBundle::new(&bundle).unwrap()).unwrap()
+        }
+        OscPacket::Message(_) => panic!("Expected an nrt_record bundle"),
+    };
+
+    assert!(command == JsonCommand::NrtRecord(record.clone()));
+    assert_eq!(record.file_name, "out.wav");
+    assert_eq!(record.messages.len(), 2);
+    assert_eq!(record.messages[0].time, BigDecimal::from(1));
+}
+
+#[test]
+fn commands_serialize_to_what_they_parse_from() {
+    let lines = [
+        r#"{"command": "play_sample", "external_id": "drum_1", "sample_pack": "drums", "index": 2, "category": "bd", "delay_ms": 0, "args": ["amp", 1]}"#,
+        r#"{"command": "free_notes", "external_id_regex": "lead_.*"}"#,
+        r#"{"command": "real_time_packet", "time": 1700000000.5, "packet": {"addr": "/note_on", "args": ["default", "n_1", 0]}}"#,
+        r#"{"command": "batch_send", "packets": [{"addr": "/set_bpm", "args": [96]}]}"#,
+        r#"{"command": "osc", "addr": "/load_sample", "args": ["kick.wav", "drums", 100, "bd", 0]}"#,
+    ];
+
+    for line in lines {
+        let command = JsonCommand::parse(line).unwrap();
+        let json = serde_json::to_string(&command).unwrap();
+
+        assert!(JsonCommand::parse(&json).unwrap() == command, "{}", json);
+    }
+}
+
+#[test]
+fn rejects_unknown_commands() {
+    assert!(JsonCommand::parse(r#"{"command": "play_everything"}"#).is_err());
+    assert!(JsonCommand::parse(r#"{"command": "free_notes"}"#).is_err());
+    assert!(JsonCommand::parse("/free_notes lead_.*").is_err());
+}
+
+#[test]
+fn endpoint_passes_commands_on_and_streams_events() {
+    let config = Arc::new(Config {
+        application_in_port: 47950,
+        json_port: 47951,
+        ..Config::default()
+    });
+
+    let (packets, daemon) = mpsc::channel();
+    let events = json_endpoint::spawn(config.clone(), packets, None).unwrap();
+
+    let mut client = TcpStream::connect(config.addr(config.json_port)).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+
+    client
+        .write_all(b"{\"command\": \"free_notes\", \"external_id_regex\": \"lead_.*\"}\n")
+        .unwrap();
+
+    assert_eq!(
+        daemon.recv_timeout(TIMEOUT).unwrap(),
+        OscPacket::Message(OscMessage {
+            addr: "/free_notes".to_string(),
+            args: vec![string("lead_.*")],
+        })
+    );
+
+    // Larger than a UDP datagram could carry
+    let scd = "x".repeat(100_000);
+    let command = serde_json::json!({ "command": "osc", "addr": "/read_scd", "args": [scd] });
+    client
+        .write_all((command.to_string() + "\n").as_bytes())
+        .unwrap();
+    assert_eq!(
+        daemon.recv_timeout(TIMEOUT).unwrap(),
+        OscPacket::Message(OscMessage {
+            addr: "/read_scd".to_string(),
+            args: vec![string(&scd)],
+        })
+    );
+
+    client
+        .write_all(b"{\"command\": \"free_notes\"}\n")
+        .unwrap();
+    assert!(read_json(&mut reader)["error"].is_string());
+
+    events
+        .send(OscMessage {
+            addr: "/get_status_reply".to_string(),
+            args: vec![string("ok"), OscType::Int(3)],
+        })
+        .unwrap();
+
+    assert_eq!(
+        read_json(&mut reader),
+        serde_json::json!({ "addr": "/get_status_reply", "args": ["ok", 3] })
+    );
+}
+
+#[test]
+fn stalled_clients_do_not_hold_up_others() {
+    let config = Arc::new(Config {
+        application_in_port: 47952,
+        json_port: 47953,
+        ..Config::default()
+    });
+
+    let (packets, _daemon) = mpsc::channel();
+    let events = json_endpoint::spawn(config.clone(), packets, None).unwrap();
+
+    // Never reads, so its socket buffers fill up
+    let mut stalled = TcpStream::connect(config.addr(config.json_port)).unwrap();
+    let client = TcpStream::connect(config.addr(config.json_port)).unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    // Give the endpoint time to register both clients
+    thread::sleep(Duration::from_millis(500));
+
+    let reader = thread::spawn(move || {
+        let mut reader = BufReader::new(client);
+        let mut received = 0;
+        while read_json(&mut reader)["addr"] != "/done" {
+            received += 1;
+        }
+        received
+    });
+
+    let padding = "x".repeat(10_000);
+    for _ in 0..2000 {
+        events
+            .send(OscMessage {
+                addr: "/padding".to_string(),
+                args: vec![string(&padding)],
+            })
+            .unwrap();
+    }
+    events
+        .send(OscMessage {
+            addr: "/done".to_string(),
+            args: vec![],
+        })
+        .unwrap();
+
+    assert_eq!(reader.join().unwrap(), 2000);
+
+    // Disconnected after the write timed out: whatever was buffered, then end of stream
+    stalled.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut buffered = vec![];
+    assert!(stalled.read_to_end(&mut buffered).is_ok());
+}
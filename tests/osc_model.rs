@@ -0,0 +1,211 @@
+mod common;
+
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use bigdecimal::BigDecimal;
+use jdw_osc_lib::model::{TaggedBundle, TimedOSCPacket};
+use jdw_sc::osc_model::{
+    BatchSendMessage, FreeNotesMessage, LoadSampleMessage, NRTPreloadMessage, NRTRecordMessage,
+    NoteModifyMessage, NoteOnMessage, NoteOnTimedMessage, PlaySampleMessage, RealTimePacket,
+};
+use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+
+use common::string;
+
+// Through the wire format and back, as the daemon receives it
+fn over_the_wire(packet: OscPacket) -> OscPacket {
+    let bytes = encoder::encode(&packet).unwrap();
+    decoder::decode_udp(&bytes).unwrap().1
+}
+
+fn received_message(msg: OscMessage) -> OscMessage {
+    match over_the_wire(OscPacket::Message(msg)) {
+        OscPacket::Message(msg) => msg,
+        OscPacket::Bundle(_) => panic!("Expected a message"),
+    }
+}
+
+fn received_bundle(packet: OscPacket) -> TaggedBundle {
+    match over_the_wire(packet) {
+        OscPacket::Bundle(bundle) => TaggedBundle::new(&bundle).unwrap(),
+        OscPacket::Message(_) => panic!("Expected a bundle"),
+    }
+}
+
+fn named_args() -> Vec<OscType> {
+    vec![
+        string("freq"),
+        OscType::Float(440.0),
+        string("amp"),
+        OscType::Float(0.2),
+    ]
+}
+
+fn note_on(external_id: &str) -> NoteOnMessage {
+    NoteOnMessage {
+        synth_name: "default".to_string(),
+        external_id: external_id.to_string(),
+        delay_ms: 0,
+        args: named_args(),
+    }
+}
+
+fn timed(time: &str, external_id: &str) -> TimedOSCPacket {
+    TimedOSCPacket {
+        time: BigDecimal::from_str(time).unwrap(),
+        packet: OscPacket::Message(note_on(external_id).to_osc()),
+    }
+}
+
+#[test]
+fn note_on_timed_round_trip() {
+    let note = NoteOnTimedMessage {
+        synth_name: "default".to_string(),
+        external_id: "lead_1".to_string(),
+        gate_time: BigDecimal::from_str("0.5").unwrap(),
+        delay_ms: 250,
+        args: named_args(),
+    };
+
+    assert_eq!(
+        NoteOnTimedMessage::new(&received_message(note.to_osc())).unwrap(),
+        note
+    );
+}
+
+#[test]
+fn note_on_round_trip() {
+    let note = NoteOnMessage {
+        delay_ms: 120,
+        ..note_on("lead_1")
+    };
+
+    assert_eq!(
+        NoteOnMessage::new(&received_message(note.to_osc())).unwrap(),
+        note
+    );
+}
+
+#[test]
+fn note_modify_round_trip() {
+    let modify = NoteModifyMessage {
+        external_id_regex: "lead_.*".to_string(),
+        delay_ms: 10,
+        args: vec![string("gate"), OscType::Float(0.0)],
+    };
+
+    assert_eq!(
+        NoteModifyMessage::new(&received_message(modify.to_osc())).unwrap(),
+        modify
+    );
+}
+
+#[test]
+fn free_notes_round_trip() {
+    let free = FreeNotesMessage {
+        external_id_regex: "lead_.*".to_string(),
+    };
+
+    assert_eq!(
+        FreeNotesMessage::new(&received_message(free.to_osc())).unwrap(),
+        free
+    );
+}
+
+#[test]
+fn load_sample_round_trip() {
+    let load = LoadSampleMessage {
+        file_path: "/samples/drums/kick.wav".to_string(),
+        sample_pack: "drums".to_string(),
+        buffer_number: 12,
+        category_tag: "bd".to_string(),
+        tone_index: 3,
+    };
+
+    assert_eq!(
+        LoadSampleMessage::new(&received_message(load.to_osc())).unwrap(),
+        load
+    );
+}
+
+#[test]
+fn play_sample_round_trip() {
+    let with_category = PlaySampleMessage {
+        external_id: "drum_1".to_string(),
+        sample_pack: "drums".to_string(),
+        index: 2,
+        category: Some("bd".to_string()),
+        delay_ms: 0,
+        args: named_args(),
+    };
+    let without_category = PlaySampleMessage {
+        category: None,
+        ..with_category.clone()
+    };
+
+    for play in vec![with_category, without_category] {
+        assert_eq!(
+            PlaySampleMessage::new(&received_message(play.to_osc())).unwrap(),
+            play
+        );
+    }
+}
+
+#[test]
+fn real_time_packet_round_trip() {
+    // Half a second is exact in OSC time, which counts in 1/2^32 seconds
+    let real_time = RealTimePacket {
+        packet: OscPacket::Message(note_on("lead_1").to_osc()),
+        time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+    };
+
+    assert_eq!(
+        RealTimePacket::new(received_bundle(real_time.to_osc())).unwrap(),
+        real_time
+    );
+}
+
+#[test]
+fn batch_send_round_trip() {
+    let batch = BatchSendMessage {
+        packets: vec![
+            OscPacket::Message(note_on("lead_1").to_osc()),
+            OscPacket::Message(note_on("lead_2").to_osc()),
+        ],
+    };
+
+    assert_eq!(
+        BatchSendMessage::from_bundle(received_bundle(batch.to_osc())).unwrap(),
+        batch
+    );
+}
+
+#[test]
+fn nrt_preload_round_trip() {
+    let preload = NRTPreloadMessage {
+        messages: vec![timed("0", "pad_1"), timed("0.25", "pad_2")],
+    };
+
+    assert!(NRTPreloadMessage::from_bundle(received_bundle(preload.to_osc())).unwrap() == preload);
+}
+
+#[test]
+fn nrt_record_round_trip() {
+    let record = NRTRecordMessage {
+        file_name: "bounce.wav".to_string(),
+        bpm: 120.0,
+        messages: vec![timed("0.5", "lead_1"), timed("1.5", "lead_2")],
+        end_beat: 8.0,
+    };
+
+    assert!(NRTRecordMessage::from_bundle(received_bundle(record.to_osc())).unwrap() == record);
+}
+
+#[test]
+fn bundles_are_not_parsed_as_other_tags() {
+    let batch = BatchSendMessage { packets: vec![] }.to_osc();
+
+    assert!(NRTPreloadMessage::from_bundle(received_bundle(batch.clone())).is_err());
+    assert!(NRTRecordMessage::from_bundle(received_bundle(batch)).is_err());
+}
@@ -0,0 +1,154 @@
+use jdw_sc::sclang_output::{classify, BlockCollector, LineKind, SclangErrorEvent};
+use rosc::OscType;
+
+fn command() -> Option<String> {
+    Some("/read_scd req_1".to_string())
+}
+
+// Events completed while feeding the lines, then whatever is left at the end of the output
+fn events(lines: &[&str]) -> Vec<SclangErrorEvent> {
+    let mut collector = BlockCollector::new();
+
+    let mut events: Vec<SclangErrorEvent> = lines
+        .iter()
+        .flat_map(|line| collector.feed(line, command()))
+        .collect();
+    events.extend(collector.flush());
+    events
+}
+
+#[test]
+fn classifies_lines() {
+    let lines = [
+        ("ERROR: Message 'foo' not understood.", LineKind::Error),
+        ("  ERROR: indented", LineKind::Error),
+        (
+            "exception in GraphDef_Recv: UGen 'Foo' not installed.",
+            LineKind::Error,
+        ),
+        (
+            "FAILURE IN SERVER /s_new SynthDef not found",
+            LineKind::ServerFailure,
+        ),
+        (
+            "WARNING: server 'localhost' already booting",
+            LineKind::Warning,
+        ),
+        (
+            "Server 'localhost' exited with exit code 0.",
+            LineKind::Warning,
+        ),
+        ("SuperCollider 3 server ready.", LineKind::Notice),
+        (
+            "Booting server 'localhost' on address 127.0.0.1:57110.",
+            LineKind::Notice,
+        ),
+        ("compile done", LineKind::Notice),
+        ("number of synthdefs: 3", LineKind::Plain),
+        ("", LineKind::Plain),
+    ];
+
+    for (line, kind) in lines {
+        assert_eq!(classify(line), kind, "{}", line);
+    }
+}
+
+#[test]
+fn error_dump_is_one_event() {
+    let events = events(&[
+        "compile done",
+        "ERROR: Message 'foo' not understood.",
+        "RECEIVER:",
+        "   nil",
+        "CALL STACK:",
+        "\tDoesNotUnderstandError:reportError",
+        "^^ The preceding error dump is for ERROR: Message 'foo' not understood.",
+        "sc3> ",
+    ]);
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, "ERROR");
+    assert_eq!(events[0].message, "ERROR: Message 'foo' not understood.");
+    assert_eq!(events[0].command, command());
+    assert_eq!(events[0].details.len(), 5);
+    assert!(events[0].details[4].starts_with("^^ The preceding error dump"));
+}
+
+#[test]
+fn parse_error_ends_at_separator_and_skips_trailer() {
+    let events = events(&[
+        "ERROR: syntax error, unexpected NAME, expecting $end",
+        "  in interpreted text",
+        "  line 1 char 5:",
+        "",
+        "  foo bar",
+        "      ^^^",
+        "-----------------------------------",
+        "ERROR: Command line parse failed",
+    ]);
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].message,
+        "ERROR: syntax error, unexpected NAME, expecting $end"
+    );
+    assert_eq!(events[0].details.len(), 6);
+    assert_eq!(events[0].details[5], "-----------------------------------");
+}
+
+#[test]
+fn dump_ends_at_unrelated_line() {
+    let events = events(&["ERROR: first", "   detail", "ERROR: second"]);
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].message, "ERROR: first");
+    assert_eq!(events[0].details, vec!["   detail".to_string()]);
+    assert_eq!(events[1].message, "ERROR: second");
+    assert!(events[1].details.is_empty());
+}
+
+#[test]
+fn long_dumps_are_cut_off() {
+    let mut lines = vec!["ERROR: deep recursion".to_string()];
+    lines.extend((0..100).map(|i| format!("   frame {}", i)));
+    let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+
+    let events = events(&lines);
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].details.len(), 64);
+}
+
+#[test]
+fn server_failure_names_the_failed_command() {
+    let events = events(&[
+        "FAILURE IN SERVER /s_new SynthDef not found",
+        "FAILURE IN SERVER",
+    ]);
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].kind, "FAILURE IN SERVER");
+    assert_eq!(
+        events[0].message,
+        "FAILURE IN SERVER /s_new SynthDef not found"
+    );
+    assert_eq!(events[0].command, Some("/s_new".to_string()));
+    assert_eq!(events[1].command, command());
+}
+
+#[test]
+fn event_is_published_as_sclang_error() {
+    let events = events(&["ERROR: first", "   detail 1", "   detail 2"]);
+    let msg = events[0].as_osc();
+
+    assert_eq!(msg.addr, "/sclang_error");
+    assert_eq!(
+        msg.args,
+        vec![
+            OscType::String("ERROR".to_string()),
+            OscType::String("/read_scd req_1".to_string()),
+            OscType::String("ERROR: first".to_string()),
+            OscType::String("   detail 1\n   detail 2".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,36 @@
+use jdw_sc::config::Config;
+use jdw_sc::sc_process_management::scsynth_args;
+use jdw_sc::scd_templating::server_option_lines;
+
+fn device_args(in_device: &str, out_device: &str) -> Vec<String> {
+    let mut config = Config::default();
+    config.in_device = in_device.to_string();
+    config.out_device = out_device.to_string();
+
+    let args = scsynth_args(&config);
+    match args.iter().position(|arg| arg == "-H") {
+        Some(index) => args[index + 1..].to_vec(),
+        None => vec![],
+    }
+}
+
+#[test]
+fn scsynth_devices_fill_in_for_each_other() {
+    assert_eq!(device_args("", ""), Vec::<String>::new());
+    assert_eq!(device_args("Mic", "Mic"), vec!["Mic"]);
+    assert_eq!(device_args("Mic", "Speakers"), vec!["Mic", "Speakers"]);
+    assert_eq!(device_args("Mic", ""), vec!["Mic"]);
+    assert_eq!(device_args("", "Speakers"), vec!["Speakers"]);
+}
+
+#[test]
+fn device_names_are_escaped_in_server_options() {
+    let mut config = Config::default();
+    config.in_device = "Mic \"USB\"".to_string();
+    config.out_device = "C:\\Speakers".to_string();
+
+    let lines = server_option_lines(&config, "s.options", false);
+
+    assert!(lines.contains("s.options.inDevice = \"Mic \\\"USB\\\"\";"));
+    assert!(lines.contains("s.options.outDevice = \"C:\\\\Speakers\";"));
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::fs;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bigdecimal::BigDecimal;
+use jdw_osc_lib::model::TaggedBundle;
+use jdw_sc::config::Config;
+use jdw_sc::journal::JournalEntry;
+use jdw_sc::osc_model::NRTRecordMessage;
+use jdw_sc::sc_backend::RecordingBackend;
+use jdw_sc::session_render;
+use rosc::{OscMessage, OscPacket, OscType};
+
+use common::{interpreter, msg, string};
+
+fn received(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + ms)
+}
+
+fn entry(ms: u64, message: OscMessage) -> JournalEntry {
+    JournalEntry {
+        receive_time: received(ms),
+        packet: OscPacket::Message(message),
+    }
+}
+
+/*
+    At 120 bpm, counted from the first note: a one beat note at 0ms, a note received at 500ms
+        with 250ms delay, and both freed at 1000ms.
+*/
+fn session() -> Vec<JournalEntry> {
+    vec![
+        entry(
+            0,
+            msg(
+                "/create_synthdef",
+                vec![string("SynthDef(\"pad\", { Out.ar(0, SinOsc.ar) })")],
+            ),
+        ),
+        entry(0, msg("/set_bpm", vec![OscType::Int(120)])),
+        entry(
+            100,
+            msg(
+                "/note_on_timed",
+                vec![
+                    string("default"),
+                    string("lead_1"),
+                    string("1"),
+                    OscType::Int(0),
+                ],
+            ),
+        ),
+        entry(
+            600,
+            msg(
+                "/note_on",
+                vec![string("default"), string("lead_2"), OscType::Int(250)],
+            ),
+        ),
+        entry(1100, msg("/free_notes", vec![string("lead_.*")])),
+    ]
+}
+
+fn record(packet: OscPacket) -> NRTRecordMessage {
+    match packet {
+        OscPacket::Bundle(bundle) => {
+            NRTRecordMessage::from_bundle(TaggedBundle::new(&bundle).unwrap()).unwrap()
+        }
+        OscPacket::Message(_) => panic!("Expected an nrt_record bundle"),
+    }
+}
+
+#[test]
+fn notes_keep_session_timing() {
+    let score = session_render::session_score(&session(), "bounce.wav", 100);
+
+    assert_eq!(score.notes, 3);
+    assert_eq!(score.setup.len(), 1);
+
+    let record = record(score.record);
+    let waits: Vec<BigDecimal> = record.messages.iter().map(|row| row.time.clone()).collect();
+
+    assert_eq!(record.file_name, "bounce.wav");
+    assert_eq!(record.bpm, 60.0);
+    assert_eq!(
+        waits,
+        vec![
+            BigDecimal::from_str("0.75").unwrap(),
+            BigDecimal::from_str("0.25").unwrap(),
+            BigDecimal::from(0),
+        ]
+    );
+    // Last note plus the ringout tail
+    assert_eq!(record.end_beat, 3.0);
+
+    // One beat at 120 bpm
+    match &record.messages[0].packet {
+        OscPacket::Message(note) => assert_eq!(note.args[2], string("0.500000")),
+        OscPacket::Bundle(_) => panic!("Expected /note_on_timed"),
+    }
+}
+
+#[test]
+fn bpm_at_or_below_zero_is_ignored() {
+    let mut entries = session();
+    entries.insert(2, entry(50, msg("/set_bpm", vec![OscType::Int(0)])));
+
+    let score = session_render::session_score(&entries, "bounce.wav", 100);
+    let record = record(score.record);
+
+    // Still one beat at 120 bpm
+    match &record.messages[0].packet {
+        OscPacket::Message(note) => assert_eq!(note.args[2], string("0.500000")),
+        OscPacket::Bundle(_) => panic!("Expected /note_on_timed"),
+    }
+}
+
+#[test]
+fn score_is_rendered_through_nrt_record() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    let file_name = std::env::temp_dir()
+        .join(format!("jdw-sc-test-{}-render", std::process::id()))
+        .join("bounce.wav")
+        .to_string_lossy()
+        .to_string();
+
+    let score = session_render::session_score(&session(), &file_name, 120);
+
+    backend.push_incoming(msg("/nrt_done", vec![string("ok")]));
+    interpreter.interpret(score.record, SystemTime::now());
+
+    let script = fs::read_to_string(file_name.clone() + ".scd").unwrap();
+    fs::remove_dir_all(std::path::Path::new(&file_name).parent().unwrap()).ok();
+
+    assert!(script.contains("[ 0.00000, [\"/s_new\",\"default\",101,0,0] ]"));
+    assert!(script.contains("[ 0.50000, [\"/n_set\",101,\"gate\",0.00000] ]"));
+    assert!(script.contains("[ 0.75000, [\"/s_new\",\"default\",102,0,0] ]"));
+    assert!(script.contains("[ 1.00000, [\"/n_free\",101] ]"));
+    assert!(script.contains("[ 1.00000, [\"/n_free\",102] ]"));
+}
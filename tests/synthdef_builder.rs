@@ -0,0 +1,134 @@
+use jdw_sc::scsyndef::{self, InputSpec};
+use jdw_sc::synthdef_builder::{self, Envelope, Rate, Signal, SynthDefBuilder, DONE_FREE_SELF};
+use jdw_sc::synthdef_registry::SynthDefControl;
+
+fn ping() -> SynthDefBuilder {
+    let mut def = SynthDefBuilder::new("ping");
+    let freq = def.control("freq", 440.0);
+    let amp = def.control("amp", 0.5);
+    let osc = def.sin_osc(Rate::Audio, freq, 0.0);
+    let env = def.env_gen(&Envelope::perc(0.01, 1.0), 1.0, DONE_FREE_SELF);
+    let sig = def.mul(osc, env);
+    let sig = def.mul(sig, amp);
+    let (left, right) = def.pan2(sig, 0.0, 1.0);
+    def.out(0.0, &[left, right]);
+    def
+}
+
+#[test]
+fn encoded_definition_decodes_to_built_definition() {
+    let def = ping();
+    let decoded = scsyndef::decode_all(&def.encode()).unwrap();
+
+    assert_eq!(decoded, vec![def.build()]);
+}
+
+#[test]
+fn encoded_definition_has_synthdef2_header() {
+    let bytes = ping().encode();
+
+    assert_eq!(&bytes[0..4], b"SCgf");
+    assert_eq!(&bytes[4..8], &2i32.to_be_bytes());
+    assert_eq!(&bytes[8..10], &1i16.to_be_bytes());
+}
+
+#[test]
+fn decode_reports_name_and_controls() {
+    let compiled = scsyndef::decode(&ping().encode()).unwrap();
+
+    assert_eq!(compiled.name, "ping");
+    assert_eq!(
+        compiled.controls,
+        vec![
+            SynthDefControl {
+                name: "freq".to_string(),
+                default: 440.0
+            },
+            SynthDefControl {
+                name: "amp".to_string(),
+                default: 0.5
+            },
+        ]
+    );
+}
+
+#[test]
+fn controls_are_outputs_of_first_ugen() {
+    let built = ping().build();
+
+    assert_eq!(built.ugens[0].name, "Control");
+    assert_eq!(built.ugens[0].outputs.len(), 2);
+
+    // SinOsc follows the Control UGen and reads freq from its first output
+    assert_eq!(built.ugens[1].name, "SinOsc");
+    assert_eq!(built.ugens[1].inputs[0], InputSpec::UGen(0, 0));
+}
+
+#[test]
+fn constants_are_deduplicated() {
+    let built = ping().build();
+
+    let zeros = built
+        .constants
+        .iter()
+        .filter(|value| **value == 0.0)
+        .count();
+    assert_eq!(zeros, 1);
+}
+
+#[test]
+fn binary_op_takes_highest_input_rate() {
+    let mut def = SynthDefBuilder::new("rates");
+    let amp = def.control("amp", 1.0);
+    let noise = def.white_noise(Rate::Audio);
+
+    match def.mul(amp, noise) {
+        Signal::UGen { rate, .. } => assert_eq!(rate, Rate::Audio),
+        other => panic!("Expected a UGen signal, got {:?}", other),
+    }
+}
+
+#[test]
+fn definition_without_controls_has_no_control_ugen() {
+    let mut def = SynthDefBuilder::new("noise");
+    let noise = def.white_noise(Rate::Audio);
+    let filtered = def.lpf(noise, 800.0);
+    def.out(0.0, &[filtered]);
+
+    let built = def.build();
+    assert_eq!(built.ugens[0].name, "WhiteNoise");
+    assert_eq!(built.ugens[1].inputs[0], InputSpec::UGen(0, 0));
+    assert_eq!(scsyndef::decode_all(&def.encode()).unwrap(), vec![built]);
+}
+
+#[test]
+fn truncated_data_is_rejected() {
+    let bytes = ping().encode();
+
+    assert!(scsyndef::decode(&bytes[..bytes.len() - 3]).is_err());
+    assert!(scsyndef::decode(b"NOPE").is_err());
+}
+
+#[test]
+fn built_in_sampler_has_controls_of_sampler_scd() {
+    let compiled = scsyndef::decode(&synthdef_builder::sampler().encode()).unwrap();
+
+    assert_eq!(compiled.name, "sampler");
+    assert_eq!(
+        compiled
+            .controls
+            .iter()
+            .map(|control| (control.name.as_str(), control.default))
+            .collect::<Vec<_>>(),
+        vec![
+            ("out", 0.0),
+            ("start", 0.0),
+            ("sus", 10.0),
+            ("amp", 1.0),
+            ("rate", 1.0),
+            ("buf", 0.0),
+            ("pan", 0.0),
+            ("ofs", 0.05),
+        ]
+    );
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use jdw_sc::config::Config;
+use jdw_sc::sc_backend::RecordingBackend;
+use jdw_sc::synthdef_library::{self, LibraryEvent};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use common::interpreter;
+
+fn test_dir(test_name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("jdw-sc-test-{}-{}", std::process::id(), test_name));
+    fs::create_dir_all(dir.join("library")).unwrap();
+    dir
+}
+
+#[test]
+fn contains_only_files_inside_the_dir() {
+    let dir = test_dir("library-contains");
+    let library = dir.join("library");
+    fs::write(library.join("pad.scd"), "SynthDef(\"pad\", {})").unwrap();
+    fs::write(dir.join("outside.scd"), "SynthDef(\"outside\", {})").unwrap();
+
+    assert!(synthdef_library::contains(
+        &library,
+        &library.join("pad.scd")
+    ));
+    assert!(!synthdef_library::contains(
+        &library,
+        &dir.join("outside.scd")
+    ));
+    assert!(!synthdef_library::contains(
+        &library,
+        &library.join("..").join("outside.scd")
+    ));
+    assert!(!synthdef_library::contains(
+        &library,
+        &library.join("missing.scd")
+    ));
+
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn files_outside_synthdef_dir_are_refused() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    interpreter.library_event(LibraryEvent::Changed(PathBuf::from("/etc/hostname")));
+
+    assert!(backend.sclang_messages().is_empty());
+    assert!(backend.scsynth_messages().is_empty());
+
+    let out = backend.out_messages();
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].addr, "/synthdef_file_loaded");
+    assert_eq!(out[0].args[0], OscType::String("FAILURE".to_string()));
+}
+
+#[test]
+fn watcher_messages_are_not_accepted_over_osc() {
+    let backend = RecordingBackend::new(Config::default());
+    let mut interpreter = interpreter(&backend);
+
+    interpreter.interpret(
+        OscPacket::Message(OscMessage {
+            addr: "/load_synthdef_file".to_string(),
+            args: vec![OscType::String("/etc/hostname".to_string())],
+        }),
+        SystemTime::now(),
+    );
+
+    assert!(backend.sclang_messages().is_empty());
+    assert!(backend.out_messages().is_empty());
+}